@@ -1,39 +1,85 @@
 mod builder;
 mod iterator;
 
+use anyhow::{bail, Context, Result};
 pub use builder::BlockBuilder;
 pub use iterator::BlockIterator;
 use bytes::{Buf, BufMut, Bytes};
-use crate::utils::{SIZEOF_U16, two_u8_to_u16};
+use crate::key;
+use crate::utils::{get_varint, SIZEOF_U32, SIZEOF_U64};
 
 /// A block is the smallest unit of read and caching in LSM tree. It is a collection of sorted
 /// key-value pairs.
 pub struct Block {
     pub data: Vec<u8>,
-    pub offsets: Vec<u16>,
+    pub offsets: Vec<u32>,
 }
 
 impl Block {
+    /// `[entries, offsets(4B each), num_of_elements(4B)]`
     pub fn encode(&self) -> Bytes {
         let mut buf = self.data.clone();
         for offset in &self.offsets {
-            buf.put_u16(*offset);
+            buf.put_u32(*offset);
         }
-        buf.put_u16(self.offsets.len() as u16);
+        buf.put_u32(self.offsets.len() as u32);
         buf.into()
     }
 
-    pub fn decode(buf: &[u8]) -> Self {
-        let num_of_elements = two_u8_to_u16(&buf[(buf.len() - SIZEOF_U16)..]) as usize;
-        let offsets_raw = &buf[(buf.len() - SIZEOF_U16 - num_of_elements * SIZEOF_U16)..(buf.len() - SIZEOF_U16)];
-        let offsets = offsets_raw
-            .chunks(SIZEOF_U16)
-            .map(|s| two_u8_to_u16(s))
+    /// Decode a block, every entry is checked to be within the block.
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() < SIZEOF_U32 {
+            bail!("block of {} bytes is too short", buf.len());
+        }
+        let num_of_elements = (&buf[(buf.len() - SIZEOF_U32)..]).get_u32() as usize;
+        let Some(data_len) = (buf.len() - SIZEOF_U32).checked_sub(num_of_elements * SIZEOF_U32)
+        else {
+            bail!(
+                "block of {} bytes can't have {} entries",
+                buf.len(),
+                num_of_elements
+            );
+        };
+        let offsets: Vec<u32> = buf[data_len..(buf.len() - SIZEOF_U32)]
+            .chunks(SIZEOF_U32)
+            .map(|mut offset| offset.get_u32())
             .collect();
-        Self {
-            data: buf[..(buf.len() - SIZEOF_U16 - num_of_elements * SIZEOF_U16)].to_vec(),
-            offsets,
+        let data = buf[..data_len].to_vec();
+        for offset in &offsets {
+            let (key, value) = Self::decode_entry(&data, *offset as usize)?;
+            if key.len() <= SIZEOF_U64 {
+                bail!("key at offset {} is shorter than an internal key", offset);
+            }
+            key::check_entry(key, value)
+                .with_context(|| format!("invalid entry at offset {}", offset))?;
         }
+        Ok(Self { data, offsets })
+    }
+
+    /// The key and the value of the entry at `offset` of `data`.
+    fn decode_entry(data: &[u8], offset: usize) -> Result<(&[u8], &[u8])> {
+        let Some(mut entry) = data.get(offset..) else {
+            bail!(
+                "entry offset {} is beyond the block data of {} bytes",
+                offset,
+                data.len()
+            );
+        };
+        let key = Self::decode_slice(&mut entry)
+            .with_context(|| format!("invalid key at offset {}", offset))?;
+        let value = Self::decode_slice(&mut entry)
+            .with_context(|| format!("invalid value at offset {}", offset))?;
+        Ok((key, value))
     }
-}
 
+    /// A slice prefixed with its length as a varint.
+    fn decode_slice<'a>(buf: &mut &'a [u8]) -> Result<&'a [u8]> {
+        let len = get_varint(buf)?;
+        if len > buf.len() as u64 {
+            bail!("length {} is beyond the end of the block", len);
+        }
+        let (slice, rest) = buf.split_at(len as usize);
+        *buf = rest;
+        Ok(slice)
+    }
+}
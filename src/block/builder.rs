@@ -1,12 +1,13 @@
 use bytes::BufMut;
-use crate::block::{Block, SIZEOF_U16};
+use crate::block::Block;
+use crate::utils::{put_varint, varint_len, SIZEOF_U32, SIZEOF_U64};
 
 /// Builds a block.
 pub struct BlockBuilder {
     occupy_size: usize,
     block_size: usize,
     data: Vec<u8>,
-    offsets: Vec<u16>,
+    offsets: Vec<u32>,
 }
 
 impl BlockBuilder {
@@ -20,19 +21,23 @@ impl BlockBuilder {
         }
     }
 
-    /// Adds a key-value pair to the block. Returns false when the block is full.
+    /// Adds a key-value pair to the block, `key` is an internal key and keys must be added in
+    /// the order of internal keys. Returns false when the block is full. The first entry is
+    /// always added, so a block holding a single large entry exceeds the block size.
     #[must_use]
     pub fn add(&mut self, key: &[u8], value: &[u8]) -> bool {
-        assert!(!key.is_empty(), "key must not be empty");
-        let entry_total_size = self.entry_size(key, value) + SIZEOF_U16; /* offset size */
-        if self.occupy_size + entry_total_size > self.block_size - SIZEOF_U16 /* num_of_elements */
+        assert!(key.len() > SIZEOF_U64, "key must not be empty");
+        let entry_total_size = self.entry_size(key, value) + SIZEOF_U32; /* offset size */
+        let block_size = self.block_size.saturating_sub(SIZEOF_U32 /* num_of_elements */);
+        if (self.occupy_size + entry_total_size > block_size
+            // the offsets are 4 bytes
+            || self.data.len() > u32::MAX as usize)
             && !self.is_empty() /* first key always can set */ {
-            // println!("over block size, key: {:?}, block_size: {:?}", key, self.block_size);
             return false;
         }
         let offset = self.data.len();
         self.data.append(&mut self.entry_encode(key, value));
-        self.offsets.push(u16::try_from(offset).unwrap());
+        self.offsets.push(offset as u32);
         self.occupy_size += entry_total_size;
         true
     }
@@ -51,12 +56,12 @@ impl BlockBuilder {
     }
 
     /// key & value -> entry
-    /// `[key_len(2B), key, value_len(2B), value]`
+    /// `[key_len(varint), key, value_len(varint), value]`
     fn entry_encode(&self, key: &[u8], value: &[u8]) -> Vec<u8> {
-        let mut arr = Vec::new();
-        arr.put_u16(key.len() as u16);
+        let mut arr = Vec::with_capacity(self.entry_size(key, value));
+        put_varint(&mut arr, key.len() as u64);
         arr.put(key);
-        arr.put_u16(value.len() as u16);
+        put_varint(&mut arr, value.len() as u64);
         arr.put(value);
         arr
     }
@@ -64,7 +69,7 @@ impl BlockBuilder {
     /// entry size
     fn entry_size(&self, key: &[u8], value: &[u8]) -> usize {
         // key_len + key + value_len + value
-        SIZEOF_U16 + key.len() + SIZEOF_U16 + value.len()
+        varint_len(key.len() as u64) + key.len() + varint_len(value.len() as u64) + value.len()
     }
 
 
@@ -1,27 +1,45 @@
 use std::cmp::Ordering;
 use std::sync::Arc;
-use bytes::Buf;
-use crate::block::{Block, SIZEOF_U16};
-use crate::utils::two_u8_to_u16;
+use crate::block::Block;
+use crate::comparator::{BytewiseComparator, Comparator};
+use crate::key;
 
-/// Iterates on a block.
+/// Iterates on a block, keys are internal keys.
 pub struct BlockIterator {
     block: Arc<Block>,
+    /// Orders the user keys of the block when seeking.
+    comparator: Arc<dyn Comparator>,
     key: Vec<u8>,
     value: Vec<u8>,
     idx: usize,
 }
 
 impl BlockIterator {
+    /// Creates an iterator over a block whose keys are ordered bytewise.
     pub fn new(block: Arc<Block>) -> Self {
+        Self::new_with_comparator(block, Arc::new(BytewiseComparator))
+    }
+
+    /// Creates an iterator over a block whose keys are ordered by `comparator`, it is invalid
+    /// until positioned.
+    pub fn new_with_comparator(block: Arc<Block>, comparator: Arc<dyn Comparator>) -> Self {
         Self {
             block,
+            comparator,
             key: Vec::new(),
             value: Vec::new(),
             idx: 0,
         }
     }
 
+    /// Creates an iterator that is never valid, for an SSTable without data blocks.
+    pub fn empty() -> Self {
+        Self::new(Arc::new(Block {
+            data: Vec::new(),
+            offsets: Vec::new(),
+        }))
+    }
+
     /// Creates a block iterators and seek to the first entry.
     pub fn create_and_seek_to_first(block: Arc<Block>) -> Self {
         let mut iter = Self::new(block);
@@ -36,6 +54,20 @@ impl BlockIterator {
         iter
     }
 
+    /// Creates a block iterators and seek to the last entry.
+    pub fn create_and_seek_to_last(block: Arc<Block>) -> Self {
+        let mut iter = Self::new(block);
+        iter.seek_to_last();
+        iter
+    }
+
+    /// Creates a block iterators and seek to the last key that <= `key`.
+    pub fn create_and_seek_for_prev(block: Arc<Block>, key: &[u8]) -> Self {
+        let mut iter = Self::new(block);
+        iter.seek_for_prev(key);
+        iter
+    }
+
     /// Returns the key of the current entry.
     pub fn key(&self) -> &[u8] {
         &self.key
@@ -56,20 +88,36 @@ impl BlockIterator {
         self.seek_to_idx(0);
     }
 
+    /// Seeks to the last key in the block.
+    pub fn seek_to_last(&mut self) {
+        self.seek_to_idx(self.block.offsets.len());
+        self.prev();
+    }
+
     /// Move to the next key in the block.
     pub fn next(&mut self) {
         self.idx += 1;
         self.seek_to_idx(self.idx);
     }
 
-    /// Seek to the first key that >= `key`.
+    /// Move to the previous key in the block, the iterator is invalid after the first key.
+    pub fn prev(&mut self) {
+        if self.idx == 0 {
+            self.key.clear();
+            self.value.clear();
+        } else {
+            self.seek_to_idx(self.idx - 1);
+        }
+    }
+
+    /// Seek to the first key that >= `key`, in the order of internal keys.
     pub fn seek_to_key(&mut self, key: &[u8]) {
         let mut low = 0;
         let mut high = self.block.offsets.len();
         while low < high {
             let mid = low + (high - low) / 2;
             self.seek_to_idx(mid);
-            match self.key().cmp(key) {
+            match key::compare(&*self.comparator, self.key(), key) {
                 Ordering::Greater => high = mid,
                 Ordering::Less => low = mid + 1,
                 Ordering::Equal => return,
@@ -78,26 +126,29 @@ impl BlockIterator {
         self.seek_to_idx(low);
     }
 
+    /// Seek to the last key that <= `key`, in the order of internal keys.
+    pub fn seek_for_prev(&mut self, key: &[u8]) {
+        self.seek_to_key(key);
+        if !self.is_valid() || key::compare(&*self.comparator, self.key(), key).is_gt() {
+            self.prev();
+        }
+    }
+
     fn seek_to_idx(&mut self, idx: usize) {
+        self.idx = idx;
         if idx >= self.block.offsets.len() {
             self.key.clear();
             self.value.clear();
         } else {
             self.seek_to_offset(self.block.offsets[idx] as usize);
-            self.idx = idx;
         }
     }
 
     fn seek_to_offset(&mut self, offset: usize) {
-        let key_len_bytes = &self.block.data[offset..(offset + SIZEOF_U16)];
-        let key_len = two_u8_to_u16(key_len_bytes) as usize;
-        let key = &self.block.data[(offset + SIZEOF_U16)..(offset + SIZEOF_U16 + key_len)];
-        let value_len_bytes = &self.block.data[(offset + SIZEOF_U16 + key_len)..(offset + SIZEOF_U16 + key_len + SIZEOF_U16)];
-        let value_len = two_u8_to_u16(value_len_bytes) as usize;
-        let value = &self.block.data[(offset + SIZEOF_U16 + key_len + SIZEOF_U16)..(offset + SIZEOF_U16 + key_len + SIZEOF_U16 + value_len)];
+        let (key, value) = Block::decode_entry(&self.block.data, offset)
+            .expect("entries are checked when the block is decoded");
         self.key = key.to_vec();
         self.value = value.to_vec();
     }
 
 }
-
@@ -0,0 +1,28 @@
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The source of the current time used to expire the values written by `put_with_ttl`, tests
+/// inject their own to move time forward.
+pub trait Clock: Send + Sync {
+    /// Milliseconds since the UNIX epoch, it must never go backward.
+    fn now(&self) -> u64;
+}
+
+impl fmt::Debug for dyn Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Clock({})", self.now())
+    }
+}
+
+/// The system clock.
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or(0)
+    }
+}
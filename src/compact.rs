@@ -0,0 +1,403 @@
+mod filter;
+mod leveled;
+mod tiered;
+
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use anyhow::Result;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+pub use filter::{CompactionDecision, CompactionFilter, CompactionFilterContext};
+pub use leveled::{LeveledCompactionController, LeveledCompactionOptions, LeveledCompactionTask};
+pub use tiered::{TieredCompactionController, TieredCompactionOptions, TieredCompactionTask};
+
+use crate::iterators::merge_iterator::MergeIterator;
+use crate::key::{decode_value_with_expiry, encode_value_with_expiry, live_value, InternalKey, ValueType};
+use crate::iterators::StorageIterator;
+use crate::lsm_storage::{LsmStorageCore, LsmStorageInner, SharedState};
+use crate::manifest::{ColumnFamilyLayout, ManifestRecord};
+use crate::merge_operator::collapse_merge_operands;
+use crate::range_tombstone::{FragmentedRangeTombstones, RangeTombstone};
+use crate::table::{SsTable, SsTableBuilder, SsTableIterator};
+use crate::write_stall::BackgroundJob;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum CompactionTask {
+    Leveled(LeveledCompactionTask),
+    Tiered(TieredCompactionTask),
+}
+
+impl CompactionTask {
+    /// Apply the result of the task to the structure of the tree, `output` are the IDs of the
+    /// SSTs produced by the task.
+    pub fn apply(&self, layout: &mut ColumnFamilyLayout, output: &[usize]) {
+        match self {
+            CompactionTask::Leveled(task) => task.apply(layout, output),
+            CompactionTask::Tiered(task) => task.apply(layout, output),
+        }
+    }
+
+    /// IDs of the SSTs compacted by the task.
+    fn input_sst_ids(&self) -> Vec<usize> {
+        match self {
+            CompactionTask::Leveled(task) => task
+                .upper_level_sst_ids
+                .iter()
+                .chain(task.lower_level_sst_ids.iter())
+                .copied()
+                .collect(),
+            CompactionTask::Tiered(task) => task.tiers.iter().flatten().copied().collect(),
+        }
+    }
+
+    fn compact_to_bottom_level(&self) -> bool {
+        match self {
+            CompactionTask::Leveled(task) => task.is_lower_level_bottom_level,
+            CompactionTask::Tiered(task) => task.bottom_tier_included,
+        }
+    }
+
+    /// The level the output of the task goes to, a tiered compaction outputs the latest sorted
+    /// run.
+    fn output_level(&self) -> usize {
+        match self {
+            CompactionTask::Leveled(task) => task.lower_level,
+            CompactionTask::Tiered(_) => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum CompactionOptions {
+    /// No compaction, every flushed SST stays in L0.
+    NoCompaction,
+    /// Leveled compaction, each level is a sorted run whose target size grows with its depth.
+    Leveled(LeveledCompactionOptions),
+    /// Tiered (universal) compaction, sorted runs of similar size are compacted together. It
+    /// has less write amplification but more read amplification than leveled compaction.
+    Tiered(TieredCompactionOptions),
+}
+
+pub(crate) enum CompactionController {
+    NoCompaction,
+    Leveled(LeveledCompactionController),
+    Tiered(TieredCompactionController),
+}
+
+impl CompactionController {
+    pub(crate) fn new(options: &CompactionOptions) -> Self {
+        match options {
+            CompactionOptions::NoCompaction => CompactionController::NoCompaction,
+            CompactionOptions::Leveled(options) => {
+                CompactionController::Leveled(LeveledCompactionController::new(options.clone()))
+            }
+            CompactionOptions::Tiered(options) => {
+                CompactionController::Tiered(TieredCompactionController::new(options.clone()))
+            }
+        }
+    }
+
+    /// Number of levels below L0 the tree starts with.
+    pub(crate) fn num_of_levels(&self) -> usize {
+        match self {
+            CompactionController::NoCompaction | CompactionController::Tiered(_) => 0,
+            CompactionController::Leveled(controller) => controller.num_of_levels(),
+        }
+    }
+
+    /// Bytes the compaction has to rewrite to catch up with the writes.
+    pub(crate) fn estimate_pending_compaction_bytes(&self, snapshot: &LsmStorageInner) -> u64 {
+        match self {
+            CompactionController::NoCompaction => 0,
+            CompactionController::Leveled(controller) => {
+                controller.estimate_pending_compaction_bytes(snapshot)
+            }
+            CompactionController::Tiered(controller) => {
+                controller.estimate_pending_compaction_bytes(snapshot)
+            }
+        }
+    }
+
+    fn generate_compaction_task(&self, snapshot: &LsmStorageInner) -> Option<CompactionTask> {
+        match self {
+            CompactionController::NoCompaction => None,
+            CompactionController::Leveled(controller) => controller
+                .generate_compaction_task(snapshot)
+                .map(CompactionTask::Leveled),
+            CompactionController::Tiered(controller) => controller
+                .generate_compaction_task(snapshot)
+                .map(CompactionTask::Tiered),
+        }
+    }
+}
+
+impl LsmStorageCore {
+    /// Merge the input SSTs of `task` into new SSTs of about `target_sst_size`.
+    fn compact(
+        &self,
+        snapshot: &LsmStorageInner,
+        task: &CompactionTask,
+    ) -> Result<Vec<Arc<SsTable>>> {
+        // read after the snapshot of the tree, a snapshot taken later sees every input version
+        let live_snapshots = self.shared.mvcc.live_snapshots();
+        // range tombstones in any SST may delete the input versions, the ones in memtables are
+        // not used, they may be lost if the WAL is disabled
+        let comparator = &self.options.comparator;
+        let fragments = FragmentedRangeTombstones::merge(
+            comparator.clone(),
+            snapshot
+                .sstables()
+                .map(|sst| sst.range_tombstone_fragments()),
+        );
+        // a range tombstone deletes a version for every reader if no snapshot is older than it
+        let deletes_for_all = |tombstone: &RangeTombstone| {
+            live_snapshots
+                .first()
+                .is_none_or(|&oldest| tombstone.seq <= oldest)
+        };
+
+        // from latest to earliest, so the merge iterator keeps the latest value of a key
+        let input_sst_ids = task.input_sst_ids();
+        let sstables: Vec<&Arc<SsTable>> = snapshot
+            .l0_sstables
+            .iter()
+            .rev()
+            .chain(snapshot.levels.iter().flatten())
+            .filter(|sst| input_sst_ids.contains(&sst.sst_id()))
+            .collect();
+        let mut iters = Vec::new();
+        for sst in &sstables {
+            // an SST whose keys are all deleted by a range tombstone is dropped without reading it
+            let deleted = sst.num_of_blocks() > 0
+                && snapshot
+                    .sstables()
+                    .flat_map(|sst| sst.range_tombstones.iter())
+                    .any(|tombstone| {
+                        tombstone.seq > sst.max_seq
+                            && tombstone.contains(&**comparator, sst.first_key())
+                            && tombstone.contains(&**comparator, sst.last_key())
+                            && deletes_for_all(tombstone)
+                    });
+            if !deleted {
+                iters.push(Box::new(SsTableIterator::create_and_seek_to_first(
+                    (*sst).clone(),
+                )?));
+            }
+        }
+        let mut iter = MergeIterator::create_with_comparator(iters, comparator.clone());
+
+        let compact_to_bottom_level = task.compact_to_bottom_level();
+        // an expired value is a delete for every reader, now and later
+        let now = self.options.clock.now();
+        let filter_context = CompactionFilterContext {
+            output_level: task.output_level(),
+            is_bottommost: compact_to_bottom_level,
+        };
+        let mut output = Vec::new();
+        let mut sst_id = self.shared.next_sst_id();
+        let mut builder = self.new_sst_builder(sst_id);
+        // a range tombstone of the input is useless once it deletes a version for every reader
+        // and no SST outside the compaction has a key in its range, it goes to the first output
+        // SST otherwise
+        for tombstone in sstables.iter().flat_map(|sst| sst.range_tombstones.iter()) {
+            let overlaps_others = snapshot.sstables().any(|sst| {
+                !input_sst_ids.contains(&sst.sst_id())
+                    && sst.num_of_blocks() > 0
+                    && tombstone.overlaps(&**comparator, sst.first_key(), sst.last_key())
+            });
+            if overlaps_others || !deletes_for_all(tombstone) {
+                builder.add_range_tombstone(tombstone.clone());
+            }
+        }
+        while iter.is_valid() {
+            let key = iter.key().to_vec();
+            let mut versions = Vec::new();
+            while iter.is_valid() && iter.key() == &key[..] {
+                let expired = iter.value_type() == ValueType::PutWithTtl
+                    && live_value(ValueType::PutWithTtl, iter.value(), now).is_none();
+                if expired {
+                    versions.push((iter.seq(), ValueType::Delete, Bytes::new()));
+                } else {
+                    versions.push((
+                        iter.seq(),
+                        iter.value_type(),
+                        Bytes::copy_from_slice(iter.value()),
+                    ));
+                }
+                iter.next()?;
+            }
+            let covering_seqs = fragments.covering_seqs(&key);
+            if let Some(operator) = self.options.merge_operator.as_deref() {
+                versions = collapse_merge_operands(
+                    operator,
+                    &key,
+                    versions,
+                    &live_snapshots,
+                    covering_seqs,
+                    compact_to_bottom_level,
+                )?;
+            }
+            // the sequence number of the previous put or delete, a merge operand doesn't hide the
+            // versions below it
+            let mut prev_seq: Option<u64> = None;
+            for (seq, mut value_type, mut value) in versions {
+                // the version is hidden from the readers at or after the next version or the oldest
+                // range tombstone deleting it
+                let deleted_at = covering_seqs
+                    .iter()
+                    .rev()
+                    .find(|&&tombstone_seq| tombstone_seq > seq);
+                let hidden_at = match (prev_seq, deleted_at) {
+                    (Some(prev_seq), Some(&deleted_at)) => Some(prev_seq.min(deleted_at)),
+                    (prev_seq, deleted_at) => prev_seq.or(deleted_at.copied()),
+                };
+                // the latest version is visible to new readers, an older one only to the snapshots
+                // before it is hidden
+                let visible = match hidden_at {
+                    None => true,
+                    Some(hidden_at) => live_snapshots
+                        .iter()
+                        .any(|&snapshot| seq <= snapshot && snapshot < hidden_at),
+                };
+                // the filter only sees the latest value, and never changes what a snapshot sees
+                if let Some(filter) = self.options.compaction_filter.as_deref() {
+                    let unseen_by_snapshots =
+                        live_snapshots.last().is_none_or(|&newest| newest < seq);
+                    if hidden_at.is_none() && value_type.is_put() && unseen_by_snapshots {
+                        let decision = match value_type {
+                            ValueType::PutWithTtl => filter.filter(
+                                &filter_context,
+                                &key,
+                                decode_value_with_expiry(&value).1,
+                            ),
+                            _ => filter.filter(&filter_context, &key, &value),
+                        };
+                        match decision {
+                            CompactionDecision::Keep => {}
+                            // the older versions in lower levels are deleted too
+                            CompactionDecision::Remove => {
+                                value_type = ValueType::Delete;
+                                value = Bytes::new();
+                            }
+                            // the new value expires at the same time
+                            CompactionDecision::ChangeValue(new_value) => {
+                                value = match value_type {
+                                    ValueType::PutWithTtl => encode_value_with_expiry(
+                                        &new_value,
+                                        decode_value_with_expiry(&value).0,
+                                    ),
+                                    _ => new_value,
+                                }
+                            }
+                        }
+                    }
+                }
+                // no older data below and no snapshot before it, the tombstone is useless
+                let useless_tombstone = compact_to_bottom_level
+                    && value_type == ValueType::Delete
+                    && live_snapshots.first().is_none_or(|&oldest| seq <= oldest);
+                if visible && !useless_tombstone {
+                    builder.add(InternalKey::new(&key, seq, value_type).as_bytes(), &value);
+                }
+                if value_type != ValueType::Merge {
+                    prev_seq = Some(seq);
+                }
+            }
+            // versions of a key never span two SSTs of a level
+            if builder.estimated_size() >= self.options.target_sst_size {
+                let next_sst_id = self.shared.next_sst_id();
+                let builder = std::mem::replace(&mut builder, self.new_sst_builder(next_sst_id));
+                output.push(self.build_sst(std::mem::replace(&mut sst_id, next_sst_id), builder)?);
+            }
+        }
+        if !builder.is_empty() {
+            output.push(self.build_sst(sst_id, builder)?);
+        }
+        Ok(output)
+    }
+
+    fn build_sst(&self, sst_id: usize, builder: SsTableBuilder) -> Result<Arc<SsTable>> {
+        Ok(Arc::new(
+            builder.build(sst_id, Some(self.shared.block_cache.clone()))?,
+        ))
+    }
+
+    /// Run compaction tasks until the compaction controller generates no more task.
+    pub(crate) fn trigger_compaction(&self) -> Result<()> {
+        let _compaction_lock = self.compaction_lock.lock();
+        loop {
+            // the SSTs of a dropped family are deleted
+            if self.is_dropped() {
+                return Ok(());
+            }
+            let snapshot = {
+                let guard = self.inner.read();
+                Arc::clone(&guard)
+            };
+            let Some(task) = self
+                .compaction_controller
+                .generate_compaction_task(&snapshot)
+            else {
+                return Ok(());
+            };
+            let output = self.compact(&snapshot, &task)?;
+            let output_ids: Vec<usize> = output.iter().map(|sst| sst.sst_id()).collect();
+
+            {
+                let mut guard = self.inner.write();
+                let mut snapshot = guard.as_ref().clone();
+                // L0 may have new SSTs flushed during the compaction, the task only removes the
+                // SSTs it compacted
+                let layout = self.map_layout(&snapshot, |layout| task.apply(layout, &output_ids));
+                snapshot.apply_layout(&layout, output);
+                let record = ManifestRecord::Compaction {
+                    column_family: self.id,
+                    task: task.clone(),
+                    output: output_ids,
+                };
+                self.shared.write_manifest(&record)?;
+                *guard = Arc::new(snapshot);
+            }
+            self.notify_write_stall_change();
+
+            // readers holding an old snapshot keep the file open, so it is safe to remove it
+            for sst_id in task.input_sst_ids() {
+                std::fs::remove_file(self.shared.path_of_sst(sst_id))?;
+            }
+        }
+    }
+}
+
+impl SharedState {
+    /// Spawn the thread compacting the column families in the background.
+    pub(crate) fn spawn_compaction_thread(
+        self: &Arc<Self>,
+        rx: crossbeam_channel::Receiver<()>,
+    ) -> Result<JoinHandle<()>> {
+        let this = self.clone();
+        let handle = std::thread::spawn(move || {
+            let ticker = crossbeam_channel::tick(Duration::from_millis(50));
+            loop {
+                crossbeam_channel::select! {
+                    recv(ticker) -> _ => {
+                        // every family is compacted even if an earlier one fails
+                        let results: Vec<Result<()>> = this
+                            .column_families()
+                            .iter()
+                            .map(|column_family| column_family.trigger_compaction())
+                            .collect();
+                        this.record_background_result(
+                            BackgroundJob::Compaction,
+                            results.into_iter().collect(),
+                        );
+                    },
+                    recv(rx) -> _ => return,
+                }
+            }
+        });
+        Ok(handle)
+    }
+}
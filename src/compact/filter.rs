@@ -0,0 +1,44 @@
+use std::fmt;
+
+use bytes::Bytes;
+
+/// What compaction does with a value passed to a [`CompactionFilter`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CompactionDecision {
+    Keep,
+    /// Delete the key, older versions of it are deleted too.
+    Remove,
+    /// Replace the value.
+    ChangeValue(Bytes),
+}
+
+/// The compaction a [`CompactionFilter`] is called from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompactionFilterContext {
+    /// The level the output SSTs go to, L0 is level 0. With tiered compaction the output is
+    /// always the latest sorted run, level 1.
+    pub output_level: usize,
+    /// No level below the output has data.
+    pub is_bottommost: bool,
+}
+
+/// Drops or rewrites values while compaction merges SSTs, e.g. to remove expired records. It is
+/// called with the latest value of each key in the compaction that no live snapshot sees, after
+/// merge operands are resolved. Deletes, merge operands and older versions are not passed to it.
+pub trait CompactionFilter: Send + Sync {
+    /// The name of the filter.
+    fn name(&self) -> &str;
+
+    fn filter(
+        &self,
+        context: &CompactionFilterContext,
+        key: &[u8],
+        value: &[u8],
+    ) -> CompactionDecision;
+}
+
+impl fmt::Debug for dyn CompactionFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CompactionFilter({})", self.name())
+    }
+}
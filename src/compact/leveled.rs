@@ -0,0 +1,189 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::lsm_storage::LsmStorageInner;
+use crate::manifest::ColumnFamilyLayout;
+use crate::table::SsTable;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct LeveledCompactionTask {
+    /// The level compacted from, `None` is L0.
+    pub upper_level: Option<usize>,
+    pub upper_level_sst_ids: Vec<usize>,
+    /// The level compacted into, L1 is 1.
+    pub lower_level: usize,
+    pub lower_level_sst_ids: Vec<usize>,
+    /// No level below `lower_level` has data, tombstones can be dropped.
+    pub is_lower_level_bottom_level: bool,
+}
+
+impl LeveledCompactionTask {
+    /// Remove the compacted SSTs and put `output` into the lower level. The lower level is not
+    /// sorted here, the caller sorts it by key range once the SSTs are opened.
+    pub fn apply(&self, layout: &mut ColumnFamilyLayout, output: &[usize]) {
+        if layout.levels.len() < self.lower_level {
+            layout.levels.resize(self.lower_level, vec![]);
+        }
+        let upper_level = match self.upper_level {
+            None => &mut layout.l0_sstables,
+            Some(level) => &mut layout.levels[level - 1],
+        };
+        upper_level.retain(|id| !self.upper_level_sst_ids.contains(id));
+        let lower_level = &mut layout.levels[self.lower_level - 1];
+        lower_level.retain(|id| !self.lower_level_sst_ids.contains(id));
+        lower_level.extend_from_slice(output);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LeveledCompactionOptions {
+    /// L0 is compacted into L1 once it has this many SSTs.
+    pub level0_file_num_compaction_trigger: usize,
+    /// Number of levels below L0.
+    pub max_levels: usize,
+    /// Target size of L1 in bytes.
+    pub base_level_size: u64,
+    /// The target size of a level is this many times the target size of the level above.
+    pub level_size_multiplier: u64,
+}
+
+impl Default for LeveledCompactionOptions {
+    fn default() -> Self {
+        Self {
+            level0_file_num_compaction_trigger: 4,
+            max_levels: 6,
+            base_level_size: 64 << 20,
+            level_size_multiplier: 10,
+        }
+    }
+}
+
+pub struct LeveledCompactionController {
+    options: LeveledCompactionOptions,
+}
+
+impl LeveledCompactionController {
+    pub fn new(options: LeveledCompactionOptions) -> Self {
+        Self { options }
+    }
+
+    pub fn num_of_levels(&self) -> usize {
+        self.options.max_levels
+    }
+
+    /// Compact L0 if it has too many SSTs, otherwise compact one SST of the level which exceeds
+    /// its target size the most into the next level.
+    pub fn generate_compaction_task(
+        &self,
+        snapshot: &LsmStorageInner,
+    ) -> Option<LeveledCompactionTask> {
+        let levels = &snapshot.levels;
+        if snapshot.l0_sstables.len() >= self.options.level0_file_num_compaction_trigger {
+            let (first_key, last_key) = snapshot
+                .l0_sstables
+                .iter()
+                .filter_map(|sst| sst.key_range().map(|range| (sst.comparator(), range)))
+                .reduce(|(comparator, (first, last)), (_, (start, end))| {
+                    let first = if comparator.compare(start, first).is_lt() {
+                        start
+                    } else {
+                        first
+                    };
+                    let last = if comparator.compare(end, last).is_gt() {
+                        end
+                    } else {
+                        last
+                    };
+                    (comparator, (first, last))
+                })
+                .map(|(_, range)| range)
+                .unwrap_or_default();
+            return Some(LeveledCompactionTask {
+                upper_level: None,
+                upper_level_sst_ids: snapshot
+                    .l0_sstables
+                    .iter()
+                    .map(|sst| sst.sst_id())
+                    .collect(),
+                lower_level: 1,
+                lower_level_sst_ids: find_overlapping_ssts(levels.first()?, first_key, last_key),
+                is_lower_level_bottom_level: levels.iter().skip(1).all(|level| level.is_empty()),
+            });
+        }
+
+        let mut compact_level = None;
+        let mut max_ratio = 1.0;
+        for (idx, (size, target_size)) in self.level_sizes(snapshot).enumerate() {
+            let ratio = size as f64 / target_size as f64;
+            if ratio > max_ratio {
+                max_ratio = ratio;
+                compact_level = Some(idx);
+            }
+        }
+        let idx = compact_level?;
+
+        // compact the oldest SST of the level
+        let sst = levels[idx].iter().min_by_key(|sst| sst.sst_id())?;
+        let (first_key, last_key) = sst.key_range().unwrap_or_default();
+        Some(LeveledCompactionTask {
+            upper_level: Some(idx + 1),
+            upper_level_sst_ids: vec![sst.sst_id()],
+            lower_level: idx + 2,
+            lower_level_sst_ids: find_overlapping_ssts(&levels[idx + 1], first_key, last_key),
+            is_lower_level_bottom_level: levels.iter().skip(idx + 2).all(|level| level.is_empty()),
+        })
+    }
+
+    /// Bytes to be compacted to bring L0 and every level back under their targets.
+    pub fn estimate_pending_compaction_bytes(&self, snapshot: &LsmStorageInner) -> u64 {
+        let mut pending_bytes = 0;
+        if snapshot.l0_sstables.len() >= self.options.level0_file_num_compaction_trigger {
+            pending_bytes += snapshot
+                .l0_sstables
+                .iter()
+                .map(|sst| sst.table_size())
+                .sum::<u64>();
+        }
+        for (size, target_size) in self.level_sizes(snapshot) {
+            pending_bytes += size.saturating_sub(target_size);
+        }
+        pending_bytes
+    }
+
+    /// The size and the target size of every level but the last one, which is never compacted.
+    fn level_sizes<'a>(
+        &self,
+        snapshot: &'a LsmStorageInner,
+    ) -> impl Iterator<Item = (u64, u64)> + 'a {
+        let levels = &snapshot.levels;
+        let (base_level_size, multiplier) = (
+            self.options.base_level_size,
+            self.options.level_size_multiplier,
+        );
+        levels.iter().take(levels.len().saturating_sub(1)).scan(
+            base_level_size,
+            move |target_size, level| {
+                let size: u64 = level.iter().map(|sst| sst.table_size()).sum();
+                let item = (size, *target_size);
+                *target_size = target_size.saturating_mul(multiplier);
+                Some(item)
+            },
+        )
+    }
+}
+
+/// IDs of the SSTs in `level` whose key ranges overlap with `[first_key, last_key]`.
+fn find_overlapping_ssts(level: &[Arc<SsTable>], first_key: &[u8], last_key: &[u8]) -> Vec<usize> {
+    level
+        .iter()
+        .filter(|sst| {
+            sst.key_range().is_some_and(|(start, end)| {
+                let comparator = sst.comparator();
+                !(comparator.compare(end, first_key).is_lt()
+                    || comparator.compare(start, last_key).is_gt())
+            })
+        })
+        .map(|sst| sst.sst_id())
+        .collect()
+}
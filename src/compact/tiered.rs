@@ -0,0 +1,144 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::lsm_storage::LsmStorageInner;
+use crate::manifest::ColumnFamilyLayout;
+use crate::table::SsTable;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct TieredCompactionTask {
+    /// Sorted runs to compact, from latest to earliest, each is a list of SST IDs. Every L0 SST
+    /// is a sorted run of its own.
+    pub tiers: Vec<Vec<usize>>,
+    /// The earliest sorted run is compacted, tombstones can be dropped.
+    pub bottom_tier_included: bool,
+}
+
+impl TieredCompactionTask {
+    /// Remove the compacted sorted runs and put `output` in front of the levels as the latest
+    /// sorted run. The task always compacts all of L0 and the latest sorted runs of the levels,
+    /// SSTs flushed to L0 during the compaction are newer than `output`.
+    pub fn apply(&self, layout: &mut ColumnFamilyLayout, output: &[usize]) {
+        let compacted = |id: &usize| self.tiers.iter().flatten().any(|x| x == id);
+        layout.l0_sstables.retain(|id| !compacted(id));
+        layout.levels.retain(|run| !run.iter().any(compacted));
+        if !output.is_empty() {
+            layout.levels.insert(0, output.to_vec());
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TieredCompactionOptions {
+    /// Compaction starts once there are this many sorted runs.
+    pub num_tiers: usize,
+    /// All sorted runs are compacted into one once the size of all but the earliest sorted run
+    /// is this percentage of the earliest one.
+    pub max_size_amplification_percent: usize,
+    /// A sorted run is compacted with the latest ones if it is at most `100 + size_ratio`
+    /// percent of their total size.
+    pub size_ratio: usize,
+    /// Compact at least this many sorted runs at a time.
+    pub min_merge_width: usize,
+}
+
+impl Default for TieredCompactionOptions {
+    fn default() -> Self {
+        Self {
+            num_tiers: 8,
+            max_size_amplification_percent: 200,
+            size_ratio: 1,
+            min_merge_width: 2,
+        }
+    }
+}
+
+pub struct TieredCompactionController {
+    options: TieredCompactionOptions,
+}
+
+impl TieredCompactionController {
+    pub fn new(options: TieredCompactionOptions) -> Self {
+        Self { options }
+    }
+
+    pub fn generate_compaction_task(
+        &self,
+        snapshot: &LsmStorageInner,
+    ) -> Option<TieredCompactionTask> {
+        // sorted runs from latest to earliest
+        let tiers: Vec<(Vec<usize>, u64)> = snapshot
+            .l0_sstables
+            .iter()
+            .rev()
+            .map(|sst| (vec![sst.sst_id()], sst.table_size()))
+            .chain(snapshot.levels.iter().map(|run| {
+                (
+                    run.iter().map(|sst| sst.sst_id()).collect(),
+                    run.iter().map(|sst| sst.table_size()).sum(),
+                )
+            }))
+            .collect();
+        if tiers.len() < self.options.num_tiers.max(2) {
+            return None;
+        }
+        let num_of_l0_tiers = snapshot.l0_sstables.len();
+        let task = |num_of_tiers: usize| {
+            // L0 is always compacted as a whole, so the output can be the latest sorted run
+            let num_of_tiers = num_of_tiers.max(num_of_l0_tiers).min(tiers.len());
+            Some(TieredCompactionTask {
+                tiers: tiers[..num_of_tiers]
+                    .iter()
+                    .map(|(ids, _)| ids.clone())
+                    .collect(),
+                bottom_tier_included: num_of_tiers == tiers.len(),
+            })
+        };
+
+        // space amplification
+        let (_, last_size) = tiers.last()?;
+        let others_size: u64 = tiers[..tiers.len() - 1].iter().map(|(_, size)| size).sum();
+        if others_size * 100 >= *last_size * self.options.max_size_amplification_percent as u64 {
+            return task(tiers.len());
+        }
+
+        // size ratio
+        let mut total_size = tiers[0].1;
+        let mut num_of_tiers = 1;
+        for (_, size) in &tiers[1..] {
+            if total_size * (100 + self.options.size_ratio as u64) < size * 100 {
+                break;
+            }
+            total_size += size;
+            num_of_tiers += 1;
+        }
+        if num_of_tiers >= self.options.min_merge_width {
+            return task(num_of_tiers);
+        }
+
+        // reduce the number of sorted runs below the trigger
+        task(
+            (tiers.len() + 2 - self.options.num_tiers)
+                .max(self.options.min_merge_width)
+                .max(2),
+        )
+    }
+
+    /// Bytes to be compacted once there are too many sorted runs, all but the earliest one are
+    /// counted.
+    pub fn estimate_pending_compaction_bytes(&self, snapshot: &LsmStorageInner) -> u64 {
+        let num_of_tiers = snapshot.l0_sstables.len() + snapshot.levels.len();
+        if num_of_tiers < self.options.num_tiers.max(2) {
+            return 0;
+        }
+        let run_size = |run: &[Arc<SsTable>]| run.iter().map(|sst| sst.table_size()).sum::<u64>();
+        let total_size = run_size(&snapshot.l0_sstables)
+            + snapshot.levels.iter().map(|run| run_size(run)).sum::<u64>();
+        let earliest_size = match snapshot.levels.last() {
+            Some(run) => run_size(run),
+            None => run_size(&snapshot.l0_sstables[..1]),
+        };
+        total_size - earliest_size
+    }
+}
@@ -0,0 +1,44 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+/// Orders the user keys of a column family. The comparator is chosen when the column family is
+/// created and its name is recorded in the manifest and in every SST, opening them with a
+/// comparator of another name fails. Keys comparing equal must be byte-identical.
+pub trait Comparator: Send + Sync {
+    /// The name of the comparator, changing the order of keys requires a new name.
+    fn name(&self) -> &str;
+
+    /// Compare two user keys.
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+
+    /// Whether the keys starting with the same bytes are contiguous and not before those bytes,
+    /// as in the bytewise order. `scan_prefix` and prefix extractors require it, other orders
+    /// would return only part of the keys with a prefix.
+    fn supports_prefix_scan(&self) -> bool {
+        false
+    }
+}
+
+impl fmt::Debug for dyn Comparator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Comparator({})", self.name())
+    }
+}
+
+/// Orders keys lexicographically by their bytes, the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BytewiseComparator;
+
+impl Comparator for BytewiseComparator {
+    fn name(&self) -> &str {
+        "lsm.BytewiseComparator"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+
+    fn supports_prefix_scan(&self) -> bool {
+        true
+    }
+}
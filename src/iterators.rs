@@ -1,16 +1,111 @@
+pub mod concat_iterator;
 pub mod merge_iterator;
 pub mod two_merge_iterator;
 
+use std::cmp::Ordering;
+
+use anyhow::{bail, Result};
+
+use crate::comparator::Comparator;
+use crate::key::{self, ValueType};
+
+/// Iterates over versions of keys, ordered by key, then by sequence number descending.
 pub trait StorageIterator {
     /// Get the current value.
     fn value(&self) -> &[u8];
 
-    /// Get the current key.
+    /// Get the current user key.
     fn key(&self) -> &[u8];
 
+    /// Get the sequence number of the current version. Iterators over unversioned data only
+    /// have version 0.
+    fn seq(&self) -> u64 {
+        0
+    }
+
+    /// Get the value type of the current version.
+    fn value_type(&self) -> ValueType {
+        ValueType::Put
+    }
+
     /// Check if the current iterator is valid.
     fn is_valid(&self) -> bool;
 
     /// Move to the next position.
-    fn next(&mut self) -> anyhow::Result<()>;
+    fn next(&mut self) -> Result<()>;
+
+    /// Move to the previous position, iterators only moving forward don't support it.
+    fn prev(&mut self) -> Result<()> {
+        bail!("backward iteration is not supported")
+    }
+
+    /// Seek to the first version of the first key.
+    fn seek_to_first(&mut self) -> Result<()> {
+        bail!("seeking is not supported")
+    }
+
+    /// Seek to the last (oldest) version of the last key.
+    fn seek_to_last(&mut self) -> Result<()> {
+        bail!("seeking is not supported")
+    }
+
+    /// Seek to the first (latest) version of the first key which >= `key`.
+    fn seek(&mut self, _key: &[u8]) -> Result<()> {
+        bail!("seeking is not supported")
+    }
+
+    /// Seek to the last (oldest) version of the last key which <= `key`.
+    fn seek_for_prev(&mut self, _key: &[u8]) -> Result<()> {
+        bail!("seeking is not supported")
+    }
+}
+
+/// The direction a bidirectional iterator moves in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Direction {
+    Forward,
+    Backward,
+}
+
+impl Direction {
+    /// Orient `ordering` of two versions, the version coming first in the direction is less.
+    pub(crate) fn orient(self, ordering: Ordering) -> Ordering {
+        match self {
+            Direction::Forward => ordering,
+            Direction::Backward => ordering.reverse(),
+        }
+    }
+}
+
+/// Move `iter` to the first version after the version `(key, seq)` in `direction`, keys are
+/// ordered by `comparator`, skipping the version itself. Used by merging iterators to bring their
+/// children to the same side of the current version when the direction is reversed.
+pub(crate) fn seek_past(
+    iter: &mut impl StorageIterator,
+    comparator: &dyn Comparator,
+    key: &[u8],
+    seq: u64,
+    direction: Direction,
+) -> Result<()> {
+    match direction {
+        Direction::Forward => iter.seek(key)?,
+        Direction::Backward => iter.seek_for_prev(key)?,
+    }
+    while iter.is_valid()
+        && direction
+            .orient(key::compare_versions(
+                comparator,
+                iter.key(),
+                iter.seq(),
+                key,
+                seq,
+            ))
+            .is_le()
+    {
+        match direction {
+            Direction::Forward => iter.next()?,
+            Direction::Backward => iter.prev()?,
+        }
+    }
+    Ok(())
 }
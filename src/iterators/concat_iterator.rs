@@ -0,0 +1,168 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use super::StorageIterator;
+use crate::key::ValueType;
+use crate::table::{SsTable, SsTableIterator};
+
+/// Concat multiple iterators ordered in key-order and their key ranges do not overlap. We do not
+/// want to create the iterators when initializing this iterator to reduce the overhead of seeking.
+pub struct SstConcatIterator {
+    current: Option<SsTableIterator>,
+    /// The index of the SSTable `current` iterates over.
+    sst_idx: usize,
+    sstables: Vec<Arc<SsTable>>,
+}
+
+impl SstConcatIterator {
+    /// Create a new iterator and seek to the first key-value pair of the first SSTable.
+    pub fn create_and_seek_to_first(sstables: Vec<Arc<SsTable>>) -> Result<Self> {
+        let mut iter = Self::new(sstables);
+        StorageIterator::seek_to_first(&mut iter)?;
+        Ok(iter)
+    }
+
+    /// Create a new iterator and seek to the first version of the first key which >= `key`, the
+    /// SSTable to start from is found by binary search on the key ranges.
+    pub fn create_and_seek_to_key(sstables: Vec<Arc<SsTable>>, key: &[u8]) -> Result<Self> {
+        let mut iter = Self::new(sstables);
+        iter.seek(key)?;
+        Ok(iter)
+    }
+
+    /// Create a new iterator, it is invalid until positioned by a seek.
+    pub fn new(sstables: Vec<Arc<SsTable>>) -> Self {
+        Self {
+            current: None,
+            sst_idx: 0,
+            sstables,
+        }
+    }
+
+    /// Move forward to the first key of the following SSTables if the current one has no more.
+    fn move_until_valid(&mut self) -> Result<()> {
+        while let Some(iter) = self.current.as_mut() {
+            if iter.is_valid() {
+                break;
+            }
+            if self.sst_idx + 1 >= self.sstables.len() {
+                self.current = None;
+            } else {
+                self.sst_idx += 1;
+                self.current = Some(SsTableIterator::create_and_seek_to_first(
+                    self.sstables[self.sst_idx].clone(),
+                )?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Move backward to the last key of the preceding SSTables if the current one has no more.
+    fn move_back_until_valid(&mut self) -> Result<()> {
+        while let Some(iter) = self.current.as_mut() {
+            if iter.is_valid() {
+                break;
+            }
+            if self.sst_idx == 0 {
+                self.current = None;
+            } else {
+                self.sst_idx -= 1;
+                self.current = Some(SsTableIterator::create_and_seek_to_last(
+                    self.sstables[self.sst_idx].clone(),
+                )?);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StorageIterator for SstConcatIterator {
+    fn value(&self) -> &[u8] {
+        self.current.as_ref().unwrap().value()
+    }
+
+    fn key(&self) -> &[u8] {
+        self.current.as_ref().unwrap().key()
+    }
+
+    fn seq(&self) -> u64 {
+        self.current.as_ref().unwrap().seq()
+    }
+
+    fn value_type(&self) -> ValueType {
+        self.current.as_ref().unwrap().value_type()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.current.as_ref().map(|x| x.is_valid()).unwrap_or(false)
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.current.as_mut().unwrap().next()?;
+        self.move_until_valid()?;
+        Ok(())
+    }
+
+    fn prev(&mut self) -> Result<()> {
+        self.current.as_mut().unwrap().prev()?;
+        self.move_back_until_valid()?;
+        Ok(())
+    }
+
+    fn seek_to_first(&mut self) -> Result<()> {
+        self.current = None;
+        if !self.sstables.is_empty() {
+            self.sst_idx = 0;
+            self.current = Some(SsTableIterator::create_and_seek_to_first(
+                self.sstables[0].clone(),
+            )?);
+            self.move_until_valid()?;
+        }
+        Ok(())
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        self.current = None;
+        if !self.sstables.is_empty() {
+            self.sst_idx = self.sstables.len() - 1;
+            self.current = Some(SsTableIterator::create_and_seek_to_last(
+                self.sstables[self.sst_idx].clone(),
+            )?);
+            self.move_back_until_valid()?;
+        }
+        Ok(())
+    }
+
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        self.current = None;
+        let idx = self
+            .sstables
+            .partition_point(|table| table.comparator().compare(table.last_key(), key).is_lt());
+        if idx < self.sstables.len() {
+            self.sst_idx = idx;
+            self.current = Some(SsTableIterator::create_and_seek_to_key(
+                self.sstables[idx].clone(),
+                key,
+            )?);
+            self.move_until_valid()?;
+        }
+        Ok(())
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()> {
+        self.current = None;
+        let idx = self
+            .sstables
+            .partition_point(|table| table.comparator().compare(table.first_key(), key).is_le());
+        if idx > 0 {
+            self.sst_idx = idx - 1;
+            self.current = Some(SsTableIterator::create_and_seek_for_prev(
+                self.sstables[self.sst_idx].clone(),
+                key,
+            )?);
+            self.move_back_until_valid()?;
+        }
+        Ok(())
+    }
+}
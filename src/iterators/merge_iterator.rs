@@ -1,16 +1,26 @@
 use std::cmp::{self};
 use std::collections::binary_heap::PeekMut;
 use std::collections::BinaryHeap;
+use std::sync::Arc;
 
 use anyhow::Result;
 
-use super::StorageIterator;
+use super::{seek_past, Direction, StorageIterator};
+use crate::comparator::{BytewiseComparator, Comparator};
+use crate::key::{self, ValueType};
 
-struct HeapWrapper<I: StorageIterator>(pub usize, pub Box<I>);
+/// A child iterator with its index, ordered by its current version in the direction of the heap,
+/// keys are ordered by the comparator.
+struct HeapWrapper<I: StorageIterator>(
+    pub usize,
+    pub Box<I>,
+    pub Direction,
+    pub Arc<dyn Comparator>,
+);
 
 impl<I: StorageIterator> PartialEq for HeapWrapper<I> {
     fn eq(&self, other: &Self) -> bool {
-        self.partial_cmp(other).unwrap() == cmp::Ordering::Equal
+        self.cmp(other) == cmp::Ordering::Equal
     }
 }
 
@@ -18,60 +28,168 @@ impl<I: StorageIterator> Eq for HeapWrapper<I> {}
 
 impl<I: StorageIterator> PartialOrd for HeapWrapper<I> {
     fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
-        match self.1.key().cmp(other.1.key()) {
-            cmp::Ordering::Greater => Some(cmp::Ordering::Greater),
-            cmp::Ordering::Less => Some(cmp::Ordering::Less),
-            cmp::Ordering::Equal => self.0.partial_cmp(&other.0),
-        }
-            .map(|x| x.reverse())
+        Some(self.cmp(other))
     }
 }
 
 impl<I: StorageIterator> Ord for HeapWrapper<I> {
     fn cmp(&self, other: &Self) -> cmp::Ordering {
-        self.partial_cmp(other).unwrap()
+        match self.2.orient(key::compare_versions(
+            &*self.3,
+            self.1.key(),
+            self.1.seq(),
+            other.1.key(),
+            other.1.seq(),
+        )) {
+            cmp::Ordering::Greater => cmp::Ordering::Greater,
+            cmp::Ordering::Less => cmp::Ordering::Less,
+            cmp::Ordering::Equal => self.0.cmp(&other.0),
+        }
+        .reverse()
     }
 }
 
-/// Merge multiple iterators of the same type. If the same key occurs multiple times in some
-/// iterators, prefer the one with smaller index.
+/// Merge multiple iterators of the same type. Versions are ordered by key, then by sequence number
+/// descending. If the same version of a key occurs multiple times in some iterators, prefer the
+/// one with smaller index, in both directions.
 pub struct MergeIterator<I: StorageIterator> {
     iters: BinaryHeap<HeapWrapper<I>>,
     current: Option<HeapWrapper<I>>,
+    /// Children with no more versions in the direction, they are kept to be repositioned.
+    exhausted: Vec<HeapWrapper<I>>,
+    direction: Direction,
+    comparator: Arc<dyn Comparator>,
 }
 
 impl<I: StorageIterator> MergeIterator<I> {
-    pub fn create(mut iters: Vec<Box<I>>) -> Self {
-        let mut heap = BinaryHeap::new();
-
-        if iters.is_empty() {
-            return Self {
-                iters: heap,
-                current: None,
-            };
+    /// Merge iterators whose keys are ordered bytewise.
+    pub fn create(iters: Vec<Box<I>>) -> Self {
+        Self::create_with_comparator(iters, Arc::new(BytewiseComparator))
+    }
+
+    /// Merge iterators whose keys are ordered by `comparator`.
+    pub fn create_with_comparator(iters: Vec<Box<I>>, comparator: Arc<dyn Comparator>) -> Self {
+        let mut iter = Self {
+            iters: BinaryHeap::new(),
+            current: None,
+            exhausted: Vec::new(),
+            direction: Direction::Forward,
+            comparator: comparator.clone(),
+        };
+        iter.rebuild(
+            iters
+                .into_iter()
+                .enumerate()
+                .map(|(idx, iter)| HeapWrapper(idx, iter, Direction::Forward, comparator.clone()))
+                .collect(),
+        );
+        iter
+    }
+
+    /// Take every child out of the iterator.
+    fn take_all(&mut self) -> Vec<HeapWrapper<I>> {
+        let mut iters: Vec<HeapWrapper<I>> = std::mem::take(&mut self.iters).into_vec();
+        iters.append(&mut self.exhausted);
+        iters.extend(self.current.take());
+        iters
+    }
+
+    /// Put the children back, the valid ones into the heap.
+    fn rebuild(&mut self, iters: Vec<HeapWrapper<I>>) {
+        for iter in iters {
+            if iter.1.is_valid() {
+                self.iters.push(iter);
+            } else {
+                self.exhausted.push(iter);
+            }
         }
+        self.current = self.iters.pop();
+    }
 
-        if iters.iter().all(|x| !x.is_valid()) {
-            // all invalid, select last one
-            return Self {
-                iters: heap,
-                current: Some(HeapWrapper(0, iters.pop().unwrap())),
-            };
+    /// Reposition every child with `seek` and merge them in `direction`.
+    fn reposition(
+        &mut self,
+        direction: Direction,
+        mut seek: impl FnMut(&mut I) -> Result<()>,
+    ) -> Result<()> {
+        let mut iters = self.take_all();
+        let mut result = Ok(());
+        for iter in &mut iters {
+            iter.2 = direction;
+            if result.is_ok() {
+                result = seek(&mut iter.1);
+            }
+        }
+        self.direction = direction;
+        self.rebuild(iters);
+        result
+    }
+
+    /// Reverse the direction at the current version. The other children are after the current
+    /// version in the old direction, they are moved to the versions after it in `direction`.
+    fn switch_direction(&mut self, direction: Direction) -> Result<()> {
+        let mut current = self.current.take().unwrap();
+        let (key, seq) = (current.1.key().to_vec(), current.1.seq());
+        let mut iters = self.take_all();
+        let mut result = Ok(());
+        for iter in &mut iters {
+            iter.2 = direction;
+            if result.is_ok() {
+                result = seek_past(&mut *iter.1, &*self.comparator, &key, seq, direction);
+            }
+        }
+        current.2 = direction;
+        iters.push(current);
+        self.direction = direction;
+        self.rebuild(iters);
+        result
+    }
+
+    // 1、堆的排序规则：key小的优先，同样key，所在iter小的优先
+    // 2、next：如果堆顶的iter和current的key相同，则堆顶iter.next，如果iter.next到底了则pop出堆
+    // 3、current.next
+    // 4、如果current无效，则从堆pop置为新current，返回
+    // 5、反之，则和堆顶比较，如果堆顶的小于current（key&iter顺序），则置换current（pop&push）
+    /// Move to the next version in the direction, `step` moves a child in the direction.
+    fn step(&mut self, step: impl Fn(&mut I) -> Result<()>) -> Result<()> {
+        let current = self.current.as_mut().unwrap();
+        // Pop the item out of the heap if they have the same value.
+        while let Some(mut inner_iter) = self.iters.peek_mut() {
+            if inner_iter.1.key() == current.1.key() && inner_iter.1.seq() == current.1.seq() {
+                // Case 1: an error occurred when calling `next`.
+                if let e @ Err(_) = step(&mut inner_iter.1) {
+                    self.exhausted.push(PeekMut::pop(inner_iter));
+                    return e;
+                }
+                if !inner_iter.1.is_valid() {
+                    self.exhausted.push(PeekMut::pop(inner_iter));
+                }
+            } else {
+                break;
+            }
         }
+        // current's key & value has used, so need go next
+        step(&mut current.1)?;
 
-        for (idx, iter) in iters.into_iter().enumerate() {
-            // here push iters.size HeapWrapper to heap
-            // [iter1, iter2, iter3] => Heap((0, iter1), (1, iter2), (2, iter3))
-            if iter.is_valid() {
-                heap.push(HeapWrapper(idx, iter));
+        // If the current iterator is invalid, pop it out of the heap and select the next one.
+        if !current.1.is_valid() {
+            if let Some(iter) = self.iters.pop() {
+                self.exhausted.push(std::mem::replace(current, iter));
             }
+            return Ok(());
         }
 
-        let current = heap.pop();
-        Self {
-            iters: heap,
-            current,
+        // Otherwise, compare with heap top and swap if necessary.
+        if let Some(mut iter) = self.iters.peek_mut() {
+            // current key & idx smaller than iter, swap
+            // the if condition need reverse, because heap is MaxHeap
+            if *current < *iter {
+                // heap.pop(); then heap.push(current);
+                std::mem::swap(current, &mut *iter);
+            }
         }
+
+        Ok(())
     }
 }
 
@@ -84,6 +202,14 @@ impl<I: StorageIterator> StorageIterator for MergeIterator<I> {
         self.current.as_ref().unwrap().1.key()
     }
 
+    fn seq(&self) -> u64 {
+        self.current.as_ref().unwrap().1.seq()
+    }
+
+    fn value_type(&self) -> ValueType {
+        self.current.as_ref().unwrap().1.value_type()
+    }
+
     fn is_valid(&self) -> bool {
         self.current.as_ref()
             .map(|x| x.1.is_valid())
@@ -108,50 +234,33 @@ impl<I: StorageIterator> StorageIterator for MergeIterator<I> {
     // ========== key: "c", value: "4" ===========
     // heap: []
     // current: iter2(key: "c")
-
-    // 1、堆的排序规则：key小的优先，同样key，所在iter小的优先
-    // 2、next：如果堆顶的iter和current的key相同，则堆顶iter.next，如果iter.next到底了则pop出堆
-    // 3、current.next
-    // 4、如果current无效，则从堆pop置为新current，返回
-    // 5、反之，则和堆顶比较，如果堆顶的小于current（key&iter顺序），则置换current（pop&push）
     fn next(&mut self) -> Result<()> {
-        let current = self.current.as_mut().unwrap();
-        // Pop the item out of the heap if they have the same value.
-        while let Some(mut inner_iter) = self.iters.peek_mut() {
-            if inner_iter.1.key() == current.1.key() {
-                // Case 1: an error occurred when calling `next`.
-                if let e @ Err(_) = inner_iter.1.next() {
-                    PeekMut::pop(inner_iter);
-                    return e;
-                }
-                if !inner_iter.1.is_valid() {
-                    PeekMut::pop(inner_iter);
-                }
-            } else {
-                break;
-            }
+        if self.direction == Direction::Backward {
+            self.switch_direction(Direction::Forward)?;
         }
-        // current's key & value has used, so need go next
-        current.1.next()?;
+        self.step(|iter| iter.next())
+    }
 
-        // If the current iterator is invalid, pop it out of the heap and select the next one.
-        if !current.1.is_valid() {
-            if let Some(iter) = self.iters.pop() {
-                self.current = Some(iter);
-            }
-            return Ok(());
+    fn prev(&mut self) -> Result<()> {
+        if self.direction == Direction::Forward {
+            self.switch_direction(Direction::Backward)?;
         }
+        self.step(|iter| iter.prev())
+    }
 
-        // Otherwise, compare with heap top and swap if necessary.
-        if let Some(mut iter) = self.iters.peek_mut() {
-            // current key & idx smaller than iter, swap
-            // the if condition need reverse, because heap is MaxHeap
-            if !(*current >= *iter) {
-                // heap.pop(); then heap.push(current);
-                std::mem::swap(current, &mut *iter);
-            }
-        }
+    fn seek_to_first(&mut self) -> Result<()> {
+        self.reposition(Direction::Forward, |iter| iter.seek_to_first())
+    }
 
-        Ok(())
+    fn seek_to_last(&mut self) -> Result<()> {
+        self.reposition(Direction::Backward, |iter| iter.seek_to_last())
+    }
+
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        self.reposition(Direction::Forward, |iter| iter.seek(key))
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()> {
+        self.reposition(Direction::Backward, |iter| iter.seek_for_prev(key))
     }
 }
@@ -1,28 +1,42 @@
+use std::sync::Arc;
+
 use anyhow::Result;
 
-use super::StorageIterator;
+use super::{seek_past, Direction, StorageIterator};
+use crate::comparator::{BytewiseComparator, Comparator};
+use crate::key::{self, ValueType};
 
-/// Merges two iterators of different types into one. If the two iterators have the same key, only
-/// produce the key once and prefer the entry from A.
+/// Merges two iterators of different types into one. If the two iterators have the same version
+/// of a key, only produce it once and prefer the entry from A, in both directions.
 pub struct TwoMergeIterator<A: StorageIterator, B: StorageIterator> {
     a: A,
     b: B,
     choose_a: bool,
+    direction: Direction,
+    comparator: Arc<dyn Comparator>,
 }
 
 impl<A: StorageIterator, B: StorageIterator> TwoMergeIterator<A, B> {
+    /// Merge two iterators whose keys are ordered bytewise.
     pub fn create(a: A, b: B) -> Result<Self> {
+        Self::create_with_comparator(a, b, Arc::new(BytewiseComparator))
+    }
+
+    /// Merge two iterators whose keys are ordered by `comparator`.
+    pub fn create_with_comparator(a: A, b: B, comparator: Arc<dyn Comparator>) -> Result<Self> {
         let mut iter = Self {
             a,
             b,
             choose_a: false,
+            direction: Direction::Forward,
+            comparator,
         };
         iter.skip_b()?;
-        iter.choose_a = Self::choose_a(&iter.a, &iter.b);
+        iter.choose_a = Self::choose_a(&*iter.comparator, &iter.a, &iter.b, iter.direction);
         Ok(iter)
     }
 
-    fn choose_a(a: &A, b: &B) -> bool {
+    fn choose_a(comparator: &dyn Comparator, a: &A, b: &B, direction: Direction) -> bool {
         if !a.is_valid() {
             return false;
         }
@@ -30,18 +44,57 @@ impl<A: StorageIterator, B: StorageIterator> TwoMergeIterator<A, B> {
             return true;
         }
         // because skip_b called, not need <= here
-        a.key() < b.key()
+        direction
+            .orient(key::compare_versions(
+                comparator,
+                a.key(),
+                a.seq(),
+                b.key(),
+                b.seq(),
+            ))
+            .is_lt()
     }
 
     fn skip_b(&mut self) -> Result<()> {
         if self.a.is_valid() {
-            while self.b.is_valid() && self.b.key() == self.a.key() {
-                self.b.next()?;
+            while self.b.is_valid() && self.b.key() == self.a.key() && self.b.seq() == self.a.seq()
+            {
+                match self.direction {
+                    Direction::Forward => self.b.next()?,
+                    Direction::Backward => self.b.prev()?,
+                }
             }
         }
         Ok(())
     }
 
+    /// Reverse the direction at the current version, the iterator not chosen is after the
+    /// current version in the old direction, it is moved to the versions after it in `direction`.
+    fn switch_direction(&mut self, direction: Direction) -> Result<()> {
+        let (key, seq) = (self.key().to_vec(), self.seq());
+        if self.choose_a {
+            seek_past(&mut self.b, &*self.comparator, &key, seq, direction)?;
+        } else {
+            seek_past(&mut self.a, &*self.comparator, &key, seq, direction)?;
+        }
+        self.direction = direction;
+        Ok(())
+    }
+
+    /// Reposition both iterators with `seek_a` and `seek_b` and merge them in `direction`.
+    fn reposition(
+        &mut self,
+        direction: Direction,
+        seek_a: impl FnOnce(&mut A) -> Result<()>,
+        seek_b: impl FnOnce(&mut B) -> Result<()>,
+    ) -> Result<()> {
+        self.direction = direction;
+        seek_a(&mut self.a)?;
+        seek_b(&mut self.b)?;
+        self.skip_b()?;
+        self.choose_a = Self::choose_a(&*self.comparator, &self.a, &self.b, self.direction);
+        Ok(())
+    }
 }
 
 impl<A: StorageIterator, B: StorageIterator> StorageIterator for TwoMergeIterator<A, B> {
@@ -61,6 +114,22 @@ impl<A: StorageIterator, B: StorageIterator> StorageIterator for TwoMergeIterato
         }
     }
 
+    fn seq(&self) -> u64 {
+        if self.choose_a {
+            self.a.seq()
+        } else {
+            self.b.seq()
+        }
+    }
+
+    fn value_type(&self) -> ValueType {
+        if self.choose_a {
+            self.a.value_type()
+        } else {
+            self.b.value_type()
+        }
+    }
+
     fn is_valid(&self) -> bool {
         if self.choose_a {
             self.a.is_valid()
@@ -70,13 +139,58 @@ impl<A: StorageIterator, B: StorageIterator> StorageIterator for TwoMergeIterato
     }
 
     fn next(&mut self) -> Result<()> {
+        if self.direction == Direction::Backward {
+            self.switch_direction(Direction::Forward)?;
+        }
         if self.choose_a {
             self.a.next()?;
         } else {
             self.b.next()?;
         }
         self.skip_b()?;
-        self.choose_a = Self::choose_a(&self.a, &self.b);
+        self.choose_a = Self::choose_a(&*self.comparator, &self.a, &self.b, self.direction);
         Ok(())
     }
+
+    fn prev(&mut self) -> Result<()> {
+        if self.direction == Direction::Forward {
+            self.switch_direction(Direction::Backward)?;
+        }
+        if self.choose_a {
+            self.a.prev()?;
+        } else {
+            self.b.prev()?;
+        }
+        self.skip_b()?;
+        self.choose_a = Self::choose_a(&*self.comparator, &self.a, &self.b, self.direction);
+        Ok(())
+    }
+
+    fn seek_to_first(&mut self) -> Result<()> {
+        self.reposition(
+            Direction::Forward,
+            |a| a.seek_to_first(),
+            |b| b.seek_to_first(),
+        )
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        self.reposition(
+            Direction::Backward,
+            |a| a.seek_to_last(),
+            |b| b.seek_to_last(),
+        )
+    }
+
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        self.reposition(Direction::Forward, |a| a.seek(key), |b| b.seek(key))
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()> {
+        self.reposition(
+            Direction::Backward,
+            |a| a.seek_for_prev(key),
+            |b| b.seek_for_prev(key),
+        )
+    }
 }
\ No newline at end of file
@@ -0,0 +1,214 @@
+use std::cmp::Ordering;
+
+use anyhow::{bail, Result};
+use bytes::{BufMut, Bytes};
+
+use crate::comparator::Comparator;
+
+use crate::utils::SIZEOF_U64;
+
+/// The largest sequence number, the lower 8 bits of the trailer hold the value type.
+pub const MAX_SEQ: u64 = (1 << 56) - 1;
+
+/// What a version of a key does.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueType {
+    /// The key is deleted, the value is empty.
+    Delete = 0,
+    Put = 1,
+    /// The keys from the user key to the value, exclusive, are deleted. Only used in the
+    /// memtable and the WAL, SSTs keep range tombstones in their own block.
+    RangeDelete = 2,
+    /// A merge operand, applied to the older versions of the key by the merge operator.
+    Merge = 3,
+    /// A value that expires, the value starts with the 8-byte expiry time in milliseconds since
+    /// the UNIX epoch. Once expired, the key is deleted.
+    PutWithTtl = 4,
+}
+
+impl TryFrom<u8> for ValueType {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        Ok(match value {
+            0 => ValueType::Delete,
+            1 => ValueType::Put,
+            2 => ValueType::RangeDelete,
+            3 => ValueType::Merge,
+            4 => ValueType::PutWithTtl,
+            _ => bail!("unknown value type {}", value),
+        })
+    }
+}
+
+impl ValueType {
+    /// Whether the version holds a value, which may expire.
+    pub fn is_put(self) -> bool {
+        matches!(self, ValueType::Put | ValueType::PutWithTtl)
+    }
+}
+
+/// Encode the value of a `PutWithTtl` version.
+pub fn encode_value_with_expiry(value: &[u8], expire_at: u64) -> Bytes {
+    let mut buf = Vec::with_capacity(SIZEOF_U64 + value.len());
+    buf.put_u64(expire_at);
+    buf.put_slice(value);
+    buf.into()
+}
+
+/// The expiry time and the value of a `PutWithTtl` version, the values read from disk are checked
+/// by [`check_entry`] to have the expiry time.
+pub fn decode_value_with_expiry(value: &[u8]) -> (u64, &[u8]) {
+    let mut expire_at = [0; SIZEOF_U64];
+    expire_at.copy_from_slice(&value[..SIZEOF_U64]);
+    (u64::from_be_bytes(expire_at), &value[SIZEOF_U64..])
+}
+
+/// The value of a version seen by readers at `now`, in milliseconds since the UNIX epoch. `None`
+/// if the version holds no value or the value has expired.
+pub fn live_value(value_type: ValueType, value: &[u8], now: u64) -> Option<&[u8]> {
+    match value_type {
+        ValueType::Put => Some(value),
+        ValueType::PutWithTtl => match decode_value_with_expiry(value) {
+            (expire_at, value) if now < expire_at => Some(value),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// A version of a user key: the user key followed by the 8-byte trailer `seq << 8 | value_type`
+/// in big endian. Internal keys are ordered by user key, then by sequence number descending, so
+/// the latest version of a key comes first. The `Ord` of the type orders user keys bytewise,
+/// [`compare`] orders them with a comparator.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct InternalKey(Bytes);
+
+impl InternalKey {
+    pub fn new(user_key: &[u8], seq: u64, value_type: ValueType) -> Self {
+        Self::with_trailer(user_key, seq << 8 | value_type as u64)
+    }
+
+    /// The key placed before every version of `user_key` visible at `seq`, seeking to it finds
+    /// the latest version whose sequence number is not larger than `seq`.
+    pub fn seek(user_key: &[u8], seq: u64) -> Self {
+        Self::with_trailer(user_key, seq << 8 | 0xff)
+    }
+
+    /// The key placed after every version of `user_key`.
+    pub fn after_all_versions(user_key: &[u8]) -> Self {
+        Self::with_trailer(user_key, 0)
+    }
+
+    fn with_trailer(user_key: &[u8], trailer: u64) -> Self {
+        let mut buf = Vec::with_capacity(user_key.len() + SIZEOF_U64);
+        buf.put_slice(user_key);
+        buf.put_u64(trailer);
+        Self(buf.into())
+    }
+
+    /// Wrap an encoded internal key.
+    pub fn from_bytes(bytes: Bytes) -> Self {
+        assert!(bytes.len() >= SIZEOF_U64, "internal key is too short");
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn user_key(&self) -> &[u8] {
+        user_key(&self.0)
+    }
+
+    pub fn seq(&self) -> u64 {
+        seq(&self.0)
+    }
+
+    pub fn value_type(&self) -> ValueType {
+        value_type(&self.0)
+    }
+}
+
+impl Ord for InternalKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        user_key(&self.0)
+            .cmp(user_key(&other.0))
+            .then_with(|| trailer(&other.0).cmp(&trailer(&self.0)))
+    }
+}
+
+impl PartialOrd for InternalKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+fn trailer(internal_key: &[u8]) -> u64 {
+    let mut trailer = [0; SIZEOF_U64];
+    trailer.copy_from_slice(&internal_key[internal_key.len() - SIZEOF_U64..]);
+    u64::from_be_bytes(trailer)
+}
+
+/// The user key of an encoded internal key.
+pub fn user_key(internal_key: &[u8]) -> &[u8] {
+    &internal_key[..internal_key.len() - SIZEOF_U64]
+}
+
+/// The sequence number of an encoded internal key.
+pub fn seq(internal_key: &[u8]) -> u64 {
+    trailer(internal_key) >> 8
+}
+
+/// The value type of an encoded internal key. Keys read from disk are checked by
+/// [`check_internal_key`] when they are decoded.
+pub fn value_type(internal_key: &[u8]) -> ValueType {
+    ValueType::try_from(trailer(internal_key) as u8)
+        .expect("value types are checked when keys are decoded")
+}
+
+/// Check an encoded internal key read from disk, fails if it is too short to have a trailer or
+/// its value type is unknown.
+pub fn check_internal_key(internal_key: &[u8]) -> Result<()> {
+    if internal_key.len() < SIZEOF_U64 {
+        bail!("internal key of {} bytes is too short", internal_key.len());
+    }
+    ValueType::try_from(trailer(internal_key) as u8)?;
+    Ok(())
+}
+
+/// Check an encoded internal key and its value read from disk, fails if the key is invalid or the
+/// value of a `PutWithTtl` version is too short to have the expiry time.
+pub fn check_entry(internal_key: &[u8], value: &[u8]) -> Result<()> {
+    check_internal_key(internal_key)?;
+    if value_type(internal_key) == ValueType::PutWithTtl && value.len() < SIZEOF_U64 {
+        bail!(
+            "value of {} bytes is too short to have an expiry time",
+            value.len()
+        );
+    }
+    Ok(())
+}
+
+/// Compare two encoded internal keys, by user key ascending in the order of `comparator`, then
+/// by trailer descending.
+pub fn compare(comparator: &dyn Comparator, a: &[u8], b: &[u8]) -> Ordering {
+    comparator
+        .compare(user_key(a), user_key(b))
+        .then_with(|| trailer(b).cmp(&trailer(a)))
+}
+
+/// Compare two versions of keys, by user key ascending in the order of `comparator`, then by
+/// sequence number descending.
+pub fn compare_versions(
+    comparator: &dyn Comparator,
+    a_key: &[u8],
+    a_seq: u64,
+    b_key: &[u8],
+    b_seq: u64,
+) -> Ordering {
+    comparator
+        .compare(a_key, b_key)
+        .then_with(|| b_seq.cmp(&a_seq))
+}
@@ -1,10 +1,22 @@
 extern crate core;
 
 pub mod block;
+pub mod clock;
+pub mod compact;
+pub mod comparator;
 pub mod table;
 pub mod lsm_storage;
 pub mod lsm_iterator;
 pub mod iterators;
+pub mod key;
+pub mod range_tombstone;
 pub mod mem_table;
+pub mod merge_operator;
+pub mod prefix_extractor;
+pub mod wal;
+pub mod manifest;
+pub mod write_batch;
+pub mod write_stall;
+pub mod mvcc;
 
 pub mod utils;
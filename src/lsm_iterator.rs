@@ -1,54 +1,271 @@
 use std::ops::Bound;
+use std::sync::Arc;
 use anyhow::Result;
 use bytes::Bytes;
+use crate::comparator::Comparator;
+use crate::iterators::concat_iterator::SstConcatIterator;
 use crate::iterators::merge_iterator::MergeIterator;
 
-use crate::iterators::StorageIterator;
+use crate::iterators::{Direction, StorageIterator};
 use crate::iterators::two_merge_iterator::TwoMergeIterator;
+use crate::key::{live_value, ValueType};
 use crate::mem_table::MemTableIterator;
+use crate::merge_operator::{full_merge, MergeOperator};
+use crate::range_tombstone::FragmentedRangeTombstones;
 use crate::table::SsTableIterator;
 
-type LsmIteratorInner = TwoMergeIterator<MergeIterator<MemTableIterator>, MergeIterator<SsTableIterator>>;
+type LsmIteratorInner = TwoMergeIterator<
+    TwoMergeIterator<MergeIterator<MemTableIterator>, MergeIterator<SsTableIterator>>,
+    MergeIterator<SstConcatIterator>,
+>;
 
+/// Iterates over the latest version of each key visible at `read_seq`, deleted keys are skipped,
+/// including the ones deleted by range tombstones and the expired ones. Merge operands are resolved
+/// with the versions below them. It moves in both directions within its bounds, and within the
+/// keys with `prefix` if it is set.
 pub struct LsmIterator {
     iter: LsmIteratorInner,
+    lower_bound: Bound<Bytes>,
     end_bound: Bound<Bytes>,
+    /// The keys with the prefix are contiguous and not before the prefix, so the iterator stops
+    /// at the first key after them.
+    prefix: Option<Bytes>,
+    read_seq: u64,
+    range_tombstones: FragmentedRangeTombstones,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    /// Orders the keys, for checking the bounds.
+    comparator: Arc<dyn Comparator>,
+    /// Values expiring at or before this time, in milliseconds since the UNIX epoch, are deleted.
+    now: u64,
+    direction: Direction,
+    /// The current version when moving backward, the inner iterator has moved past every version
+    /// of its key to find the latest visible one. Also used when moving forward to a merge
+    /// operand, its value is resolved with the versions below it.
+    saved_key: Vec<u8>,
+    saved_value: Vec<u8>,
+    saved_seq: u64,
+    /// Moving forward, the current version is saved.
+    merged: bool,
     is_valid: bool,
 }
 
 impl LsmIterator {
-    pub fn new(iter: LsmIteratorInner, end_bound: Bound<Bytes>) -> Result<Self> {
-        let mut iter = Self {
-            is_valid: iter.is_valid(),
+    /// Create an iterator over the keys in `(lower_bound, end_bound)`, it is invalid until
+    /// positioned.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        iter: LsmIteratorInner,
+        lower_bound: Bound<Bytes>,
+        end_bound: Bound<Bytes>,
+        prefix: Option<Bytes>,
+        read_seq: u64,
+        range_tombstones: FragmentedRangeTombstones,
+        merge_operator: Option<Arc<dyn MergeOperator>>,
+        comparator: Arc<dyn Comparator>,
+        now: u64,
+    ) -> Self {
+        Self {
             iter,
+            lower_bound,
             end_bound,
-        };
-        iter.move_to_non_delete()?;
-        Ok(iter)
+            prefix,
+            read_seq,
+            range_tombstones,
+            merge_operator,
+            comparator,
+            now,
+            direction: Direction::Forward,
+            saved_key: Vec::new(),
+            saved_value: Vec::new(),
+            saved_seq: 0,
+            merged: false,
+            is_valid: false,
+        }
+    }
+
+    /// Whether the current version is saved instead of read from the inner iterator.
+    fn is_saved(&self) -> bool {
+        self.direction == Direction::Backward || self.merged
     }
 
     fn next_inner(&mut self) -> Result<()> {
         self.iter.next()?;
+        self.check_valid();
+        Ok(())
+    }
+
+    fn check_valid(&mut self) {
         if !self.iter.is_valid() {
             self.is_valid = false;
-            return Ok(());
+            return;
         }
 
         match &self.end_bound {
-            Bound::Included(key) => self.is_valid = self.key() <= key,
-            Bound::Excluded(key) => self.is_valid = self.key() < key,
-            Bound::Unbounded => (),
+            Bound::Included(key) => {
+                self.is_valid = self.comparator.compare(self.iter.key(), key).is_le()
+            }
+            Bound::Excluded(key) => {
+                self.is_valid = self.comparator.compare(self.iter.key(), key).is_lt()
+            }
+            Bound::Unbounded => self.is_valid = true,
         };
+        if let Some(prefix) = &self.prefix {
+            self.is_valid = self.is_valid && self.iter.key().starts_with(prefix);
+        }
+    }
 
+    /// Skip the remaining (older) versions of the current key.
+    fn skip_versions(&mut self) -> Result<()> {
+        let key = self.key().to_vec();
+        while self.iter.is_valid() && self.iter.key() == key {
+            self.iter.next()?;
+        }
+        self.check_valid();
         Ok(())
     }
 
-    fn move_to_non_delete(&mut self) -> Result<()> {
-        while self.is_valid && self.value().is_empty() {
-            self.next_inner()?;
+    /// Move to the latest visible version of a key that is not deleted, versions newer than
+    /// `read_seq` are skipped.
+    fn move_to_visible(&mut self) -> Result<()> {
+        self.merged = false;
+        self.check_valid();
+        loop {
+            while self.is_valid && self.iter.seq() > self.read_seq {
+                self.next_inner()?;
+            }
+            if !self.is_valid {
+                return Ok(());
+            }
+            let deleted_before = self
+                .range_tombstones
+                .max_covering_seq(self.iter.key(), self.read_seq);
+            let deleted = self.iter.seq() < deleted_before
+                || match self.iter.value_type() {
+                    ValueType::Merge => false,
+                    value_type => live_value(value_type, self.iter.value(), self.now).is_none(),
+                };
+            if !deleted {
+                if self.iter.value_type() == ValueType::Merge {
+                    self.resolve_merge(deleted_before)?;
+                }
+                return Ok(());
+            }
+            self.skip_versions()?;
+        }
+    }
+
+    /// Resolve the merge operand the inner iterator is at with the versions below it, and save
+    /// the result. The inner iterator moves past the versions it reads, versions older than
+    /// `deleted_before` are deleted by a range tombstone.
+    fn resolve_merge(&mut self, deleted_before: u64) -> Result<()> {
+        self.saved_key.clear();
+        self.saved_key.extend_from_slice(self.iter.key());
+        self.saved_seq = self.iter.seq();
+        let mut operands = vec![Bytes::copy_from_slice(self.iter.value())];
+        let mut existing_value = None;
+        self.iter.next()?;
+        while self.iter.is_valid()
+            && self.iter.key() == &self.saved_key[..]
+            && self.iter.seq() >= deleted_before
+        {
+            match self.iter.value_type() {
+                ValueType::Merge => operands.push(Bytes::copy_from_slice(self.iter.value())),
+                value_type => {
+                    existing_value = live_value(value_type, self.iter.value(), self.now)
+                        .map(Bytes::copy_from_slice);
+                    break;
+                }
+            }
+            self.iter.next()?;
         }
+        operands.reverse();
+        let value = full_merge(
+            self.merge_operator.as_deref(),
+            &self.saved_key,
+            existing_value.as_deref(),
+            &operands,
+        )?;
+        self.saved_value.clear();
+        self.saved_value.extend_from_slice(&value);
+        self.merged = true;
         Ok(())
     }
+
+    /// Move backward to the latest visible version of the first key before the inner iterator
+    /// that is not deleted. Versions are visited from the oldest one, so every version of a key
+    /// is passed before knowing the latest visible one, which is saved.
+    fn move_to_visible_backward(&mut self) -> Result<()> {
+        self.merged = false;
+        loop {
+            let in_bounds = self.iter.is_valid()
+                && match &self.lower_bound {
+                    Bound::Included(key) => self.comparator.compare(self.iter.key(), key).is_ge(),
+                    Bound::Excluded(key) => self.comparator.compare(self.iter.key(), key).is_gt(),
+                    Bound::Unbounded => true,
+                }
+                && self
+                    .prefix
+                    .as_ref()
+                    .is_none_or(|prefix| self.iter.key().starts_with(prefix));
+            if !in_bounds {
+                self.is_valid = false;
+                return Ok(());
+            }
+            self.saved_key.clear();
+            self.saved_key.extend_from_slice(self.iter.key());
+            let deleted_before = self
+                .range_tombstones
+                .max_covering_seq(&self.saved_key, self.read_seq);
+            // the state after each visible version: whether the key is deleted, whether it has a
+            // value in `saved_value`, and the merge operands applied to it since
+            let mut deleted = true;
+            let mut has_value = false;
+            let mut operands = Vec::new();
+            while self.iter.is_valid() && self.iter.key() == &self.saved_key[..] {
+                let seq = self.iter.seq();
+                if seq <= self.read_seq {
+                    self.saved_seq = seq;
+                    match self.iter.value_type() {
+                        _ if seq < deleted_before => deleted = true,
+                        ValueType::Merge => {
+                            deleted = false;
+                            operands.push(Bytes::copy_from_slice(self.iter.value()));
+                        }
+                        value_type => match live_value(value_type, self.iter.value(), self.now) {
+                            Some(value) => {
+                                deleted = false;
+                                has_value = true;
+                                operands.clear();
+                                self.saved_value.clear();
+                                self.saved_value.extend_from_slice(value);
+                            }
+                            None => deleted = true,
+                        },
+                    }
+                    if deleted {
+                        has_value = false;
+                        operands.clear();
+                    }
+                }
+                self.iter.prev()?;
+            }
+            if !deleted {
+                if !operands.is_empty() {
+                    let existing_value = has_value.then_some(&self.saved_value[..]);
+                    let value = full_merge(
+                        self.merge_operator.as_deref(),
+                        &self.saved_key,
+                        existing_value,
+                        &operands,
+                    )?;
+                    self.saved_value.clear();
+                    self.saved_value.extend_from_slice(&value);
+                }
+                self.is_valid = true;
+                return Ok(());
+            }
+        }
+    }
 }
 
 impl StorageIterator for LsmIterator {
@@ -57,22 +274,142 @@ impl StorageIterator for LsmIterator {
     }
 
     fn key(&self) -> &[u8] {
-        self.iter.key()
+        if self.is_saved() {
+            &self.saved_key
+        } else {
+            self.iter.key()
+        }
     }
 
     fn value(&self) -> &[u8] {
-        self.iter.value()
+        if self.is_saved() {
+            &self.saved_value
+        } else {
+            // the expiry time is not part of the value
+            live_value(self.iter.value_type(), self.iter.value(), self.now).unwrap_or_default()
+        }
+    }
+
+    fn seq(&self) -> u64 {
+        if self.is_saved() {
+            self.saved_seq
+        } else {
+            self.iter.seq()
+        }
     }
 
     fn next(&mut self) -> Result<()> {
-        self.next_inner()?;
-        self.move_to_non_delete()?;
+        if self.direction == Direction::Backward {
+            // the inner iterator is before the current key, bring it back to the key
+            self.direction = Direction::Forward;
+            self.iter.seek(&self.saved_key)?;
+            self.check_valid();
+        }
+        self.skip_versions()?;
+        self.move_to_visible()?;
         Ok(())
     }
+
+    fn prev(&mut self) -> Result<()> {
+        if self.direction == Direction::Forward {
+            // skip the current key and its newer versions
+            let key = self.key().to_vec();
+            if self.merged {
+                // the inner iterator has moved past some versions of the key
+                self.merged = false;
+                self.iter.seek(&key)?;
+            }
+            self.direction = Direction::Backward;
+            while self.iter.is_valid() && self.iter.key() == key {
+                self.iter.prev()?;
+            }
+        }
+        self.move_to_visible_backward()
+    }
+
+    /// Seek to the first key in the bounds.
+    fn seek_to_first(&mut self) -> Result<()> {
+        self.direction = Direction::Forward;
+        match &self.lower_bound {
+            Bound::Included(key) => self.iter.seek(key)?,
+            Bound::Excluded(key) => {
+                self.iter.seek(key)?;
+                // skip every version of the key
+                while self.iter.is_valid() && self.iter.key() == &key[..] {
+                    self.iter.next()?;
+                }
+            }
+            Bound::Unbounded => self.iter.seek_to_first()?,
+        }
+        self.move_to_visible()
+    }
+
+    /// Seek to the last key in the bounds.
+    fn seek_to_last(&mut self) -> Result<()> {
+        self.direction = Direction::Backward;
+        match &self.end_bound {
+            Bound::Included(key) => self.iter.seek_for_prev(key)?,
+            Bound::Excluded(key) => {
+                self.iter.seek_for_prev(key)?;
+                // skip every version of the key
+                while self.iter.is_valid() && self.iter.key() == &key[..] {
+                    self.iter.prev()?;
+                }
+            }
+            // from the first version after the keys with the prefix
+            Bound::Unbounded if self.prefix.is_some() => {
+                let prefix = self.prefix.clone().unwrap();
+                self.iter.seek(&prefix)?;
+                while self.iter.is_valid() && self.iter.key().starts_with(&prefix) {
+                    self.iter.next()?;
+                }
+                if self.iter.is_valid() {
+                    self.iter.prev()?;
+                } else {
+                    self.iter.seek_to_last()?;
+                }
+            }
+            Bound::Unbounded => self.iter.seek_to_last()?,
+        }
+        self.move_to_visible_backward()
+    }
+
+    /// Seek to the first key which >= `key` in the bounds, the iterator keeps reading at the
+    /// same sequence number.
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        let before_lower_bound = match &self.lower_bound {
+            Bound::Included(lower) => self.comparator.compare(key, lower).is_lt(),
+            Bound::Excluded(lower) => self.comparator.compare(key, lower).is_le(),
+            Bound::Unbounded => false,
+        };
+        if before_lower_bound {
+            return self.seek_to_first();
+        }
+        self.direction = Direction::Forward;
+        self.iter.seek(key)?;
+        self.move_to_visible()
+    }
+
+    /// Seek to the last key which <= `key` in the bounds.
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()> {
+        let beyond_end_bound = match &self.end_bound {
+            Bound::Included(end) => self.comparator.compare(end, key).is_lt(),
+            Bound::Excluded(end) => self.comparator.compare(end, key).is_le(),
+            Bound::Unbounded => false,
+        } || self.prefix.as_ref().is_some_and(|prefix| {
+            !key.starts_with(prefix) && self.comparator.compare(prefix, key).is_lt()
+        });
+        if beyond_end_bound {
+            return self.seek_to_last();
+        }
+        self.direction = Direction::Backward;
+        self.iter.seek_for_prev(key)?;
+        self.move_to_visible_backward()
+    }
 }
 
 /// A wrapper around existing iterator, will prevent users from calling `next` when the iterator is
-/// invalid.
+/// invalid. A seek makes it valid again if it finds a key.
 pub struct FusedIterator<I: StorageIterator> {
     iter: I,
 }
@@ -96,10 +433,37 @@ impl<I: StorageIterator> StorageIterator for FusedIterator<I> {
         self.iter.value()
     }
 
+    fn seq(&self) -> u64 {
+        self.iter.seq()
+    }
+
     fn next(&mut self) -> Result<()> {
         if self.is_valid() {
             self.iter.next()?;
         }
         Ok(())
     }
+
+    fn prev(&mut self) -> Result<()> {
+        if self.is_valid() {
+            self.iter.prev()?;
+        }
+        Ok(())
+    }
+
+    fn seek_to_first(&mut self) -> Result<()> {
+        self.iter.seek_to_first()
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        self.iter.seek_to_last()
+    }
+
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        self.iter.seek(key)
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()> {
+        self.iter.seek_for_prev(key)
+    }
 }
@@ -1,231 +1,1719 @@
-use std::collections::hash_map::Keys;
+use std::collections::{BTreeMap, HashMap};
 use std::ops::Bound;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use bytes::Bytes;
 use parking_lot::{Mutex, RwLock};
 
 use crate::block::Block;
+use crate::clock::{Clock, SystemClock};
+use crate::compact::{
+    CompactionController, CompactionFilter, CompactionOptions, LeveledCompactionOptions,
+};
+use crate::comparator::{BytewiseComparator, Comparator};
+use crate::iterators::concat_iterator::SstConcatIterator;
 use crate::iterators::merge_iterator::MergeIterator;
-use crate::iterators::StorageIterator;
+use crate::iterators::{Direction, StorageIterator};
 use crate::iterators::two_merge_iterator::TwoMergeIterator;
 use crate::lsm_iterator::{FusedIterator, LsmIterator};
+use crate::key::{encode_value_with_expiry, live_value, InternalKey, ValueType};
+use crate::manifest::{ColumnFamilyLayout, Manifest, ManifestRecord, ManifestSnapshot};
 use crate::mem_table::{map_bound, MemTable};
-use crate::table::{SsTable, SsTableBuilder, SsTableIterator};
+use crate::merge_operator::{full_merge, MergeOperator};
+use crate::mvcc::{LockManager, Mvcc, ReadSet, Snapshot, Transaction, TransactionError};
+use crate::prefix_extractor::{prefix_of, PrefixExtractor};
+use crate::range_tombstone::{FragmentedRangeTombstones, RangeTombstone};
+use crate::table::{
+    FileObject, SsTable, SsTableBuilder, SsTableIterator, DEFAULT_BLOOM_BITS_PER_KEY,
+};
+use crate::wal::{Wal, WalSyncPolicy};
+use crate::write_batch::{WriteBatch, WriteBatchEntry};
+use crate::write_stall::{BackgroundErrors, BackgroundJob, WriteController, WriteStallStatus};
 
 pub type BlockCache = moka::sync::Cache<(usize, usize), Arc<Block>>;
 
+/// An entry to write, the user key, the type of the version and the value.
+type WriteEntry<'a> = (&'a [u8], ValueType, &'a [u8]);
+
 #[derive(Clone)]
 pub struct LsmStorageInner {
     /// The current memtable.
-    memtable: Arc<MemTable>,
+    pub(crate) memtable: Arc<MemTable>,
     /// Immutable memTables, from earliest to latest.
-    imm_memtables: Vec<Arc<MemTable>>,
+    pub(crate) imm_memtables: Vec<Arc<MemTable>>,
     /// L0 SsTables, from earliest to latest.
-    l0_sstables: Vec<Arc<SsTable>>,
-    /// L1 - L6 SsTables, sorted by key range.
-    levels: Vec<Vec<Arc<SsTable>>>,
-    /// The next SSTable ID.
-    next_sst_id: usize,
+    pub(crate) l0_sstables: Vec<Arc<SsTable>>,
+    /// L1 - L6 SsTables, sorted by key range. With tiered compaction, each level is a sorted
+    /// run, from latest to earliest. The SSTs with only range tombstones have no key range, they
+    /// are kept before the sorted run of a level.
+    pub(crate) levels: Vec<Vec<Arc<SsTable>>>,
 }
 
 impl LsmStorageInner {
-    fn create() -> Self {
+    /// Every SST in the tree, L0 first.
+    pub(crate) fn sstables(&self) -> impl Iterator<Item = &Arc<SsTable>> {
+        self.l0_sstables.iter().chain(self.levels.iter().flatten())
+    }
+
+    /// Every range tombstone in the tree, fragmented. Only the tombstones of the memtables are
+    /// fragmented here, the SSTs are fragmented when they are opened.
+    pub(crate) fn range_tombstones(
+        &self,
+        comparator: &Arc<dyn Comparator>,
+    ) -> FragmentedRangeTombstones {
+        let memtable_tombstones: Vec<RangeTombstone> = std::iter::once(&self.memtable)
+            .chain(self.imm_memtables.iter())
+            .flat_map(|memtable| memtable.range_tombstones())
+            .collect();
+        let memtable_fragments =
+            FragmentedRangeTombstones::new(comparator.clone(), &memtable_tombstones);
+        let sst_fragments = self.sstables().map(|sst| sst.range_tombstone_fragments());
+        FragmentedRangeTombstones::merge(
+            comparator.clone(),
+            std::iter::once(&memtable_fragments).chain(sst_fragments),
+        )
+    }
+
+    /// The largest sequence number of the range tombstones covering `key` that is not larger
+    /// than `read_seq`, 0 if there is none. Range tombstones may be in any memtable or SST, not
+    /// only in the ones holding the versions of the key.
+    pub(crate) fn max_covering_seq(&self, key: &[u8], read_seq: u64) -> u64 {
+        std::iter::once(&self.memtable)
+            .chain(self.imm_memtables.iter())
+            .map(|memtable| memtable.max_covering_seq(key, read_seq))
+            .chain(
+                self.sstables()
+                    .map(|sst| sst.max_covering_seq(key, read_seq)),
+            )
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Rebuild L0 and the levels with the SST IDs in `layout`. `new_sstables` are the SSTs that
+    /// are not in the tree yet.
+    pub(crate) fn apply_layout(
+        &mut self,
+        layout: &ColumnFamilyLayout,
+        new_sstables: Vec<Arc<SsTable>>,
+    ) {
+        let mut sstables: HashMap<usize, Arc<SsTable>> = self
+            .l0_sstables
+            .iter()
+            .chain(self.levels.iter().flatten())
+            .chain(new_sstables.iter())
+            .map(|sst| (sst.sst_id(), sst.clone()))
+            .collect();
+        self.l0_sstables = layout
+            .l0_sstables
+            .iter()
+            .map(|id| sstables.remove(id).unwrap())
+            .collect();
+        self.levels = layout
+            .levels
+            .iter()
+            .map(|level| {
+                let mut level: Vec<_> = level
+                    .iter()
+                    .map(|id| sstables.remove(id).unwrap())
+                    .collect();
+                level.sort_by(
+                    |a, b| match (a.num_of_blocks() > 0, b.num_of_blocks() > 0) {
+                        (true, true) => a.comparator().compare(a.first_key(), b.first_key()),
+                        (a, b) => a.cmp(&b),
+                    },
+                );
+                level
+            })
+            .collect();
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LsmStorageOptions {
+    /// The memtable is frozen once it grows larger than this size in bytes, and flushed to L0
+    /// by the flush thread.
+    pub write_buffer_size: usize,
+    /// Writes are stopped while there are this many immutable memtables waiting to be flushed.
+    pub max_imm_memtables: usize,
+    /// Writes are delayed once L0 has this many SSTs.
+    pub level0_slowdown_writes_trigger: usize,
+    /// Writes are stopped once L0 has this many SSTs.
+    pub level0_stop_writes_trigger: usize,
+    /// Writes are delayed once the compaction falls behind by this many bytes.
+    pub soft_pending_compaction_bytes_limit: u64,
+    /// Writes are stopped once the compaction falls behind by this many bytes.
+    pub hard_pending_compaction_bytes_limit: u64,
+    /// Target size of the data blocks in an SST.
+    pub block_size: usize,
+    /// Compaction splits its output into SSTs of about this size in bytes.
+    pub target_sst_size: usize,
+    /// Bits per key of the bloom filter in each SST, 0 disables the bloom filter.
+    pub bloom_bits_per_key: usize,
+    /// Write every put / delete to a WAL before applying it to the memtable.
+    pub enable_wal: bool,
+    /// When the WAL is `fsync`ed.
+    pub wal_sync_policy: WalSyncPolicy,
+    /// The manifest is rewritten with only the current structure of the tree once it grows
+    /// larger than this size in bytes.
+    pub max_manifest_size: u64,
+    /// How long a pessimistic transaction waits for the lock of a key.
+    pub lock_timeout: Duration,
+    pub compaction_options: CompactionOptions,
+    /// Resolves the operands written by `merge`, which fails without one.
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+    /// Drops or rewrites values during compaction.
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    /// Expires the values written by `put_with_ttl`.
+    pub clock: Arc<dyn Clock>,
+    /// Orders the keys. It can't be changed once the column family is created.
+    pub comparator: Arc<dyn Comparator>,
+    /// Extracts the prefixes of keys into the bloom filters of the SSTs and the memtables, so
+    /// `scan_prefix` skips the ones without the prefix.
+    pub prefix_extractor: Option<Arc<dyn PrefixExtractor>>,
+}
+
+impl LsmStorageOptions {
+    /// Fails if the prefix extractor can't be used with the comparator.
+    fn validate(&self) -> Result<()> {
+        match &self.prefix_extractor {
+            Some(extractor) if !self.comparator.supports_prefix_scan() => bail!(
+                "prefix extractor {:?} can't be used with comparator {:?}, \
+                 which doesn't support prefix scans",
+                extractor.name(),
+                self.comparator.name()
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Default for LsmStorageOptions {
+    fn default() -> Self {
         Self {
-            memtable: Arc::new(MemTable::create()),
-            imm_memtables: vec![],
-            l0_sstables: vec![],
-            levels: vec![],
-            next_sst_id: 1,
+            write_buffer_size: 4 << 20,
+            max_imm_memtables: 4,
+            level0_slowdown_writes_trigger: 20,
+            level0_stop_writes_trigger: 36,
+            soft_pending_compaction_bytes_limit: 64 << 30,
+            hard_pending_compaction_bytes_limit: 256 << 30,
+            block_size: 4096,
+            target_sst_size: 2 << 20,
+            bloom_bits_per_key: DEFAULT_BLOOM_BITS_PER_KEY,
+            enable_wal: true,
+            wal_sync_policy: WalSyncPolicy::Buffered,
+            max_manifest_size: 1 << 20,
+            lock_timeout: Duration::from_secs(1),
+            compaction_options: CompactionOptions::Leveled(LeveledCompactionOptions::default()),
+            merge_operator: None,
+            compaction_filter: None,
+            clock: Arc::new(SystemClock),
+            comparator: Arc::new(BytewiseComparator),
+            prefix_extractor: None,
         }
     }
 }
 
-/// The storage interface of the LSM tree.
-pub struct LsmStorage {
+/// The name of the column family the methods of [`LsmStorage`] work on, it always exists and
+/// can't be dropped.
+pub const DEFAULT_COLUMN_FAMILY: &str = "default";
+
+pub(crate) const DEFAULT_COLUMN_FAMILY_ID: u32 = 0;
+
+/// The state shared by the column families of a storage, and with the background threads.
+pub(crate) struct SharedState {
+    path: PathBuf,
+    pub(crate) block_cache: Arc<BlockCache>,
+    manifest: Manifest,
+    /// Writes are applied to the memtables one at a time, so a batch is never interleaved with
+    /// other writes. Column families are created and dropped with it held.
+    write_lock: Mutex<()>,
+    /// The next SSTable ID, memtables and WALs share the same ID space, a memtable is flushed to
+    /// the SST with its own ID.
+    next_sst_id: AtomicUsize,
+    next_column_family_id: AtomicU32,
+    /// The WAL every write goes to, with its ID. `None` if the WAL is disabled.
+    wal: RwLock<Option<(usize, Wal)>>,
+    /// IDs of the earlier WALs which are not deleted yet, from earliest to latest.
+    old_wals: Mutex<Vec<usize>>,
+    /// The column families by ID, the default one included.
+    column_families: RwLock<BTreeMap<u32, Arc<LsmStorageCore>>>,
+    /// The options the storage is opened with, the ones of the WAL and the manifest apply to
+    /// every column family.
+    options: LsmStorageOptions,
+    pub(crate) mvcc: Mvcc,
+    pub(crate) lock_manager: LockManager,
+    /// The last errors of the flush and the compaction threads.
+    pub(crate) background_errors: BackgroundErrors,
+}
+
+/// A column family, an LSM tree of its own. It shares the WAL, the manifest and the sequence
+/// numbers with the other families of the storage.
+pub(crate) struct LsmStorageCore {
+    pub(crate) id: u32,
+    name: String,
     // use RwLock instead Mutex, because just write operate need mutex
-    inner: Arc<RwLock<Arc<LsmStorageInner>>>,
+    pub(crate) inner: RwLock<Arc<LsmStorageInner>>,
+    /// Only one thread flushes immutable memtables at a time.
     flush_lock: Mutex<()>,
-    path: PathBuf,
-    block_cache: Arc<BlockCache>,
+    /// Only one compaction runs at a time.
+    pub(crate) compaction_lock: Mutex<()>,
+    pub(crate) options: LsmStorageOptions,
+    pub(crate) compaction_controller: CompactionController,
+    pub(crate) write_controller: WriteController,
+    pub(crate) shared: Arc<SharedState>,
+    /// Set once the family is dropped, it is no longer written, flushed or compacted.
+    dropped: AtomicBool,
+}
+
+/// The storage interface of the LSM tree.
+pub struct LsmStorage {
+    shared: Arc<SharedState>,
+    /// The default column family.
+    core: Arc<LsmStorageCore>,
+    /// Notifies the flush thread to stop.
+    flush_notifier: crossbeam_channel::Sender<()>,
+    flush_thread: Mutex<Option<JoinHandle<()>>>,
+    /// Notifies the compaction thread to stop.
+    compaction_notifier: crossbeam_channel::Sender<()>,
+    compaction_thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Drop for LsmStorage {
+    fn drop(&mut self) {
+        self.flush_notifier.send(()).ok();
+        self.compaction_notifier.send(()).ok();
+        if let Some(handle) = self.flush_thread.lock().take() {
+            handle.join().ok();
+        }
+        if let Some(handle) = self.compaction_thread.lock().take() {
+            handle.join().ok();
+        }
+        // the column families hold the shared state, break the cycle
+        self.shared.column_families.write().clear();
+    }
+}
+
+/// A handle of a column family, a named keyspace of the storage with its own memtables, SSTs
+/// and options. The writes of all families share the WAL and the sequence numbers, so a
+/// [`WriteBatch`] spanning families is applied atomically.
+#[derive(Clone)]
+pub struct ColumnFamily {
+    pub(crate) core: Arc<LsmStorageCore>,
+}
+
+impl ColumnFamily {
+    pub fn id(&self) -> u32 {
+        self.core.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.core.name
+    }
+
+    /// Get a key from the column family.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        self.core.get(key)
+    }
+
+    /// Put a key-value pair into the column family.
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.core.put(key, value)
+    }
+
+    /// Put a key-value pair that expires after `ttl`, by the clock in the options of the family.
+    pub fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        self.core.put_with_ttl(key, value, ttl)
+    }
+
+    /// Remove a key from the column family by writing a tombstone.
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        self.core.delete(key)
+    }
+
+    /// Write a merge operand of a key, fails if the family has no merge operator.
+    pub fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> {
+        self.core.merge(key, operand)
+    }
+
+    /// Remove the keys in `[start, end)` from the column family.
+    pub fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+        self.core.delete_range(start, end)
+    }
+
+    /// Create an iterators over a range of keys of the column family.
+    pub fn scan(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        self.core.scan(lower, upper)
+    }
+
+    /// Create an iterators over a range of keys of the column family positioned at the last key.
+    pub fn scan_rev(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        self.core.scan_rev(lower, upper)
+    }
+
+    /// Create an iterator over the keys of the column family starting with `prefix`. Fails if
+    /// the comparator doesn't support prefix scans.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<FusedIterator<LsmIterator>> {
+        self.core.scan_prefix(prefix)
+    }
 }
 
 impl LsmStorage {
     pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_options(path, LsmStorageOptions::default())
+    }
+
+    /// Open the storage, the structure of the trees is rebuilt from the manifest and memtables
+    /// that were not flushed before are recovered from the WALs as immutable memtables. Every
+    /// column family is opened with `options`.
+    pub fn open_with_options(path: impl AsRef<Path>, options: LsmStorageOptions) -> Result<Self> {
+        Self::open_with_column_families(path, options, HashMap::new())
+    }
+
+    /// Open the storage, the column families in `column_families` are opened with their own
+    /// options and created if they don't exist. The other ones, the default family included
+    /// unless it is listed, are opened with `options`. The options of the WAL and the manifest
+    /// are always taken from `options`.
+    pub fn open_with_column_families(
+        path: impl AsRef<Path>,
+        options: LsmStorageOptions,
+        mut column_families: HashMap<String, LsmStorageOptions>,
+    ) -> Result<Self> {
+        let shared = SharedState::open(path.as_ref(), options, &column_families)?;
+        let core = shared.column_families.read()[&DEFAULT_COLUMN_FAMILY_ID].clone();
+        let existing: Vec<String> = shared
+            .column_families()
+            .iter()
+            .map(|cf| cf.name.clone())
+            .collect();
+        for name in existing {
+            column_families.remove(&name);
+        }
+        for (name, options) in column_families {
+            shared.create_column_family(&name, options)?;
+        }
+        let (flush_notifier, flush_rx) = crossbeam_channel::unbounded();
+        let flush_thread = shared.spawn_flush_thread(flush_rx)?;
+        let (compaction_notifier, compaction_rx) = crossbeam_channel::unbounded();
+        let compaction_thread = shared.spawn_compaction_thread(compaction_rx)?;
         Ok(Self {
-            inner: Arc::new(RwLock::new(Arc::new(LsmStorageInner::create()))),
-            flush_lock: Mutex::new(()),
-            path: path.as_ref().to_path_buf(),
-            block_cache: Arc::new(BlockCache::new(1 << 20)), // 4GB block cache
+            shared,
+            core,
+            flush_notifier,
+            flush_thread: Mutex::new(Some(flush_thread)),
+            compaction_notifier,
+            compaction_thread: Mutex::new(Some(compaction_thread)),
         })
     }
 
-    /// Get a key from the storage. In day 7, this can be further optimized by using a bloom filter.
+    /// Create a column family with its own options, the options of the WAL and the manifest are
+    /// ignored. Fails if a family with the same name exists.
+    pub fn create_column_family(
+        &self,
+        name: &str,
+        options: LsmStorageOptions,
+    ) -> Result<ColumnFamily> {
+        let core = self.shared.create_column_family(name, options)?;
+        Ok(ColumnFamily { core })
+    }
+
+    /// Drop a column family and delete its SSTs. Handles of the family can still read what it
+    /// had, but can no longer write to it.
+    pub fn drop_column_family(&self, name: &str) -> Result<()> {
+        self.shared.drop_column_family(name)
+    }
+
+    /// Get the handle of a column family by name.
+    pub fn column_family(&self, name: &str) -> Option<ColumnFamily> {
+        self.shared
+            .column_families()
+            .into_iter()
+            .find(|cf| cf.name == name)
+            .map(|core| ColumnFamily { core })
+    }
+
+    /// Names of the column families, the default one included.
+    pub fn column_family_names(&self) -> Vec<String> {
+        self.shared
+            .column_families()
+            .iter()
+            .map(|cf| cf.name.clone())
+            .collect()
+    }
+
+    /// Get a key from the storage. SSTs whose bloom filter rules out the key are skipped without
+    /// reading any block.
     pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
-        let snapshot = {
-            let guard = self.inner.read();
-            Arc::clone(&guard)
-        }; // drop global lock here
+        self.core.get(key)
+    }
 
-        // Search on the current memtable.
-        if let Some(value) = snapshot.memtable.get(key) {
-            if value.is_empty() {
-                // found tomestone, return key not exists
-                return Ok(None);
+    /// Put a key-value pair into the storage by writing into the current memtable. The memtable
+    /// is frozen once it exceeds `write_buffer_size`.
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.core.put(key, value)
+    }
+
+    /// Put a key-value pair that expires after `ttl`, by the clock in the options. Once expired,
+    /// the key is deleted, reads no longer see it and compaction drops it.
+    pub fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        self.core.put_with_ttl(key, value, ttl)
+    }
+
+    /// Remove a key from the storage by writing a tombstone.
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        self.core.delete(key)
+    }
+
+    /// Write a merge operand of a key, it is applied to the value of the key by the merge
+    /// operator when the key is read. Fails if no merge operator is configured.
+    pub fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> {
+        self.core.merge(key, operand)
+    }
+
+    /// Remove the keys in `[start, end)` from the storage by writing a single range tombstone.
+    pub fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+        self.core.delete_range(start, end)
+    }
+
+    /// Apply all the entries of `batch` atomically, they go into the same WAL record, so after a
+    /// crash either all of them are recovered or none. The entries may span column families.
+    pub fn write(&self, batch: &WriteBatch) -> Result<()> {
+        self.shared.write(batch)
+    }
+
+    /// Persist data to disk.
+    ///
+    /// In day 3: flush the current memtable to disk as L0 SST.
+    /// In day 6: call `fsync` on WAL.
+    pub fn sync(&self) -> Result<()> {
+        for column_family in self.shared.column_families() {
+            column_family.sync()?;
+        }
+        self.shared.delete_obsolete_wals()
+    }
+
+    /// Run compaction until no level exceeds its target, instead of waiting for the compaction
+    /// thread.
+    pub fn compact(&self) -> Result<()> {
+        for column_family in self.shared.column_families() {
+            column_family.trigger_compaction()?;
+        }
+        Ok(())
+    }
+
+    /// Whether writes are currently delayed or stopped, and why.
+    pub fn write_stall_status(&self) -> WriteStallStatus {
+        self.core.write_stall_status()
+    }
+
+    /// Take a consistent snapshot of the storage, it only sees the writes done before it is taken.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::new(self.core.clone())
+    }
+
+    /// Begin an optimistic transaction, it reads from a snapshot taken now.
+    pub fn begin(&self) -> Transaction {
+        Transaction::new(self.core.clone(), false)
+    }
+
+    /// Begin a pessimistic transaction, it locks the keys it is going to write.
+    pub fn begin_pessimistic(&self) -> Transaction {
+        Transaction::new(self.core.clone(), true)
+    }
+
+    /// Create an iterators over a range of keys.
+    pub fn scan(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        self.core.scan(lower, upper)
+    }
+
+    /// Create an iterators over a range of keys positioned at the last key, `prev` moves it
+    /// toward the first key.
+    pub fn scan_rev(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        self.core.scan_rev(lower, upper)
+    }
+
+    /// Create an iterator over the keys starting with `prefix`, it stops at the first key after
+    /// them. If `prefix` is a whole prefix of the prefix extractor, the memtables and SSTs whose
+    /// filter rules out the prefix are skipped. Fails if the comparator doesn't support prefix
+    /// scans.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<FusedIterator<LsmIterator>> {
+        self.core.scan_prefix(prefix)
+    }
+}
+
+impl SharedState {
+    fn open(
+        path: &Path,
+        options: LsmStorageOptions,
+        column_families: &HashMap<String, LsmStorageOptions>,
+    ) -> Result<Arc<Self>> {
+        for options in std::iter::once(&options).chain(column_families.values()) {
+            options.validate()?;
+        }
+        let path = path.to_path_buf();
+        std::fs::create_dir_all(&path)
+            .with_context(|| format!("failed to create storage directory {:?}", path))?;
+        let block_cache = Arc::new(BlockCache::new(1 << 20)); // 4GB block cache
+
+        let manifest_path = path.join("MANIFEST");
+        let (manifest, mut manifest_snapshot) = if manifest_path.exists() {
+            Manifest::recover(&manifest_path)?
+        } else {
+            (
+                Manifest::create(&manifest_path)?,
+                ManifestSnapshot::default(),
+            )
+        };
+        if !manifest_snapshot
+            .column_families
+            .contains_key(&DEFAULT_COLUMN_FAMILY_ID)
+        {
+            let options = column_families
+                .get(DEFAULT_COLUMN_FAMILY)
+                .unwrap_or(&options);
+            let record = ManifestRecord::CreateColumnFamily {
+                id: DEFAULT_COLUMN_FAMILY_ID,
+                name: DEFAULT_COLUMN_FAMILY.to_string(),
+                comparator: options.comparator.name().to_string(),
+                num_of_levels: CompactionController::new(&options.compaction_options)
+                    .num_of_levels(),
+                log_number: 0,
+            };
+            manifest.add_record(&record)?;
+            manifest_snapshot.apply(record);
+        }
+
+        let cf_options = |layout: &ColumnFamilyLayout| {
+            column_families
+                .get(&layout.name)
+                .unwrap_or(&options)
+                .clone()
+        };
+        let mut sstables = BTreeMap::new();
+        for (id, layout) in &manifest_snapshot.column_families {
+            let comparator = cf_options(layout).comparator;
+            if layout.comparator != comparator.name() {
+                bail!(
+                    "column family {} is ordered by comparator {:?}, but opened with {:?}",
+                    layout.name,
+                    layout.comparator,
+                    comparator.name()
+                );
+            }
+            let tables = layout
+                .l0_sstables
+                .iter()
+                .chain(layout.levels.iter().flatten())
+                .map(|id| {
+                    let file = FileObject::open(&Self::sst_path(&path, *id))?;
+                    Ok(Arc::new(SsTable::open_with_comparator(
+                        *id,
+                        Some(block_cache.clone()),
+                        file,
+                        comparator.clone(),
+                    )?))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            sstables.insert(*id, tables);
+        }
+        // SSTs of a flush or a compaction that crashed before recording them in the manifest, they
+        // have IDs no record has used. Nothing is deleted unless the manifest is replayed cleanly.
+        if !manifest.dropped_torn_record() {
+            for id in Self::file_ids(&path, "sst")? {
+                if id >= manifest_snapshot.next_sst_id {
+                    std::fs::remove_file(Self::sst_path(&path, id))?;
+                }
+            }
+        }
+        // temporary files of SSTs and of the manifest left by a crash before they were renamed
+        for entry in std::fs::read_dir(&path)? {
+            let entry = entry?;
+            let is_file = entry.file_type()?.is_file();
+            if is_file && entry.path().extension().is_some_and(|ext| ext == "tmp") {
+                std::fs::remove_file(entry.path())?;
             }
-            return Ok(Some(value));
         }
 
-        // Search on immutable memtables.
-        // imm_memtables is from earliest to latest, so need reverse
-        for memtable in snapshot.imm_memtables.iter().rev() {
-            if let Some(value) = memtable.get(key) {
-                if value.is_empty() {
-                    // found tomestone, return key not exists
-                    return Ok(None);
+        let mut wal_ids = Self::file_ids(&path, "wal")?;
+        wal_ids.sort_unstable();
+        let mut next_sst_id = manifest_snapshot
+            .next_sst_id
+            .max(1)
+            .max(wal_ids.last().map_or(0, |id| id + 1));
+
+        // every WAL is replayed, an entry is recovered unless its family is dropped or has
+        // flushed it
+        let mut imm_memtables: HashMap<u32, Vec<Arc<MemTable>>> = HashMap::new();
+        if options.enable_wal {
+            for &wal_id in &wal_ids {
+                let mut memtables: BTreeMap<u32, MemTable> = BTreeMap::new();
+                Wal::replay(Self::wal_path(&path, wal_id), |column_family, key, value| {
+                    let Some(layout) = manifest_snapshot.column_families.get(&column_family) else {
+                        return;
+                    };
+                    if wal_id < layout.log_number {
+                        return;
+                    }
+                    memtables
+                        .entry(column_family)
+                        .or_insert_with(|| {
+                            next_sst_id += 1;
+                            let options = cf_options(layout);
+                            MemTable::create(
+                                next_sst_id - 1,
+                                wal_id,
+                                options.comparator,
+                                options.prefix_extractor,
+                            )
+                        })
+                        .put(key, value);
+                })?;
+                for (column_family, memtable) in memtables {
+                    imm_memtables.entry(column_family).or_default().push(Arc::new(memtable));
                 }
-                return Ok(Some(value));
             }
         }
+        // recovered memtables may have newer writes than any SST
+        let last_seq = sstables
+            .values()
+            .flatten()
+            .map(|sst| sst.max_seq)
+            .chain(
+                imm_memtables
+                    .values()
+                    .flatten()
+                    .map(|memtable| memtable.max_seq()),
+            )
+            .max()
+            .unwrap_or(0);
 
-        // Search on ssTables
-        let mut iters = Vec::new();
-        for sstable in snapshot.l0_sstables.iter().rev() {
-            let iter = SsTableIterator::create_and_seek_to_key(sstable.clone(), key)?;
-            iters.push(Box::new(iter));
+        let wal = if options.enable_wal {
+            let wal_id = next_sst_id;
+            next_sst_id += 1;
+            Some((
+                wal_id,
+                Wal::create(Self::wal_path(&path, wal_id), options.wal_sync_policy)?,
+            ))
+        } else {
+            None
+        };
+        let shared = Arc::new(Self {
+            path,
+            block_cache,
+            manifest,
+            write_lock: Mutex::new(()),
+            next_sst_id: AtomicUsize::new(next_sst_id),
+            next_column_family_id: AtomicU32::new(manifest_snapshot.next_column_family_id),
+            wal: RwLock::new(wal),
+            old_wals: Mutex::new(wal_ids),
+            column_families: RwLock::new(BTreeMap::new()),
+            options: options.clone(),
+            mvcc: Mvcc::new(last_seq),
+            lock_manager: LockManager::new(),
+            background_errors: BackgroundErrors::default(),
+        });
+        for (id, layout) in manifest_snapshot.column_families {
+            let options = cf_options(&layout);
+            let core = LsmStorageCore::new(
+                shared.clone(),
+                id,
+                layout,
+                options,
+                sstables.remove(&id).unwrap_or_default(),
+                imm_memtables.remove(&id).unwrap_or_default(),
+            );
+            shared.column_families.write().insert(id, Arc::new(core));
+        }
+        // WALs which have nothing to recover
+        shared.delete_obsolete_wals()?;
+        Ok(shared)
+    }
+
+    /// The column families which are not dropped.
+    pub(crate) fn column_families(&self) -> Vec<Arc<LsmStorageCore>> {
+        self.column_families.read().values().cloned().collect()
+    }
+
+    fn create_column_family(
+        self: &Arc<Self>,
+        name: &str,
+        options: LsmStorageOptions,
+    ) -> Result<Arc<LsmStorageCore>> {
+        // no entry of the family can be written before it is recorded
+        let _write_lock = self.write_lock.lock();
+        if self.column_families().iter().any(|cf| cf.name == name) {
+            bail!("column family {} already exists", name);
+        }
+        options.validate()?;
+        let id = self.next_column_family_id.fetch_add(1, Ordering::SeqCst);
+        let log_number = self.current_wal_id();
+        let num_of_levels = CompactionController::new(&options.compaction_options).num_of_levels();
+        let layout = ColumnFamilyLayout {
+            name: name.to_string(),
+            comparator: options.comparator.name().to_string(),
+            log_number,
+            l0_sstables: vec![],
+            levels: vec![vec![]; num_of_levels],
+        };
+        let core = Arc::new(LsmStorageCore::new(
+            self.clone(),
+            id,
+            layout,
+            options,
+            vec![],
+            vec![],
+        ));
+        self.write_manifest(&ManifestRecord::CreateColumnFamily {
+            id,
+            name: name.to_string(),
+            comparator: core.options.comparator.name().to_string(),
+            num_of_levels,
+            log_number,
+        })?;
+        self.column_families.write().insert(id, core.clone());
+        Ok(core)
+    }
+
+    fn drop_column_family(&self, name: &str) -> Result<()> {
+        if name == DEFAULT_COLUMN_FAMILY {
+            bail!("the default column family can't be dropped");
+        }
+        let Some(core) = self
+            .column_families()
+            .into_iter()
+            .find(|cf| cf.name == name)
+        else {
+            bail!("column family {} doesn't exist", name);
+        };
+        {
+            // wait for the running flush, compaction and write of the family
+            let _flush_lock = core.flush_lock.lock();
+            let _compaction_lock = core.compaction_lock.lock();
+            let _write_lock = self.write_lock.lock();
+            self.write_manifest(&ManifestRecord::DropColumnFamily(core.id))?;
+            core.dropped.store(true, Ordering::SeqCst);
+            self.column_families.write().remove(&core.id);
         }
-        let merge_iter = MergeIterator::create(iters);
-        if merge_iter.is_valid() {
-            return Ok(Some(Bytes::copy_from_slice(merge_iter.value())));
+        // readers holding an old snapshot keep the file open, so it is safe to remove it
+        let snapshot = Arc::clone(&core.inner.read());
+        for sst in snapshot.sstables() {
+            std::fs::remove_file(self.path_of_sst(sst.sst_id()))?;
+        }
+        // its entries in the WALs are no longer needed
+        self.delete_obsolete_wals()
+    }
+
+    fn write(&self, batch: &WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let mut column_families: BTreeMap<u32, Arc<LsmStorageCore>> = BTreeMap::new();
+        for (id, entry) in batch.entries() {
+            if !column_families.contains_key(id) {
+                let Some(core) = self.column_families.read().get(id).cloned() else {
+                    bail!("column family {} doesn't exist", id);
+                };
+                column_families.insert(*id, core);
+            }
+            // nothing is written, the operand couldn't be read back
+            if matches!(entry, WriteBatchEntry::Merge(..))
+                && column_families[id].options.merge_operator.is_none()
+            {
+                bail!(
+                    "no merge operator is configured for column family {}",
+                    column_families[id].name
+                );
+            }
+        }
+        for core in column_families.values() {
+            core.stall_writes()?;
+        }
+        let _write_lock = self.write_lock.lock();
+        let mut entries: BTreeMap<(u32, Bytes), (ValueType, Bytes)> = BTreeMap::new();
+        let mut range_tombstones: Vec<(u32, &Bytes, &Bytes)> = Vec::new();
+        for (id, entry) in batch.entries() {
+            let core = &column_families[id];
+            let comparator = &*core.options.comparator;
+            let in_range = |start: &[u8], end: &[u8], key: &[u8]| {
+                comparator.compare(start, key).is_le() && comparator.compare(key, end).is_lt()
+            };
+            match entry {
+                WriteBatchEntry::Put(key, value) => {
+                    entries.insert((*id, key.clone()), (ValueType::Put, value.clone()));
+                }
+                WriteBatchEntry::Delete(key) => {
+                    entries.insert((*id, key.clone()), (ValueType::Delete, Bytes::new()));
+                }
+                WriteBatchEntry::Merge(key, operand) => {
+                    // the entries of a batch share a sequence number, so an operand is combined
+                    // with the earlier entry of the batch on the same key
+                    let entry = match entries.get(&(*id, key.clone())) {
+                        Some((ValueType::Put, value)) => {
+                            (ValueType::Put, core.full_merge(key, Some(value), operand)?)
+                        }
+                        Some((ValueType::Merge, earlier)) => {
+                            let operator = core
+                                .options
+                                .merge_operator
+                                .as_deref()
+                                .context("no merge operator is configured")?;
+                            let operand = operator
+                                .partial_merge(key, &[earlier, operand])
+                                .with_context(|| {
+                                    format!(
+                                        "merge operands of {:?} in one batch can't be combined",
+                                        key
+                                    )
+                                })?;
+                            (ValueType::Merge, operand)
+                        }
+                        Some(_) => (ValueType::Put, core.full_merge(key, None, operand)?),
+                        // a range tombstone of the batch doesn't delete the operand
+                        None if range_tombstones
+                            .iter()
+                            .any(|&(cf, start, end)| cf == *id && in_range(start, end, key)) =>
+                        {
+                            (ValueType::Put, core.full_merge(key, None, operand)?)
+                        }
+                        None => (ValueType::Merge, operand.clone()),
+                    };
+                    entries.insert((*id, key.clone()), entry);
+                }
+                WriteBatchEntry::DeleteRange(start, end) => {
+                    if comparator.compare(start, end).is_ge() {
+                        continue;
+                    }
+                    // the range tombstone deletes the earlier entries of the batch in the range,
+                    // the later ones share its sequence number and are not deleted
+                    entries.retain(|(cf, key), _| !(cf == id && in_range(start, end, key)));
+                    range_tombstones.push((*id, start, end));
+                }
+            }
+        }
+        let writes: Vec<(&LsmStorageCore, Vec<WriteEntry>)> = column_families
+            .iter()
+            .map(|(id, core)| {
+                let entries = entries
+                    .range((*id, Bytes::new())..)
+                    .take_while(|((cf, _), _)| cf == id)
+                    .map(|((_, key), (value_type, value))| (&key[..], *value_type, &value[..]))
+                    .chain(
+                        range_tombstones
+                            .iter()
+                            .filter(|(cf, _, _)| cf == id)
+                            .map(|(_, start, end)| (&start[..], ValueType::RangeDelete, &end[..])),
+                    )
+                    .collect();
+                (core.as_ref(), entries)
+            })
+            .filter(|(_, entries): &(_, Vec<_>)| !entries.is_empty())
+            .collect();
+        if writes.is_empty() {
+            return Ok(());
+        }
+        let writes: Vec<(&LsmStorageCore, &[WriteEntry])> = writes
+            .iter()
+            .map(|(core, entries)| (*core, &entries[..]))
+            .collect();
+        self.write_memtables(&writes)
+    }
+
+    /// Write the entries of each column family to its current memtable with the next sequence
+    /// number, must be called with `write_lock` held. All the entries share the sequence number
+    /// and the WAL record, so readers see all of them or none, and so does the recovery.
+    fn write_memtables(&self, writes: &[(&LsmStorageCore, &[WriteEntry])]) -> Result<()> {
+        if let Some((core, _)) = writes.iter().find(|(core, _)| core.is_dropped()) {
+            bail!("column family {} is dropped", core.name);
+        }
+        let seq = self.mvcc.last_seq() + 1;
+        let writes: Vec<(&LsmStorageCore, Vec<(InternalKey, Bytes)>)> = writes
+            .iter()
+            .map(|(core, entries)| {
+                let entries = entries
+                    .iter()
+                    .map(|(key, value_type, value)| {
+                        (
+                            InternalKey::new(key, seq, *value_type),
+                            Bytes::copy_from_slice(value),
+                        )
+                    })
+                    .collect();
+                (*core, entries)
+            })
+            .collect();
+        let sizes: Vec<usize> = {
+            // the skipList in MemTable is concurrency safe, so read lock here is enough
+            // and change memTable to imm_memtables use write lock, ensure that no put() called in sync()
+            let guards: Vec<_> = writes.iter().map(|(core, _)| core.inner.read()).collect();
+            // a WAL is not rotated while the entries in it are not in the memtables yet
+            let wal = self.wal.read();
+            if let Some((_, wal)) = wal.as_ref() {
+                let entries: Vec<(u32, &[u8], &[u8])> = writes
+                    .iter()
+                    .flat_map(|(core, entries)| {
+                        entries
+                            .iter()
+                            .map(|(key, value)| (core.id, key.as_bytes(), &value[..]))
+                    })
+                    .collect();
+                wal.put_batch(&entries)?;
+            }
+            for ((_, entries), guard) in writes.iter().zip(&guards) {
+                guard.memtable.put_batch(entries);
+            }
+            // published before the read locks are released, so a reader that sees the sequence
+            // number also sees the memtables holding the entries
+            self.mvcc.publish(seq);
+            guards
+                .iter()
+                .map(|guard| guard.memtable.approximate_size())
+                .collect()
+        };
+        // transactions only work on the default column family
+        if let Some((_, entries)) = writes
+            .iter()
+            .find(|(core, _)| core.id == DEFAULT_COLUMN_FAMILY_ID)
+        {
+            self.mvcc.record_write(seq, entries);
+        }
+        for ((core, _), size) in writes.iter().zip(sizes) {
+            if size >= core.options.write_buffer_size {
+                // other writers may have frozen it already
+                core.freeze_memtable(|memtable| {
+                    memtable.approximate_size() >= core.options.write_buffer_size
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The ID of the WAL writes go to, 0 if the WAL is disabled.
+    fn current_wal_id(&self) -> usize {
+        self.wal.read().as_ref().map_or(0, |(id, _)| *id)
+    }
+
+    /// Start a new WAL and return its ID, writes go to it from now on.
+    fn rotate_wal(&self) -> Result<usize> {
+        let mut guard = self.wal.write();
+        let Some((old_id, old_wal)) = guard.as_ref() else {
+            return Ok(0);
+        };
+        let id = self.next_sst_id();
+        let wal = Wal::create(self.path_of_wal(id), self.options.wal_sync_policy)?;
+        // The old WAL will not be written anymore, make sure it is on disk.
+        old_wal.sync()?;
+        self.old_wals.lock().push(*old_id);
+        *guard = Some((id, wal));
+        Ok(id)
+    }
+
+    /// Delete the WALs whose entries are flushed by every column family.
+    fn delete_obsolete_wals(&self) -> Result<()> {
+        let obsolete = {
+            // no write is in progress, every entry in the WALs is in a memtable
+            let _write_lock = self.write_lock.lock();
+            let current_wal_id = self.current_wal_id();
+            let min_log_number = self
+                .column_families()
+                .iter()
+                .filter_map(|cf| cf.min_log_number())
+                .min()
+                .unwrap_or(current_wal_id)
+                .min(current_wal_id);
+            let mut old_wals = self.old_wals.lock();
+            let obsolete: Vec<usize> = old_wals
+                .iter()
+                .copied()
+                .filter(|id| *id < min_log_number)
+                .collect();
+            old_wals.retain(|id| *id >= min_log_number);
+            obsolete
+        };
+        for id in obsolete {
+            std::fs::remove_file(self.path_of_wal(id))?;
+        }
+        Ok(())
+    }
+
+    /// Flush the immutable memtables of every column family, then delete the WALs that are no
+    /// longer needed.
+    fn trigger_flush(&self) -> Result<()> {
+        let mut flushed = false;
+        for column_family in self.column_families() {
+            flushed |= column_family.trigger_flush()?;
+        }
+        if flushed {
+            self.delete_obsolete_wals()?;
+        }
+        Ok(())
+    }
+
+    fn spawn_flush_thread(
+        self: &Arc<Self>,
+        rx: crossbeam_channel::Receiver<()>,
+    ) -> Result<JoinHandle<()>> {
+        let this = self.clone();
+        let handle = std::thread::spawn(move || {
+            let ticker = crossbeam_channel::tick(Duration::from_millis(50));
+            loop {
+                crossbeam_channel::select! {
+                    recv(ticker) -> _ => {
+                        this.record_background_result(BackgroundJob::Flush, this.trigger_flush())
+                    }
+                    recv(rx) -> _ => return,
+                }
+            }
+        });
+        Ok(handle)
+    }
+
+    /// Allocate a new SST ID.
+    pub(crate) fn next_sst_id(&self) -> usize {
+        self.next_sst_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Append `record` to the manifest. A change of a column family is recorded with the write
+    /// lock of its `inner` held, so its records are in the same order as its changes.
+    pub(crate) fn write_manifest(&self, record: &ManifestRecord) -> Result<()> {
+        self.manifest.add_record(record)?;
+        if self.manifest.size()? > self.options.max_manifest_size {
+            self.manifest
+                .rollover(self.next_sst_id.load(Ordering::SeqCst))?;
+        }
+        Ok(())
+    }
+
+    /// IDs of the files in the storage directory `path` with `extension`.
+    fn file_ids(path: &Path, extension: &str) -> Result<Vec<usize>> {
+        Ok(std::fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<Vec<_>>>()?
+            .iter()
+            .filter(|path| path.extension().is_some_and(|ext| ext == extension))
+            .filter_map(|path| path.file_stem()?.to_str()?.parse().ok())
+            .collect())
+    }
+
+    pub(crate) fn path_of_sst(&self, id: usize) -> PathBuf {
+        Self::sst_path(&self.path, id)
+    }
+
+    fn sst_path(path: &Path, id: usize) -> PathBuf {
+        path.join(format!("{:05}.sst", id))
+    }
+
+    fn path_of_wal(&self, id: usize) -> PathBuf {
+        Self::wal_path(&self.path, id)
+    }
+
+    fn wal_path(path: &Path, id: usize) -> PathBuf {
+        path.join(format!("{:05}.wal", id))
+    }
+}
+
+impl LsmStorageCore {
+    /// Open a column family with the SSTs in `layout`, and the memtables recovered from the WALs.
+    fn new(
+        shared: Arc<SharedState>,
+        id: u32,
+        layout: ColumnFamilyLayout,
+        options: LsmStorageOptions,
+        sstables: Vec<Arc<SsTable>>,
+        imm_memtables: Vec<Arc<MemTable>>,
+    ) -> Self {
+        let compaction_controller = CompactionController::new(&options.compaction_options);
+        let memtable = MemTable::create(
+            shared.next_sst_id(),
+            shared.current_wal_id(),
+            options.comparator.clone(),
+            options.prefix_extractor.clone(),
+        );
+        let mut inner = LsmStorageInner {
+            memtable: Arc::new(memtable),
+            imm_memtables,
+            l0_sstables: vec![],
+            levels: vec![],
+        };
+        inner.apply_layout(&layout, sstables);
+        Self {
+            id,
+            name: layout.name,
+            inner: RwLock::new(Arc::new(inner)),
+            flush_lock: Mutex::new(()),
+            compaction_lock: Mutex::new(()),
+            options,
+            compaction_controller,
+            write_controller: WriteController::default(),
+            shared,
+            dropped: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn is_dropped(&self) -> bool {
+        self.dropped.load(Ordering::SeqCst)
+    }
+
+    /// The current state of the tree and the sequence number of the latest write in it, taken
+    /// together so every write up to the sequence number is in the state.
+    fn read_state(&self) -> (Arc<LsmStorageInner>, u64) {
+        let guard = self.inner.read();
+        (Arc::clone(&guard), self.shared.mvcc.last_seq())
+    } // drop global lock here
+    fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        let (snapshot, read_seq) = self.read_state();
+        self.get_from(&snapshot, key, read_seq)
+    }
+
+    /// Get the latest value of `key`, with the sequence number it is read at.
+    pub(crate) fn get_latest(&self, key: &[u8]) -> Result<(Option<Bytes>, u64)> {
+        let (snapshot, read_seq) = self.read_state();
+        Ok((self.get_from(&snapshot, key, read_seq)?, read_seq))
+    }
+
+    /// Get the value of `key` at `read_seq`, the versions written after it are ignored.
+    pub(crate) fn get_at(&self, key: &[u8], read_seq: u64) -> Result<Option<Bytes>> {
+        let snapshot = Arc::clone(&self.inner.read());
+        self.get_from(&snapshot, key, read_seq)
+    }
+
+    fn get_from(
+        &self,
+        snapshot: &LsmStorageInner,
+        key: &[u8],
+        read_seq: u64,
+    ) -> Result<Option<Bytes>> {
+        // a version older than a range tombstone covering the key is deleted
+        let deleted_before = snapshot.max_covering_seq(key, read_seq);
+        let now = self.options.clock.now();
+        // merge operands are collected from the latest until a put or a delete is found
+        let mut operands = Vec::new();
+        let mut read_seq = read_seq;
+        let existing_value = loop {
+            match Self::get_version(snapshot, key, read_seq)? {
+                Some((seq, ValueType::Merge, operand)) if seq >= deleted_before => {
+                    operands.push(operand);
+                    read_seq = seq - 1;
+                }
+                // an expired value is deleted
+                Some((seq, value_type, value)) if seq >= deleted_before => {
+                    break live_value(value_type, &value, now).map(|live| value.slice_ref(live))
+                }
+                // found tomestone, return key not exists
+                _ => break None,
+            }
+        };
+        if operands.is_empty() {
+            return Ok(existing_value);
+        }
+        operands.reverse();
+        let value = full_merge(
+            self.options.merge_operator.as_deref(),
+            key,
+            existing_value.as_deref(),
+            &operands,
+        )?;
+        Ok(Some(value))
+    }
+
+    /// The latest version of `key` visible at `read_seq`, range tombstones are not checked.
+    fn get_version(
+        snapshot: &LsmStorageInner,
+        key: &[u8],
+        read_seq: u64,
+    ) -> Result<Option<(u64, ValueType, Bytes)>> {
+        // Search on the current memtable, then on immutable memtables.
+        // imm_memtables is from earliest to latest, so need reverse
+        for memtable in
+            std::iter::once(&snapshot.memtable).chain(snapshot.imm_memtables.iter().rev())
+        {
+            if let Some(version) = memtable.get(key, read_seq) {
+                return Ok(Some(version));
+            }
+        }
+
+        // Search on L0 ssTables, from latest to earliest, their key ranges may overlap.
+        // Then search on each level, SsTables in a level are sorted by key range and don't
+        // overlap, only the one whose range may contain the key need to be searched.
+        let level_sstables = snapshot.levels.iter().filter_map(|level| {
+            let level = sorted_run(level);
+            let idx =
+                level.partition_point(|sst| sst.comparator().compare(sst.last_key(), key).is_lt());
+            level.get(idx)
+        });
+        for sstable in snapshot.l0_sstables.iter().rev().chain(level_sstables) {
+            if let Some(version) = sstable.get_version(key, read_seq)? {
+                return Ok(Some(version));
+            }
         }
 
         Ok(None)
     }
 
-    /// Put a key-value pair into the storage by writing into the current memtable.
-    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
         assert!(!value.is_empty(), "value cannot be empty");
         assert!(!key.is_empty(), "key cannot be empty");
 
-        // the skipList in MemTable is concurrency safe, so read lock here is enough
-        // and change memTable to imm_memtables use write lock, ensure that no put() called in sync()
-        let guard = self.inner.read();
-        guard.memtable.put(key, value);
+        self.stall_writes()?;
+        let _write_lock = self.shared.write_lock.lock();
+        self.write_memtable(&[(key, ValueType::Put, value)])
+    }
 
-        Ok(())
+    fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        assert!(!value.is_empty(), "value cannot be empty");
+        assert!(!key.is_empty(), "key cannot be empty");
+
+        let expire_at = self
+            .options
+            .clock
+            .now()
+            .saturating_add(ttl.as_millis() as u64);
+        let value = encode_value_with_expiry(value, expire_at);
+        self.stall_writes()?;
+        let _write_lock = self.shared.write_lock.lock();
+        self.write_memtable(&[(key, ValueType::PutWithTtl, &value)])
     }
 
-    /// Remove a key from the storage by writing an empty value.
-    pub fn delete(&self, key: &[u8]) -> Result<()> {
+    fn delete(&self, key: &[u8]) -> Result<()> {
         assert!(!key.is_empty(), "key cannot be empty");
 
-        let guard = self.inner.read();
-        guard.memtable.put(key, b"");
-        Ok(())
+        self.stall_writes()?;
+        let _write_lock = self.shared.write_lock.lock();
+        self.write_memtable(&[(key, ValueType::Delete, b"")])
     }
 
-    /// Persist data to disk.
-    ///
-    /// In day 3: flush the current memtable to disk as L0 SST.
-    /// In day 6: call `fsync` on WAL.
-    pub fn sync(&self) -> Result<()> {
-        let _flush_lock = self.flush_lock.lock();
-        let flush_memtable;
-        let sst_id;
+    fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> {
+        assert!(!key.is_empty(), "key cannot be empty");
+        if self.options.merge_operator.is_none() {
+            bail!("no merge operator is configured");
+        }
+
+        self.stall_writes()?;
+        let _write_lock = self.shared.write_lock.lock();
+        self.write_memtable(&[(key, ValueType::Merge, operand)])
+    }
+
+    /// Apply a single merge operand to `existing_value`.
+    fn full_merge(
+        &self,
+        key: &[u8],
+        existing_value: Option<&[u8]>,
+        operand: &[u8],
+    ) -> Result<Bytes> {
+        full_merge(
+            self.options.merge_operator.as_deref(),
+            key,
+            existing_value,
+            &[operand],
+        )
+    }
+
+    fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+        if self.options.comparator.compare(start, end).is_ge() {
+            return Ok(());
+        }
+
+        self.stall_writes()?;
+        let _write_lock = self.shared.write_lock.lock();
+        self.write_memtable(&[(start, ValueType::RangeDelete, end)])
+    }
 
-        // Move mutable memtable to immutable memtables.
+    /// Commit the writes of a transaction, fails if any key or range in `read_set` is written
+    /// after the sequence number it is read at.
+    pub(crate) fn commit_transaction(
+        &self,
+        read_set: &ReadSet,
+        writes: &BTreeMap<Bytes, Option<Bytes>>,
+    ) -> Result<()> {
+        // a read-only transaction reads a consistent snapshot, there is nothing to validate
+        if writes.is_empty() {
+            return Ok(());
+        }
+        self.stall_writes()?;
+        // no other write can be applied between the validation and the writes
+        let _write_lock = self.shared.write_lock.lock();
+        if self
+            .shared
+            .mvcc
+            .is_modified_after(read_set, &*self.options.comparator)
         {
-            let mut guard = self.inner.write();
-            let mut snapshot = guard.as_ref().clone();
-            // Swap the current memtable with a new one.
-            let memtable = std::mem::replace(&mut snapshot.memtable, Arc::new(MemTable::create()));
-            flush_memtable = memtable.clone();
-            sst_id = snapshot.next_sst_id;
-            // Add the memtable to the immutable memtables.
-            snapshot.imm_memtables.push(memtable);
-            // Update the snapshot.
-            *guard = Arc::new(snapshot);
+            return Err(TransactionError::Conflict.into());
+        }
+        self.write_entries(writes)
+    }
+
+    /// Write the resolved entries of a batch, `None` deletes the key. Must be called with
+    /// `write_lock` held.
+    fn write_entries(&self, entries: &BTreeMap<Bytes, Option<Bytes>>) -> Result<()> {
+        let entries: Vec<(&[u8], ValueType, &[u8])> = entries
+            .iter()
+            .map(|(key, value)| match value {
+                Some(value) => (&key[..], ValueType::Put, &value[..]),
+                None => (&key[..], ValueType::Delete, &b""[..]),
+            })
+            .collect();
+        self.write_memtable(&entries)
+    }
+
+    /// Write `entries` to the current memtable with the next sequence number, must be called with
+    /// `write_lock` held.
+    fn write_memtable(&self, entries: &[WriteEntry]) -> Result<()> {
+        self.shared.write_memtables(&[(self, entries)])
+    }
+
+    fn sync(&self) -> Result<()> {
+        let _flush_lock = self.flush_lock.lock();
+        self.force_freeze_memtable()?;
+        // flush from the earliest, memtables recovered from WAL are flushed too
+        while self.force_flush_earliest_memtable()? {}
+        Ok(())
+    }
+
+    /// Move the mutable memtable to immutable memtables, an empty memtable is kept.
+    fn force_freeze_memtable(&self) -> Result<()> {
+        self.freeze_memtable(|memtable| !memtable.is_empty())
+    }
+
+    /// Move the mutable memtable to immutable memtables if `should_freeze` holds for it, it is
+    /// checked with the write lock held.
+    fn freeze_memtable(&self, should_freeze: impl FnOnce(&MemTable) -> bool) -> Result<()> {
+        let mut guard = self.inner.write();
+        if !should_freeze(&guard.memtable) {
+            return Ok(());
         }
+        let mut snapshot = guard.as_ref().clone();
+        // the new memtable starts in a new WAL, so the WALs of the old one can be deleted once
+        // it is flushed
+        let log_number = self.shared.rotate_wal()?;
+        let memtable = MemTable::create(
+            self.shared.next_sst_id(),
+            log_number,
+            self.options.comparator.clone(),
+            self.options.prefix_extractor.clone(),
+        );
+        // Swap the current memtable with a new one.
+        let memtable = std::mem::replace(&mut snapshot.memtable, Arc::new(memtable));
+        // Add the memtable to the immutable memtables.
+        snapshot.imm_memtables.push(memtable);
+        // Update the snapshot.
+        *guard = Arc::new(snapshot);
+        Ok(())
+    }
 
-        // At this point, the old memtable should be disabled for write, and all write threads
-        // should be operating on the new memtable. We can safely flush the old memtable to
-        // disk.
-        let mut builder = SsTableBuilder::new(4096);
-        flush_memtable.flush(&mut builder)?;
-        let sst = Arc::new(builder.build(
-            sst_id,
-            Some(self.block_cache.clone()),
-            self.path_of_sst(sst_id),
-        )?);
+    /// The first WAL holding entries of the family that are not flushed, `None` if there is none.
+    fn min_log_number(&self) -> Option<usize> {
+        let guard = self.inner.read();
+        guard
+            .imm_memtables
+            .iter()
+            .chain(std::iter::once(&guard.memtable))
+            .find(|memtable| !memtable.is_empty())
+            .map(|memtable| memtable.log_number())
+    }
+
+    /// Flush the earliest immutable memtable to an L0 SST. Returns false if there is no
+    /// immutable memtable.
+    fn force_flush_earliest_memtable(&self) -> Result<bool> {
+        if self.is_dropped() {
+            return Ok(false);
+        }
+        let flush_memtable = {
+            let guard = self.inner.read();
+            match guard.imm_memtables.first() {
+                Some(memtable) => memtable.clone(),
+                None => return Ok(false),
+            }
+        };
+
+        // At this point, the memtable is disabled for write, and all write threads are
+        // operating on the new memtable. We can safely flush the memtable to disk.
+        let sst_id = flush_memtable.id();
+        // read after the memtable is frozen, a snapshot taken later sees every version in it
+        let live_snapshots = self.shared.mvcc.live_snapshots();
+        let mut builder = self.new_sst_builder(sst_id);
+        flush_memtable.flush(
+            &mut builder,
+            self.options.merge_operator.as_deref(),
+            &live_snapshots,
+        )?;
+        let sst = Arc::new(builder.build(sst_id, Some(self.shared.block_cache.clone()))?);
 
         // Add the flushed L0 table to the list.
         {
             let mut guard = self.inner.write();
             let mut snapshot = guard.as_ref().clone();
-            // Remove the memtable from the immutable memtables.
-            snapshot.imm_memtables.pop();
+            // Remove exactly the flushed memtable from the immutable memtables.
+            snapshot
+                .imm_memtables
+                .retain(|memtable| memtable.id() != sst_id);
             // Add L0 table
             snapshot.l0_sstables.push(sst);
-            // Update SST ID
-            snapshot.next_sst_id += 1;
+            // the entries of the family in the WALs before the next memtable are all flushed
+            let log_number = snapshot
+                .imm_memtables
+                .first()
+                .unwrap_or(&snapshot.memtable)
+                .log_number();
+            self.shared.write_manifest(&ManifestRecord::Flush {
+                column_family: self.id,
+                sst_id,
+                log_number,
+            })?;
             // Update the snapshot.
             *guard = Arc::new(snapshot);
         }
+        self.notify_write_stall_change();
+        Ok(true)
+    }
 
-        Ok(())
+    /// A builder of the SST `sst_id` of the family.
+    pub(crate) fn new_sst_builder(&self, sst_id: usize) -> SsTableBuilder {
+        let builder = SsTableBuilder::new_with_comparator(
+            self.shared.path_of_sst(sst_id),
+            self.options.block_size,
+            self.options.bloom_bits_per_key,
+            self.options.comparator.clone(),
+        )
+        .with_clock(self.options.clock.clone());
+        match &self.options.prefix_extractor {
+            Some(extractor) => builder.with_prefix_extractor(extractor.clone()),
+            None => builder,
+        }
     }
 
-    /// Create an iterators over a range of keys.
-    pub fn scan(
+    /// Flush all the immutable memtables, from the earliest. Returns false if there is none.
+    fn trigger_flush(&self) -> Result<bool> {
+        let _flush_lock = self.flush_lock.lock();
+        let mut flushed = false;
+        while self.force_flush_earliest_memtable()? {
+            flushed = true;
+        }
+        Ok(flushed)
+    }
+    fn scan(
         &self,
         lower: Bound<&[u8]>,
         upper: Bound<&[u8]>,
     ) -> Result<FusedIterator<LsmIterator>> {
-        let snapshot = {
-            let guard = self.inner.read();
-            Arc::clone(&guard)
-        }; // drop global lock here
+        let (snapshot, read_seq) = self.read_state();
+        self.scan_from(&snapshot, lower, upper, None, read_seq, Direction::Forward)
+    }
+
+    fn scan_rev(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        let (snapshot, read_seq) = self.read_state();
+        self.scan_from(&snapshot, lower, upper, None, read_seq, Direction::Backward)
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<FusedIterator<LsmIterator>> {
+        if !self.options.comparator.supports_prefix_scan() {
+            bail!(
+                "comparator {:?} doesn't support prefix scans",
+                self.options.comparator.name()
+            );
+        }
+        let (snapshot, read_seq) = self.read_state();
+        self.scan_from(
+            &snapshot,
+            Bound::Included(prefix),
+            Bound::Unbounded,
+            Some(prefix),
+            read_seq,
+            Direction::Forward,
+        )
+    }
+
+    /// Scan a range of keys at `read_seq`, the versions written after it are ignored.
+    pub(crate) fn scan_at(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        read_seq: u64,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        let snapshot = Arc::clone(&self.inner.read());
+        self.scan_from(&snapshot, lower, upper, None, read_seq, Direction::Forward)
+    }
+
+    /// Scan a range of keys backward at `read_seq`, the versions written after it are ignored.
+    pub(crate) fn scan_rev_at(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        read_seq: u64,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        let snapshot = Arc::clone(&self.inner.read());
+        self.scan_from(&snapshot, lower, upper, None, read_seq, Direction::Backward)
+    }
+
+    /// Scan a range of keys at `read_seq`, the iterator is positioned at the first key in
+    /// `direction`. With `prefix`, only the keys starting with it are scanned.
+    fn scan_from(
+        &self,
+        snapshot: &LsmStorageInner,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        prefix: Option<&[u8]>,
+        read_seq: u64,
+        direction: Direction,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        // the filters have the whole prefixes extracted from the keys
+        let prefix_filter = self
+            .options
+            .prefix_extractor
+            .as_deref()
+            .zip(prefix)
+            .filter(|(extractor, prefix)| prefix_of(*extractor, prefix) == Some(prefix));
+        let may_contain = |sst: &SsTable| {
+            prefix_filter
+                .is_none_or(|(extractor, prefix)| sst.may_contain_prefix(extractor, prefix))
+        };
 
         // scan in MemTables
         let mut memtable_iters = Vec::new();
-        memtable_iters.push(Box::new(snapshot.memtable.scan(lower, upper)));
         // imm_memtables is earliest to latest, merge operate need latest first, so when do merge need reverse
-        for memtable in snapshot.imm_memtables.iter().rev() {
+        for memtable in
+            std::iter::once(&snapshot.memtable).chain(snapshot.imm_memtables.iter().rev())
+        {
+            if prefix_filter.is_some_and(|(_, prefix)| !memtable.may_contain_prefix(prefix)) {
+                continue;
+            }
             memtable_iters.push(Box::new(memtable.scan(lower, upper)));
         }
-        let memtable_merge_iter = MergeIterator::create(memtable_iters);
+        let comparator = &self.options.comparator;
+        let memtable_merge_iter =
+            MergeIterator::create_with_comparator(memtable_iters, comparator.clone());
 
-        // Scan in SsTables
+        // Scan in L0 SsTables, skip the ones out of the range
         let mut table_iters = Vec::new();
-        for ssTable in snapshot.l0_sstables.iter().rev() {
-            let iter = match lower {
-                Bound::Included(key) => {
-                    SsTableIterator::create_and_seek_to_key(ssTable.clone(), key)?
-                },
-                Bound::Excluded(key) => {
-                    let mut iter = SsTableIterator::create_and_seek_to_key(ssTable.clone(), key)?;
-                    if iter.is_valid() && iter.key() == key {
-                        iter.next()?;
-                    }
-                    iter
-                },
-                Bound::Unbounded => {
-                    SsTableIterator::create_and_seek_to_first(ssTable.clone())?
-                }
-            };
-            table_iters.push(Box::new(iter));
+        for sstable in snapshot.l0_sstables.iter().rev() {
+            if sstable.num_of_blocks() == 0
+                || !range_overlap(
+                    &**comparator,
+                    lower,
+                    upper,
+                    sstable.first_key(),
+                    sstable.last_key(),
+                )
+                || !may_contain(sstable)
+            {
+                continue;
+            }
+            table_iters.push(Box::new(SsTableIterator::new(sstable.clone())));
+        }
+        let table_merge_iter =
+            MergeIterator::create_with_comparator(table_iters, comparator.clone());
+
+        // Scan in levels, one concat iterator for each level
+        let mut level_iters = Vec::new();
+        for level in &snapshot.levels {
+            let level = sorted_run(level)
+                .iter()
+                .filter(|sst| may_contain(sst))
+                .cloned()
+                .collect();
+            level_iters.push(Box::new(SstConcatIterator::new(level)));
         }
-        let table_merge_iter = MergeIterator::create(table_iters);
+        let level_merge_iter =
+            MergeIterator::create_with_comparator(level_iters, comparator.clone());
 
-        let iter = TwoMergeIterator::create(memtable_merge_iter, table_merge_iter)?;
+        let iter = TwoMergeIterator::create_with_comparator(
+            TwoMergeIterator::create_with_comparator(
+                memtable_merge_iter,
+                table_merge_iter,
+                comparator.clone(),
+            )?,
+            level_merge_iter,
+            comparator.clone(),
+        )?;
 
-        Ok(FusedIterator::new(LsmIterator::new(iter, map_bound(upper))?))
+        let range_tombstones = snapshot.range_tombstones(comparator);
+        // the iterators are positioned once through the merged iterator
+        let mut iter = LsmIterator::new(
+            iter,
+            map_bound(lower),
+            map_bound(upper),
+            prefix.map(Bytes::copy_from_slice),
+            read_seq,
+            range_tombstones,
+            self.options.merge_operator.clone(),
+            comparator.clone(),
+            self.options.clock.now(),
+        );
+        match direction {
+            Direction::Forward => iter.seek_to_first()?,
+            Direction::Backward => iter.seek_to_last()?,
+        }
+        Ok(FusedIterator::new(iter))
     }
 
-    fn path_of_sst(&self, id: usize) -> PathBuf {
-        self.path.join(format!("{:05}.sst", id))
+    /// The structure of the tree after `f` is applied to it, `f` works on the SST IDs of `state`.
+    pub(crate) fn map_layout(
+        &self,
+        state: &LsmStorageInner,
+        f: impl FnOnce(&mut ColumnFamilyLayout),
+    ) -> ColumnFamilyLayout {
+        let mut layout = ColumnFamilyLayout {
+            name: self.name.clone(),
+            comparator: self.options.comparator.name().to_string(),
+            l0_sstables: state.l0_sstables.iter().map(|sst| sst.sst_id()).collect(),
+            levels: state
+                .levels
+                .iter()
+                .map(|level| level.iter().map(|sst| sst.sst_id()).collect())
+                .collect(),
+            ..Default::default()
+        };
+        f(&mut layout);
+        layout
     }
+}
+
+/// The SSTs of a level with point keys, the ones with only range tombstones are skipped, their
+/// range tombstones are read from every SST of the tree.
+fn sorted_run(level: &[Arc<SsTable>]) -> &[Arc<SsTable>] {
+    &level[level.partition_point(|sst| sst.num_of_blocks() == 0)..]
+}
 
+/// Check if the key range `[first_key, last_key]` overlaps with the range `(lower, upper)`, in
+/// the order of `comparator`.
+fn range_overlap(
+    comparator: &dyn Comparator,
+    lower: Bound<&[u8]>,
+    upper: Bound<&[u8]>,
+    first_key: &[u8],
+    last_key: &[u8],
+) -> bool {
+    match upper {
+        Bound::Excluded(key) if comparator.compare(key, first_key).is_le() => return false,
+        Bound::Included(key) if comparator.compare(key, first_key).is_lt() => return false,
+        _ => {}
+    }
+    match lower {
+        Bound::Excluded(key) if comparator.compare(key, last_key).is_ge() => return false,
+        Bound::Included(key) if comparator.compare(key, last_key).is_gt() => return false,
+        _ => {}
+    }
+    true
 }
@@ -0,0 +1,273 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use bytes::{Buf, BufMut};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::compact::CompactionTask;
+use crate::utils::{contains_valid_record, SIZEOF_U32, SIZEOF_U64};
+
+/// The manifest is an append-only log of the changes made to the structure of the LSM trees of
+/// the column families. Replaying it rebuilds which SSTs are in which level of each family and
+/// which entries of the WALs are flushed.
+pub struct Manifest {
+    /// The file, and the structure after every record in it, written out on rollover.
+    file: Mutex<(File, ManifestSnapshot)>,
+    path: PathBuf,
+    /// Whether a torn record at the tail was dropped when the manifest was recovered.
+    dropped_torn_record: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ManifestRecord {
+    /// A column family is created. It has no entry in the WALs before `log_number`.
+    CreateColumnFamily {
+        id: u32,
+        name: String,
+        /// The name of the comparator ordering its keys.
+        comparator: String,
+        num_of_levels: usize,
+        log_number: usize,
+    },
+    /// A column family is dropped, its SSTs are deleted.
+    DropColumnFamily(u32),
+    /// A memtable of a column family is flushed to the L0 SST `sst_id`. The entries of the
+    /// family in the WALs before `log_number` are all flushed.
+    Flush {
+        column_family: u32,
+        sst_id: usize,
+        log_number: usize,
+    },
+    /// A compaction task of a column family is done, with the IDs of the SSTs it produced.
+    Compaction {
+        column_family: u32,
+        task: CompactionTask,
+        output: Vec<usize>,
+    },
+    /// The full structure of the LSM trees, it is the first record of a manifest after rollover.
+    Snapshot(ManifestSnapshot),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ManifestSnapshot {
+    /// The column families which are not dropped, by ID.
+    pub column_families: BTreeMap<u32, ColumnFamilyLayout>,
+    /// IDs of column families are never reused, so the entries of a dropped family left in the
+    /// WALs are never recovered into another one.
+    pub next_column_family_id: u32,
+    pub next_sst_id: usize,
+}
+
+/// The structure of the LSM tree of a column family.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ColumnFamilyLayout {
+    pub name: String,
+    /// The name of the comparator ordering the keys, the family can't be opened with another one.
+    pub comparator: String,
+    /// The entries of the family in the WALs before this one are all flushed.
+    pub log_number: usize,
+    /// IDs of the L0 SSTs, from earliest to latest.
+    pub l0_sstables: Vec<usize>,
+    /// IDs of the SSTs in each level.
+    pub levels: Vec<Vec<usize>>,
+}
+
+impl ManifestSnapshot {
+    /// Apply a record on top of the snapshot.
+    pub fn apply(&mut self, record: ManifestRecord) {
+        match record {
+            ManifestRecord::CreateColumnFamily {
+                id,
+                name,
+                comparator,
+                num_of_levels,
+                log_number,
+            } => {
+                let layout = ColumnFamilyLayout {
+                    name,
+                    comparator,
+                    log_number,
+                    l0_sstables: vec![],
+                    levels: vec![vec![]; num_of_levels],
+                };
+                self.column_families.insert(id, layout);
+                self.next_column_family_id = self.next_column_family_id.max(id + 1);
+            }
+            ManifestRecord::DropColumnFamily(id) => {
+                self.column_families.remove(&id);
+            }
+            ManifestRecord::Flush {
+                column_family,
+                sst_id,
+                log_number,
+            } => {
+                if let Some(layout) = self.column_families.get_mut(&column_family) {
+                    layout.l0_sstables.push(sst_id);
+                    layout.log_number = log_number;
+                }
+                self.next_sst_id = self.next_sst_id.max(sst_id + 1);
+            }
+            ManifestRecord::Compaction {
+                column_family,
+                task,
+                output,
+            } => {
+                if let Some(layout) = self.column_families.get_mut(&column_family) {
+                    task.apply(layout, &output);
+                }
+                if let Some(id) = output.iter().max() {
+                    self.next_sst_id = self.next_sst_id.max(id + 1);
+                }
+            }
+            ManifestRecord::Snapshot(snapshot) => *self = snapshot,
+        }
+    }
+}
+
+impl Manifest {
+    /// Create a new empty manifest.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(path.as_ref())
+            .with_context(|| format!("failed to create manifest {:?}", path.as_ref()))?;
+        Ok(Self {
+            file: Mutex::new((file, ManifestSnapshot::default())),
+            path: path.as_ref().to_path_buf(),
+            dropped_torn_record: false,
+        })
+    }
+
+    /// Open an existing manifest and replay all of its records, returns the structure they
+    /// describe. A torn record at the tail is dropped and the file is truncated before it, a
+    /// corrupted record followed by more data fails the recovery.
+    pub fn recover(path: impl AsRef<Path>) -> Result<(Self, ManifestSnapshot)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(path.as_ref())
+            .with_context(|| format!("failed to recover manifest {:?}", path.as_ref()))?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+
+        let mut snapshot = ManifestSnapshot::default();
+        let mut rbuf = &buf[..];
+        while let Some(body) = Self::decode_record(&mut rbuf).with_context(|| {
+            format!(
+                "manifest {:?} is corrupted at offset {}",
+                path.as_ref(),
+                buf.len() - rbuf.len()
+            )
+        })? {
+            snapshot.apply(serde_json::from_slice(body)?);
+        }
+        // a torn record at the tail, the change it describes never took effect
+        let valid_len = buf.len() - rbuf.len();
+        if valid_len < buf.len() {
+            file.set_len(valid_len as u64)?;
+            file.sync_all()?;
+        }
+        Ok((
+            Self {
+                file: Mutex::new((file, snapshot.clone())),
+                path: path.as_ref().to_path_buf(),
+                dropped_torn_record: valid_len < buf.len(),
+            },
+            snapshot,
+        ))
+    }
+
+    /// Append a record and `fsync` the manifest.
+    pub fn add_record(&self, record: &ManifestRecord) -> Result<()> {
+        let buf = Self::encode_record(record)?;
+        let mut guard = self.file.lock();
+        let (file, snapshot) = &mut *guard;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        snapshot.apply(record.clone());
+        Ok(())
+    }
+
+    /// Whether a torn record at the tail was dropped when the manifest was recovered, the change
+    /// it describes may have been partly done.
+    pub fn dropped_torn_record(&self) -> bool {
+        self.dropped_torn_record
+    }
+
+    /// Size of the manifest file in bytes.
+    pub fn size(&self) -> Result<u64> {
+        Ok(self.file.lock().0.metadata()?.len())
+    }
+
+    /// Replace the manifest with a new one that only contains the current structure, with
+    /// `next_sst_id` as the next SST ID. The new manifest is written to a temporary file and
+    /// renamed, so a crash leaves either the old or the new one.
+    pub fn rollover(&self, next_sst_id: usize) -> Result<()> {
+        let mut guard = self.file.lock();
+        let (file, snapshot) = &mut *guard;
+        snapshot.next_sst_id = snapshot.next_sst_id.max(next_sst_id);
+        let tmp_path = self.path.with_extension("tmp");
+        {
+            let mut tmp_file = File::create(&tmp_path)?;
+            tmp_file.write_all(&Self::encode_record(&ManifestRecord::Snapshot(
+                snapshot.clone(),
+            ))?)?;
+            tmp_file.sync_all()?;
+        }
+        std::fs::rename(&tmp_path, &self.path)?;
+        if let Some(dir) = self.path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        *file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+
+    /// Decode the next record, returns `None` if the rest of the buffer is a torn record: it is
+    /// incomplete, or the checksum of the last record doesn't match. Fails if the checksum of a
+    /// record followed by more data doesn't match, or a valid record follows an incomplete one,
+    /// which has a corrupted length.
+    fn decode_record<'a>(buf: &mut &'a [u8]) -> Result<Option<&'a [u8]>> {
+        if buf.len() < SIZEOF_U64 {
+            return Ok(None);
+        }
+        let len = (&buf[..SIZEOF_U64]).get_u64();
+        if ((buf.len() - SIZEOF_U64) as u64) < len.saturating_add(SIZEOF_U32 as u64) {
+            if contains_valid_record(&buf[1..], SIZEOF_U64) {
+                bail!(
+                    "record of {} bytes runs past the end, but a valid record follows",
+                    len
+                );
+            }
+            return Ok(None);
+        }
+        let len = len as usize;
+        let record_len = SIZEOF_U64 + len + SIZEOF_U32;
+        let body = &buf[SIZEOF_U64..(SIZEOF_U64 + len)];
+        if crc32fast::hash(body) != (&buf[(SIZEOF_U64 + len)..]).get_u32() {
+            if buf.len() == record_len {
+                return Ok(None);
+            }
+            bail!(
+                "checksum mismatch of a record followed by {} bytes",
+                buf.len() - record_len
+            );
+        }
+        buf.advance(record_len);
+        Ok(Some(body))
+    }
+
+    /// record: `[len(8B), json, checksum(4B)]`
+    fn encode_record(record: &ManifestRecord) -> Result<Vec<u8>> {
+        let json = serde_json::to_vec(record)?;
+        let mut buf = Vec::with_capacity(SIZEOF_U64 + json.len() + SIZEOF_U32);
+        buf.put_u64(json.len() as u64);
+        buf.put_slice(&json);
+        buf.put_u32(crc32fast::hash(&json));
+        Ok(buf)
+    }
+}
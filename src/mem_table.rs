@@ -1,59 +1,278 @@
-use std::borrow::{Borrow, BorrowMut};
+use std::cmp;
 use std::ops::Bound;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
 use anyhow::Result;
 use bytes::Bytes;
 use crossbeam_skiplist::map::Entry;
-use crossbeam_skiplist::SkipMap;
+use crossbeam_skiplist::{SkipMap, SkipSet};
 use ouroboros::self_referencing;
 
-use crate::iterators::StorageIterator;
+use crate::comparator::Comparator;
+use crate::iterators::{Direction, StorageIterator};
+use crate::key::{self, InternalKey, ValueType, MAX_SEQ};
+use crate::merge_operator::{collapse_merge_operands, MergeOperator};
+use crate::prefix_extractor::{prefix_of, PrefixExtractor};
+use crate::range_tombstone::{FragmentedRangeTombstones, RangeTombstone};
 use crate::table::SsTableBuilder;
 
-/// A basic mem-table based on crossbeam-skiplist
+/// An internal key in the skiplist, ordered by the comparator of the mem-table.
+#[derive(Clone)]
+pub struct MemTableKey {
+    key: InternalKey,
+    comparator: Arc<dyn Comparator>,
+}
+
+impl PartialEq for MemTableKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == cmp::Ordering::Equal
+    }
+}
+
+impl Eq for MemTableKey {}
+
+impl PartialOrd for MemTableKey {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for MemTableKey {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        key::compare(&*self.comparator, self.key.as_bytes(), other.key.as_bytes())
+    }
+}
+
+/// A basic mem-table based on crossbeam-skiplist, every version of a key is kept.
 pub struct MemTable {
-    map: Arc<SkipMap<Bytes, Bytes>>,
+    map: Arc<SkipMap<MemTableKey, Bytes>>,
+    /// Range tombstones, the key is the start key with the sequence number, the value is the end.
+    range_tombstones: SkipMap<InternalKey, Bytes>,
+    id: usize,
+    /// The first WAL holding the entries of the mem-table, it is needed until the mem-table is
+    /// flushed.
+    log_number: usize,
+    /// Total size of the keys and values put into the mem-table, overwritten ones included.
+    approximate_size: AtomicUsize,
+    comparator: Arc<dyn Comparator>,
+    prefix_extractor: Option<Arc<dyn PrefixExtractor>>,
+    /// The prefixes of the keys put into the mem-table, extracted by `prefix_extractor`.
+    prefixes: SkipSet<Bytes>,
 }
 
 impl MemTable {
-    /// Create a new mem-table.
-    pub fn create() -> Self {
+    /// Create a new mem-table whose keys are ordered by `comparator`, its entries are written to
+    /// the WALs from `log_number` on. With a prefix extractor, the prefixes of the keys are kept
+    /// for prefix scans.
+    pub fn create(
+        id: usize,
+        log_number: usize,
+        comparator: Arc<dyn Comparator>,
+        prefix_extractor: Option<Arc<dyn PrefixExtractor>>,
+    ) -> Self {
         Self {
             map: Arc::new(SkipMap::new()),
+            range_tombstones: SkipMap::new(),
+            id,
+            log_number,
+            approximate_size: AtomicUsize::new(0),
+            comparator,
+            prefix_extractor,
+            prefixes: SkipSet::new(),
         }
     }
 
-    /// Get a value by key.
-    pub fn get(&self, key: &[u8]) -> Option<Bytes> {
-        self.map.get(key).map(|entry| entry.value().clone())
+    fn map_key(&self, key: InternalKey) -> MemTableKey {
+        MemTableKey {
+            key,
+            comparator: self.comparator.clone(),
+        }
+    }
+
+    /// Get the latest version of `key` whose sequence number is not larger than `read_seq`, with
+    /// its sequence number. Range tombstones are not checked.
+    pub fn get(&self, key: &[u8], read_seq: u64) -> Option<(u64, ValueType, Bytes)> {
+        self.map
+            .range(self.map_key(InternalKey::seek(key, read_seq))..)
+            .next()
+            .filter(|entry| entry.key().key.user_key() == key)
+            .map(|entry| {
+                (
+                    entry.key().key.seq(),
+                    entry.key().key.value_type(),
+                    entry.value().clone(),
+                )
+            })
+    }
+
+    /// Check if the mem-table may have a key with `prefix`, a prefix extracted by the prefix
+    /// extractor of the mem-table.
+    pub fn may_contain_prefix(&self, prefix: &[u8]) -> bool {
+        self.prefix_extractor.is_none() || self.prefixes.contains(prefix)
+    }
+
+    /// The range tombstones in the mem-table.
+    pub fn range_tombstones(&self) -> Vec<RangeTombstone> {
+        self.range_tombstones
+            .iter()
+            .map(|entry| {
+                RangeTombstone::new(entry.key().user_key(), entry.value(), entry.key().seq())
+            })
+            .collect()
     }
 
-    /// Put a key-value pair into the mem-table.
-    pub fn put(&self, key: &[u8], value: &[u8]) {
-        self.map.insert(Bytes::copy_from_slice(key), Bytes::copy_from_slice(value));
+    /// The largest sequence number of the range tombstones covering `key` that is not larger
+    /// than `read_seq`, 0 if there is none.
+    pub fn max_covering_seq(&self, key: &[u8], read_seq: u64) -> u64 {
+        self.range_tombstones
+            .iter()
+            .filter(|entry| {
+                entry.key().seq() <= read_seq
+                    && self.comparator.compare(entry.key().user_key(), key).is_le()
+                    && self.comparator.compare(key, entry.value()).is_lt()
+            })
+            .map(|entry| entry.key().seq())
+            .max()
+            .unwrap_or(0)
     }
 
-    /// Get an iterator over a range of keys.
+    /// Put a version of a key into the mem-table, the caller writes it to the WAL first.
+    pub fn put(&self, key: InternalKey, value: Bytes) {
+        self.approximate_size
+            .fetch_add(key.as_bytes().len() + value.len(), Ordering::Relaxed);
+        if key.value_type() == ValueType::RangeDelete {
+            self.range_tombstones.insert(key, value);
+            return;
+        }
+        if let Some(prefix) = self
+            .prefix_extractor
+            .as_deref()
+            .and_then(|extractor| prefix_of(extractor, key.user_key()))
+        {
+            if !self.prefixes.contains(prefix) {
+                self.prefixes.insert(Bytes::copy_from_slice(prefix));
+            }
+        }
+        self.map.insert(self.map_key(key), value);
+    }
+
+    /// Put versions of keys into the mem-table.
+    pub fn put_batch(&self, entries: &[(InternalKey, Bytes)]) {
+        for (key, value) in entries {
+            self.put(key.clone(), value.clone());
+        }
+    }
+
+    /// Get an iterator over every version of a range of keys.
     pub fn scan(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> MemTableIterator {
-        let (lower, upper) = (map_bound(lower), map_bound(upper));
-        let mut iter: MemTableIterator = MemTableIteratorBuilder {
-            map: self.map.clone(),
-            iter_builder: |map| map.range((lower, upper)),
-            item: (Bytes::from_static(&[]), Bytes::copy_from_slice(&[])),
-        }.build();
-        let entry = iter.with_iter_mut(|iter: &mut SkipMapRangeIter| MemTableIterator::entry_to_item(iter.next()));
-        iter.with_mut(|x| *x.item = entry);
-        iter
+        let lower = match lower {
+            Bound::Included(key) => Bound::Included(self.map_key(InternalKey::seek(key, MAX_SEQ))),
+            Bound::Excluded(key) => {
+                Bound::Excluded(self.map_key(InternalKey::after_all_versions(key)))
+            }
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let upper = match upper {
+            Bound::Included(key) => {
+                Bound::Included(self.map_key(InternalKey::after_all_versions(key)))
+            }
+            Bound::Excluded(key) => Bound::Excluded(self.map_key(InternalKey::seek(key, MAX_SEQ))),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        MemTableIterator::create(
+            self.map.clone(),
+            (lower.clone(), upper.clone()),
+            lower,
+            upper,
+            self.comparator.clone(),
+            Direction::Forward,
+        )
     }
 
-    /// Flush the mem-table to SSTable.
-    pub fn flush(&self, builder: &mut SsTableBuilder) -> Result<()> {
-        for entry in self.map.iter() {
-            builder.add(&entry.key()[..], &entry.value()[..]);
+    /// Flush the mem-table to SSTable. With a merge operator, the merge operands of a key are
+    /// combined with the versions below them that no snapshot in `live_snapshots` sees apart.
+    pub fn flush(
+        &self,
+        builder: &mut SsTableBuilder,
+        merge_operator: Option<&dyn MergeOperator>,
+        live_snapshots: &[u64],
+    ) -> Result<()> {
+        let range_tombstones = self.range_tombstones();
+        match merge_operator {
+            None => {
+                for entry in self.map.iter() {
+                    builder.add(entry.key().key.as_bytes(), &entry.value()[..]);
+                }
+            }
+            Some(operator) => {
+                let fragments =
+                    FragmentedRangeTombstones::new(self.comparator.clone(), &range_tombstones);
+                let mut entries = self.map.iter().peekable();
+                while let Some(entry) = entries.next() {
+                    let key = entry.key().key.user_key();
+                    let mut versions = vec![(
+                        entry.key().key.seq(),
+                        entry.key().key.value_type(),
+                        entry.value().clone(),
+                    )];
+                    while let Some(entry) =
+                        entries.next_if(|entry| entry.key().key.user_key() == key)
+                    {
+                        versions.push((
+                            entry.key().key.seq(),
+                            entry.key().key.value_type(),
+                            entry.value().clone(),
+                        ));
+                    }
+                    let versions = collapse_merge_operands(
+                        operator,
+                        key,
+                        versions,
+                        live_snapshots,
+                        fragments.covering_seqs(key),
+                        false,
+                    )?;
+                    for (seq, value_type, value) in versions {
+                        builder.add(InternalKey::new(key, seq, value_type).as_bytes(), &value);
+                    }
+                }
+            }
+        }
+        for tombstone in range_tombstones {
+            builder.add_range_tombstone(tombstone);
         }
         Ok(())
     }
+
+    /// The id of the mem-table, it is also the id of its WAL and of the SST it is flushed to.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn log_number(&self) -> usize {
+        self.log_number
+    }
+
+    /// The largest sequence number in the mem-table.
+    pub fn max_seq(&self) -> u64 {
+        self.map
+            .iter()
+            .map(|entry| entry.key().key.seq())
+            .chain(self.range_tombstones.iter().map(|entry| entry.key().seq()))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Approximate size of the mem-table in bytes.
+    pub fn approximate_size(&self) -> usize {
+        self.approximate_size.load(Ordering::Relaxed)
+    }
+
+    /// Check if there is no key-value pair or range tombstone in the mem-table.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty() && self.range_tombstones.is_empty()
+    }
 }
 
 pub fn map_bound(bound: Bound<&[u8]>) -> Bound<Bytes> {
@@ -64,43 +283,196 @@ pub fn map_bound(bound: Bound<&[u8]>) -> Bound<Bytes> {
     }
 }
 
-type SkipMapRangeIter<'a> =
-crossbeam_skiplist::map::Range<'a, Bytes, (Bound<Bytes>, Bound<Bytes>), Bytes, Bytes>;
+type SkipMapRangeIter<'a> = crossbeam_skiplist::map::Range<
+    'a,
+    MemTableKey,
+    (Bound<MemTableKey>, Bound<MemTableKey>),
+    MemTableKey,
+    Bytes,
+>;
 
-/// An iterator over a range of `SkipMap`.
+/// An iterator over a range of `SkipMap`. It walks a sub-range of the scanned range in one
+/// direction, seeking or reversing the direction starts a new sub-range.
 #[self_referencing]
 pub struct MemTableIterator {
-    map: Arc<SkipMap<Bytes, Bytes>>,
+    map: Arc<SkipMap<MemTableKey, Bytes>>,
     #[borrows(map)]
     #[not_covariant]
     iter: SkipMapRangeIter<'this>,
-    item: (Bytes, Bytes),
+    item: Option<(MemTableKey, Bytes)>,
+    /// Bounds of the scanned range.
+    lower: Bound<MemTableKey>,
+    upper: Bound<MemTableKey>,
+    comparator: Arc<dyn Comparator>,
+    direction: Direction,
 }
 
 impl MemTableIterator {
-    fn entry_to_item(entry: Option<Entry<'_, Bytes, Bytes>>) -> (Bytes, Bytes) {
+    /// Create an iterator walking `range` in `direction`, positioned at its first entry in the
+    /// direction.
+    fn create(
+        map: Arc<SkipMap<MemTableKey, Bytes>>,
+        range: (Bound<MemTableKey>, Bound<MemTableKey>),
+        lower: Bound<MemTableKey>,
+        upper: Bound<MemTableKey>,
+        comparator: Arc<dyn Comparator>,
+        direction: Direction,
+    ) -> Self {
+        let mut iter: MemTableIterator = MemTableIteratorBuilder {
+            map,
+            iter_builder: |map| map.range(range),
+            item: None,
+            lower,
+            upper,
+            comparator,
+            direction,
+        }
+        .build();
+        iter.move_in_direction();
+        iter
+    }
+
+    /// Walk `range` of the scanned range in `direction` from now on.
+    fn reposition(
+        &mut self,
+        range: (Bound<MemTableKey>, Bound<MemTableKey>),
+        direction: Direction,
+    ) {
+        let (lower, upper) = (self.borrow_lower().clone(), self.borrow_upper().clone());
+        *self = Self::create(
+            self.borrow_map().clone(),
+            range,
+            lower,
+            upper,
+            self.borrow_comparator().clone(),
+            direction,
+        );
+    }
+
+    fn map_key(&self, key: InternalKey) -> MemTableKey {
+        MemTableKey {
+            key,
+            comparator: self.borrow_comparator().clone(),
+        }
+    }
+
+    fn move_in_direction(&mut self) {
+        let entry = self.with_mut(|x| {
+            let entry = match x.direction {
+                Direction::Forward => x.iter.next(),
+                Direction::Backward => x.iter.next_back(),
+            };
+            MemTableIterator::entry_to_item(entry)
+        });
+        self.with_mut(|x| *x.item = entry);
+    }
+
+    fn entry_to_item(entry: Option<Entry<'_, MemTableKey, Bytes>>) -> Option<(MemTableKey, Bytes)> {
         entry.map(|e| (e.key().clone(), e.value().clone()))
-            .unwrap_or_else(|| (Bytes::from_static(&[]), Bytes::from_static(&[])))
+    }
+
+    fn item(&self) -> &(MemTableKey, Bytes) {
+        self.borrow_item().as_ref().unwrap()
     }
 }
 
 impl StorageIterator for MemTableIterator {
 
     fn value(&self) -> &[u8] {
-        &self.borrow_item().1[..]
+        &self.item().1[..]
     }
 
     fn key(&self) -> &[u8] {
-        &self.borrow_item().0[..]
+        self.item().0.key.user_key()
+    }
+
+    fn seq(&self) -> u64 {
+        self.item().0.key.seq()
+    }
+
+    fn value_type(&self) -> ValueType {
+        self.item().0.key.value_type()
     }
 
     fn is_valid(&self) -> bool {
-        !self.borrow_item().0.is_empty()
+        self.borrow_item().is_some()
     }
 
     fn next(&mut self) -> Result<()> {
-        let entry = self.with_iter_mut(|iter: &mut SkipMapRangeIter| MemTableIterator::entry_to_item(iter.next()));
-        self.with_mut(|x| *x.item = entry);
+        if *self.borrow_direction() == Direction::Backward {
+            let range = (
+                Bound::Excluded(self.item().0.clone()),
+                self.borrow_upper().clone(),
+            );
+            self.reposition(range, Direction::Forward);
+        } else {
+            self.move_in_direction();
+        }
         Ok(())
     }
+
+    fn prev(&mut self) -> Result<()> {
+        if *self.borrow_direction() == Direction::Forward {
+            let range = (
+                self.borrow_lower().clone(),
+                Bound::Excluded(self.item().0.clone()),
+            );
+            self.reposition(range, Direction::Backward);
+        } else {
+            self.move_in_direction();
+        }
+        Ok(())
+    }
+
+    fn seek_to_first(&mut self) -> Result<()> {
+        let range = (self.borrow_lower().clone(), self.borrow_upper().clone());
+        self.reposition(range, Direction::Forward);
+        Ok(())
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        let range = (self.borrow_lower().clone(), self.borrow_upper().clone());
+        self.reposition(range, Direction::Backward);
+        Ok(())
+    }
+
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        let range = (
+            max_lower_bound(
+                self.borrow_lower(),
+                self.map_key(InternalKey::seek(key, MAX_SEQ)),
+            ),
+            self.borrow_upper().clone(),
+        );
+        self.reposition(range, Direction::Forward);
+        Ok(())
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()> {
+        let range = (
+            self.borrow_lower().clone(),
+            min_upper_bound(
+                self.borrow_upper(),
+                self.map_key(InternalKey::after_all_versions(key)),
+            ),
+        );
+        self.reposition(range, Direction::Backward);
+        Ok(())
+    }
+}
+
+/// The tighter one of `lower` and the inclusive lower bound `key`.
+fn max_lower_bound(lower: &Bound<MemTableKey>, key: MemTableKey) -> Bound<MemTableKey> {
+    match lower {
+        Bound::Included(bound) | Bound::Excluded(bound) if *bound >= key => lower.clone(),
+        _ => Bound::Included(key),
+    }
+}
+
+/// The tighter one of `upper` and the inclusive upper bound `key`.
+fn min_upper_bound(upper: &Bound<MemTableKey>, key: MemTableKey) -> Bound<MemTableKey> {
+    match upper {
+        Bound::Included(bound) | Bound::Excluded(bound) if *bound <= key => upper.clone(),
+        _ => Bound::Included(key),
+    }
 }
@@ -0,0 +1,127 @@
+use std::fmt;
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+
+use crate::key::ValueType;
+
+/// Combines the operands written by `LsmStorage::merge` with the value of a key, so a
+/// read-modify-write such as increasing a counter doesn't need to read the key. Operands are
+/// resolved lazily by reads, and combined by flush and compaction so they don't pile up.
+pub trait MergeOperator: Send + Sync {
+    /// The name of the operator.
+    fn name(&self) -> &str;
+
+    /// Apply `operands`, from the earliest to the latest, to `existing_value`, which is `None` if
+    /// the key doesn't exist or is deleted.
+    fn full_merge(
+        &self,
+        key: &[u8],
+        existing_value: Option<&[u8]>,
+        operands: &[&[u8]],
+    ) -> Result<Bytes>;
+
+    /// Combine `operands`, from the earliest to the latest, into one operand with the same
+    /// effect, without knowing the existing value. `None` keeps them apart.
+    fn partial_merge(&self, _key: &[u8], _operands: &[&[u8]]) -> Option<Bytes> {
+        None
+    }
+}
+
+impl fmt::Debug for dyn MergeOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MergeOperator({})", self.name())
+    }
+}
+
+/// Apply `operands`, from the earliest to the latest, to `existing_value`, fails if no merge
+/// operator is configured.
+pub(crate) fn full_merge<T: AsRef<[u8]>>(
+    operator: Option<&dyn MergeOperator>,
+    key: &[u8],
+    existing_value: Option<&[u8]>,
+    operands: &[T],
+) -> Result<Bytes> {
+    let Some(operator) = operator else {
+        bail!("merge operand found but no merge operator is configured");
+    };
+    let operands: Vec<&[u8]> = operands.iter().map(|operand| operand.as_ref()).collect();
+    operator.full_merge(key, existing_value, &operands)
+}
+
+/// Combine the merge operands in `versions`, the versions of `key` from the latest to the
+/// earliest, with the versions below them. Versions are only combined when no snapshot in
+/// `live_snapshots` sees one without the other. An operand whose chain reaches a put, a delete
+/// or a range tombstone in `covering_seqs` becomes a put. If `complete`, there is no older
+/// version of the key elsewhere, so a chain reaching the earliest version becomes a put too.
+/// Other operands are combined into one with `partial_merge` if possible, including the ones
+/// reaching a value that expires, which is resolved at read time.
+pub(crate) fn collapse_merge_operands(
+    operator: &dyn MergeOperator,
+    key: &[u8],
+    versions: Vec<(u64, ValueType, Bytes)>,
+    live_snapshots: &[u64],
+    covering_seqs: &[u64],
+    complete: bool,
+) -> Result<Vec<(u64, ValueType, Bytes)>> {
+    // versions seen by the same snapshots have the same stripe
+    let stripe = |seq: u64| live_snapshots.partition_point(|&snapshot| snapshot < seq);
+    let mut output = Vec::with_capacity(versions.len());
+    let mut idx = 0;
+    while idx < versions.len() {
+        let (seq, value_type, value) = &versions[idx];
+        if *value_type != ValueType::Merge {
+            output.push(versions[idx].clone());
+            idx += 1;
+            continue;
+        }
+        // operands from the latest, and the existing value once the chain ends
+        let mut operands = vec![&value[..]];
+        let mut existing_value: Option<Option<&[u8]>> = None;
+        let mut end = idx + 1;
+        while end < versions.len() && stripe(versions[end].0) == stripe(*seq) {
+            let (older_seq, older_type, older_value) = &versions[end];
+            if covering_seqs
+                .iter()
+                .any(|&tombstone_seq| *older_seq < tombstone_seq && tombstone_seq <= *seq)
+            {
+                existing_value = Some(None);
+                break;
+            }
+            if *older_type == ValueType::PutWithTtl {
+                break;
+            }
+            end += 1;
+            match older_type {
+                ValueType::Merge => operands.push(&older_value[..]),
+                ValueType::Put => {
+                    existing_value = Some(Some(&older_value[..]));
+                    break;
+                }
+                _ => {
+                    existing_value = Some(None);
+                    break;
+                }
+            }
+        }
+        if existing_value.is_none() && end == versions.len() && complete {
+            existing_value = Some(None);
+        }
+        operands.reverse();
+        match existing_value {
+            Some(existing_value) => {
+                let value = operator.full_merge(key, existing_value, &operands)?;
+                output.push((*seq, ValueType::Put, value));
+            }
+            None => match (operands.len() > 1)
+                .then(|| operator.partial_merge(key, &operands))
+                .flatten()
+            {
+                Some(operand) => output.push((*seq, ValueType::Merge, operand)),
+                None => output.extend_from_slice(&versions[idx..end]),
+            },
+        }
+        idx = end;
+    }
+    Ok(output)
+}
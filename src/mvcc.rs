@@ -0,0 +1,254 @@
+mod lock_manager;
+mod txn;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::Bytes;
+use parking_lot::Mutex;
+
+use crate::comparator::Comparator;
+use crate::key::{InternalKey, ValueType};
+use crate::lsm_iterator::{FusedIterator, LsmIterator};
+use crate::lsm_storage::{ColumnFamily, LsmStorageCore};
+
+pub(crate) use lock_manager::LockManager;
+pub use txn::{Transaction, TransactionError, TxnIterator};
+
+/// Sequence numbers of the writes and of the live snapshots.
+pub(crate) struct Mvcc {
+    /// The sequence number of the latest write visible to readers.
+    last_seq: AtomicU64,
+    state: Mutex<MvccState>,
+}
+
+#[derive(Default)]
+struct MvccState {
+    /// Sequence numbers of the live snapshots, with the number of snapshots at each of them.
+    snapshots: BTreeMap<u64, usize>,
+    /// Keys written at each sequence number newer than the oldest live snapshot, transactions
+    /// check their reads against them at commit.
+    writes: BTreeMap<u64, WriteSet>,
+}
+
+/// The keys written at a sequence number.
+struct WriteSet {
+    keys: HashSet<Bytes>,
+    /// Ranges deleted by range tombstones, `[start, end)`.
+    ranges: Vec<(Bytes, Bytes)>,
+}
+
+impl WriteSet {
+    /// Whether `key` is written, the ranges are in the order of `comparator`.
+    fn contains(&self, comparator: &dyn Comparator, key: &[u8]) -> bool {
+        self.keys.contains(key)
+            || self.ranges.iter().any(|(start, end)| {
+                comparator.compare(start, key).is_le() && comparator.compare(key, end).is_lt()
+            })
+    }
+
+    /// Whether any key in the range `(lower, upper)` may be written, in the order of
+    /// `comparator`.
+    fn overlaps(
+        &self,
+        comparator: &dyn Comparator,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> bool {
+        let after_lower = |key: &[u8]| match lower {
+            Bound::Included(lower) => comparator.compare(key, lower).is_ge(),
+            Bound::Excluded(lower) => comparator.compare(key, lower).is_gt(),
+            Bound::Unbounded => true,
+        };
+        let before_upper = |key: &[u8]| match upper {
+            Bound::Included(upper) => comparator.compare(key, upper).is_le(),
+            Bound::Excluded(upper) => comparator.compare(key, upper).is_lt(),
+            Bound::Unbounded => true,
+        };
+        self.keys
+            .iter()
+            .any(|key| after_lower(key) && before_upper(key))
+            || self.ranges.iter().any(|(start, end)| {
+                let end_after_lower = match lower {
+                    Bound::Included(lower) | Bound::Excluded(lower) => {
+                        comparator.compare(end, lower).is_gt()
+                    }
+                    Bound::Unbounded => true,
+                };
+                end_after_lower && before_upper(start)
+            })
+    }
+}
+
+/// What a transaction read, it is validated at commit.
+#[derive(Default)]
+pub(crate) struct ReadSet {
+    /// Keys, with the sequence number each one is read at.
+    pub(crate) keys: HashMap<Bytes, u64>,
+    /// Scanned ranges, with the sequence number each one is read at. A key written into one
+    /// later would change what the scan returns.
+    pub(crate) ranges: Vec<(Bound<Bytes>, Bound<Bytes>, u64)>,
+}
+
+impl Mvcc {
+    pub(crate) fn new(last_seq: u64) -> Self {
+        Self {
+            last_seq: AtomicU64::new(last_seq),
+            state: Mutex::new(MvccState::default()),
+        }
+    }
+
+    pub(crate) fn last_seq(&self) -> u64 {
+        self.last_seq.load(Ordering::SeqCst)
+    }
+
+    /// Make the writes up to `seq` visible to readers.
+    pub(crate) fn publish(&self, seq: u64) {
+        self.last_seq.store(seq, Ordering::SeqCst);
+    }
+
+    /// Record the keys written at `seq`, must be called after `seq` is published. Nothing is
+    /// recorded without live snapshots, a snapshot taken later doesn't care about the write.
+    pub(crate) fn record_write(&self, seq: u64, entries: &[(InternalKey, Bytes)]) {
+        let mut state = self.state.lock();
+        if state.snapshots.is_empty() {
+            return;
+        }
+        let mut write_set = WriteSet {
+            keys: HashSet::new(),
+            ranges: Vec::new(),
+        };
+        for (key, value) in entries {
+            let user_key = Bytes::copy_from_slice(key.user_key());
+            if key.value_type() == ValueType::RangeDelete {
+                write_set.ranges.push((user_key, value.clone()));
+            } else {
+                write_set.keys.insert(user_key);
+            }
+        }
+        state.writes.insert(seq, write_set);
+    }
+
+    /// Whether any key or range in `reads` is written after the sequence number it is read at,
+    /// keys are ordered by `comparator`. A snapshot not newer than any of the reads must be
+    /// alive, so the writes after them are all recorded.
+    pub(crate) fn is_modified_after(&self, reads: &ReadSet, comparator: &dyn Comparator) -> bool {
+        let read_seqs = reads
+            .keys
+            .values()
+            .chain(reads.ranges.iter().map(|(_, _, read_seq)| read_seq));
+        let Some(&oldest_read) = read_seqs.min() else {
+            return false;
+        };
+        let state = self.state.lock();
+        state
+            .writes
+            .range(oldest_read + 1..)
+            .any(|(&seq, written)| {
+                reads
+                    .keys
+                    .iter()
+                    .any(|(key, &read_seq)| seq > read_seq && written.contains(comparator, key))
+                    || reads.ranges.iter().any(|(lower, upper, read_seq)| {
+                        seq > *read_seq
+                            && written.overlaps(
+                                comparator,
+                                lower.as_ref().map(|key| &key[..]),
+                                upper.as_ref().map(|key| &key[..]),
+                            )
+                    })
+            })
+    }
+
+    fn acquire_snapshot(&self) -> u64 {
+        let mut state = self.state.lock();
+        let seq = self.last_seq();
+        *state.snapshots.entry(seq).or_default() += 1;
+        seq
+    }
+
+    fn release_snapshot(&self, seq: u64) {
+        let mut state = self.state.lock();
+        let count = state.snapshots.get_mut(&seq).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            state.snapshots.remove(&seq);
+        }
+        // no snapshot needs the writes at or before the oldest one
+        match state.snapshots.keys().next().copied() {
+            Some(oldest) => state.writes = state.writes.split_off(&(oldest + 1)),
+            None => state.writes.clear(),
+        }
+    }
+
+    /// Sequence numbers of the live snapshots, in ascending order. A snapshot taken later sees
+    /// at least every write published now.
+    pub(crate) fn live_snapshots(&self) -> Vec<u64> {
+        self.state.lock().snapshots.keys().copied().collect()
+    }
+}
+
+/// A consistent read-only view of the storage, it sees the writes published before it was taken
+/// and none after. Compaction keeps the versions it sees until it is dropped.
+pub struct Snapshot {
+    core: Arc<LsmStorageCore>,
+    seq: u64,
+}
+
+impl Snapshot {
+    pub(crate) fn new(core: Arc<LsmStorageCore>) -> Self {
+        let seq = core.shared.mvcc.acquire_snapshot();
+        Self { core, seq }
+    }
+
+    /// The sequence number of the latest write the snapshot sees.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        self.core.get_at(key, self.seq)
+    }
+
+    pub fn scan(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        self.core.scan_at(lower, upper, self.seq)
+    }
+
+    /// Scan a range of keys of the snapshot backward, the iterator is positioned at the last key.
+    pub fn scan_rev(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        self.core.scan_rev_at(lower, upper, self.seq)
+    }
+
+    /// Get a key of a column family, column families share the sequence numbers, so the
+    /// snapshot is consistent across them.
+    pub fn get_cf(&self, column_family: &ColumnFamily, key: &[u8]) -> Result<Option<Bytes>> {
+        column_family.core.get_at(key, self.seq)
+    }
+
+    /// Scan a range of keys of a column family.
+    pub fn scan_cf(
+        &self,
+        column_family: &ColumnFamily,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        column_family.core.scan_at(lower, upper, self.seq)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.core.shared.mvcc.release_snapshot(self.seq);
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use bytes::Bytes;
+use parking_lot::{Condvar, Mutex};
+
+use super::TransactionError;
+
+/// Exclusive per-key locks of pessimistic transactions, held until the transaction ends.
+pub(crate) struct LockManager {
+    next_txn_id: AtomicU64,
+    state: Mutex<LockState>,
+    /// Notified when locks are released.
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct LockState {
+    /// The transaction holding the lock of each key.
+    holders: HashMap<Bytes, u64>,
+    /// The wait-for graph, a waiting transaction waits for the holder of the lock it wants.
+    waits_for: HashMap<u64, u64>,
+}
+
+impl LockState {
+    /// Whether the waits starting from `txn_id` lead back to it.
+    fn is_deadlocked(&self, txn_id: u64) -> bool {
+        let mut visited = HashSet::new();
+        let mut current = txn_id;
+        while let Some(&holder) = self.waits_for.get(&current) {
+            if holder == txn_id {
+                return true;
+            }
+            if !visited.insert(holder) {
+                return false;
+            }
+            current = holder;
+        }
+        false
+    }
+}
+
+impl LockManager {
+    pub(crate) fn new() -> Self {
+        Self {
+            next_txn_id: AtomicU64::new(1),
+            state: Mutex::new(LockState::default()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Allocate the ID of a new transaction.
+    pub(crate) fn next_txn_id(&self) -> u64 {
+        self.next_txn_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Lock `key` for `txn_id`, waiting at most `timeout` for the holder to release it. If the
+    /// wait would close a cycle in the wait-for graph, `txn_id` is chosen as the victim and
+    /// fails with `TransactionError::Deadlock` without waiting.
+    pub(crate) fn lock(&self, txn_id: u64, key: &[u8], timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock();
+        loop {
+            let holder = match state.holders.get(key) {
+                None => {
+                    state.holders.insert(Bytes::copy_from_slice(key), txn_id);
+                    break;
+                }
+                Some(&holder) if holder == txn_id => break,
+                Some(&holder) => holder,
+            };
+            // the holder may change while waiting, the edge is updated on every wake up
+            state.waits_for.insert(txn_id, holder);
+            if state.is_deadlocked(txn_id) {
+                state.waits_for.remove(&txn_id);
+                return Err(TransactionError::Deadlock.into());
+            }
+            if self.released.wait_until(&mut state, deadline).timed_out()
+                && state.holders.contains_key(key)
+            {
+                state.waits_for.remove(&txn_id);
+                return Err(TransactionError::LockTimeout.into());
+            }
+        }
+        state.waits_for.remove(&txn_id);
+        Ok(())
+    }
+
+    /// Release the locks of `keys` held by `txn_id`.
+    pub(crate) fn unlock_all(&self, txn_id: u64, keys: &HashSet<Bytes>) {
+        if keys.is_empty() {
+            return;
+        }
+        let mut state = self.state.lock();
+        for key in keys {
+            if state.holders.get(key) == Some(&txn_id) {
+                state.holders.remove(key);
+            }
+        }
+        self.released.notify_all();
+    }
+}
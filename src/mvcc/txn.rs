@@ -0,0 +1,292 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::Bytes;
+use parking_lot::Mutex;
+
+use super::{ReadSet, Snapshot};
+use crate::iterators::StorageIterator;
+use crate::lsm_iterator::{FusedIterator, LsmIterator};
+use crate::lsm_storage::LsmStorageCore;
+
+/// Why a transaction failed to commit.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TransactionError {
+    /// A key read by the transaction was written by others after the transaction started, the
+    /// transaction can be retried.
+    Conflict,
+    /// A pessimistic transaction waited too long for the lock of a key.
+    LockTimeout,
+    /// Waiting for the lock of a key would deadlock, the transaction is chosen as the victim and
+    /// should be rolled back.
+    Deadlock,
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransactionError::Conflict => write!(f, "transaction conflict"),
+            TransactionError::LockTimeout => write!(f, "lock wait timeout"),
+            TransactionError::Deadlock => write!(f, "deadlock detected"),
+        }
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
+/// A transaction. It reads from the snapshot taken when it begins and its own writes, which are
+/// buffered until `commit`.
+///
+/// An optimistic transaction fails to commit with `TransactionError::Conflict` if any key it
+/// read, or any key in a range it scanned, was written after it began, so committed
+/// transactions are serializable.
+///
+/// A pessimistic transaction locks the keys it writes and the keys read by `get_for_update`
+/// until it ends, so other transactions wait for them instead of conflicting at commit.
+pub struct Transaction {
+    core: Arc<LsmStorageCore>,
+    id: u64,
+    pessimistic: bool,
+    snapshot: Snapshot,
+    /// Buffered writes, `None` deletes the key.
+    writes: BTreeMap<Bytes, Option<Bytes>>,
+    /// Keys and ranges read by the transaction, they are validated at commit.
+    read_set: Mutex<ReadSet>,
+    /// Keys locked by a pessimistic transaction.
+    locked_keys: Mutex<HashSet<Bytes>>,
+}
+
+impl Transaction {
+    pub(crate) fn new(core: Arc<LsmStorageCore>, pessimistic: bool) -> Self {
+        Self {
+            id: core.shared.lock_manager.next_txn_id(),
+            pessimistic,
+            snapshot: Snapshot::new(core.clone()),
+            core,
+            writes: BTreeMap::new(),
+            read_set: Mutex::new(ReadSet::default()),
+            locked_keys: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// The sequence number of the snapshot the transaction reads from.
+    pub fn read_seq(&self) -> u64 {
+        self.snapshot.seq()
+    }
+
+    /// Get a key from the snapshot, the key is validated at commit only in an optimistic
+    /// transaction.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        if !self.pessimistic {
+            self.read_set
+                .lock()
+                .keys
+                .entry(Bytes::copy_from_slice(key))
+                .or_insert(self.read_seq());
+        }
+        self.snapshot.get(key)
+    }
+
+    /// Get a key the transaction is going to write. A pessimistic transaction locks the key and
+    /// reads its latest value instead of the one in the snapshot, it fails with
+    /// `TransactionError::LockTimeout` or `TransactionError::Deadlock` if the lock can't be
+    /// acquired.
+    pub fn get_for_update(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        if !self.pessimistic {
+            return self.get(key);
+        }
+        self.lock(key)?;
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        let (value, seq) = self.core.get_latest(key)?;
+        // others may still write it without a transaction
+        self.read_set
+            .lock()
+            .keys
+            .entry(Bytes::copy_from_slice(key))
+            .or_insert(seq);
+        Ok(value)
+    }
+
+    /// Scan a range of keys, the whole range is validated at commit only in an optimistic
+    /// transaction, a key written into it by others fails the commit.
+    pub fn scan(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<TxnIterator<'_>> {
+        if !self.pessimistic {
+            let range = (
+                lower.map(Bytes::copy_from_slice),
+                upper.map(Bytes::copy_from_slice),
+                self.read_seq(),
+            );
+            self.read_set.lock().ranges.push(range);
+        }
+        // the writes are buffered in bytewise order, the iterator walks them in the order of the
+        // comparator
+        let comparator = &*self.core.options.comparator;
+        let in_bounds = |key: &[u8]| {
+            let after_lower = match lower {
+                Bound::Included(lower) => comparator.compare(key, lower).is_ge(),
+                Bound::Excluded(lower) => comparator.compare(key, lower).is_gt(),
+                Bound::Unbounded => true,
+            };
+            let before_upper = match upper {
+                Bound::Included(upper) => comparator.compare(key, upper).is_le(),
+                Bound::Excluded(upper) => comparator.compare(key, upper).is_lt(),
+                Bound::Unbounded => true,
+            };
+            after_lower && before_upper
+        };
+        let mut local: Vec<(Bytes, Option<Bytes>)> = self
+            .writes
+            .iter()
+            .filter(|(key, _)| in_bounds(key))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        local.sort_by(|(a, _), (b, _)| comparator.compare(a, b));
+        TxnIterator::create(self, local, self.snapshot.scan(lower, upper)?)
+    }
+
+    /// Buffer a write, a pessimistic transaction locks the key first.
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        assert!(!value.is_empty(), "value cannot be empty");
+        assert!(!key.is_empty(), "key cannot be empty");
+        if self.pessimistic {
+            self.lock(key)?;
+        }
+        self.writes.insert(
+            Bytes::copy_from_slice(key),
+            Some(Bytes::copy_from_slice(value)),
+        );
+        Ok(())
+    }
+
+    /// Buffer a delete, a pessimistic transaction locks the key first.
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        assert!(!key.is_empty(), "key cannot be empty");
+        if self.pessimistic {
+            self.lock(key)?;
+        }
+        self.writes.insert(Bytes::copy_from_slice(key), None);
+        Ok(())
+    }
+
+    /// Apply the writes atomically, or fail with `TransactionError::Conflict` and apply nothing.
+    /// The locks are released after the writes are applied.
+    pub fn commit(mut self) -> Result<()> {
+        let read_set = std::mem::take(self.read_set.get_mut());
+        self.core.commit_transaction(&read_set, &self.writes)
+    }
+
+    /// Discard the writes and release the locks.
+    pub fn rollback(self) {}
+
+    fn lock(&self, key: &[u8]) -> Result<()> {
+        let mut locked_keys = self.locked_keys.lock();
+        if !locked_keys.contains(key) {
+            self.core
+                .shared
+                .lock_manager
+                .lock(self.id, key, self.core.options.lock_timeout)?;
+            locked_keys.insert(Bytes::copy_from_slice(key));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        self.core
+            .shared
+            .lock_manager
+            .unlock_all(self.id, self.locked_keys.get_mut());
+    }
+}
+
+/// Iterates over the writes of a transaction merged with its snapshot.
+pub struct TxnIterator<'a> {
+    txn: &'a Transaction,
+    /// Writes of the transaction in the range, in key order.
+    local: Vec<(Bytes, Option<Bytes>)>,
+    local_idx: usize,
+    iter: FusedIterator<LsmIterator>,
+    current: Option<(Bytes, Bytes)>,
+}
+
+impl<'a> TxnIterator<'a> {
+    fn create(
+        txn: &'a Transaction,
+        local: Vec<(Bytes, Option<Bytes>)>,
+        iter: FusedIterator<LsmIterator>,
+    ) -> Result<Self> {
+        let mut iter = Self {
+            txn,
+            local,
+            local_idx: 0,
+            iter,
+            current: None,
+        };
+        iter.move_to_next()?;
+        Ok(iter)
+    }
+
+    /// Move to the next key that is not deleted by the transaction, a write of the transaction
+    /// hides the version of the key in the snapshot.
+    fn move_to_next(&mut self) -> Result<()> {
+        loop {
+            let from_local = match (self.local.get(self.local_idx), self.iter.is_valid()) {
+                (None, false) => {
+                    self.current = None;
+                    return Ok(());
+                }
+                (Some(_), false) => true,
+                (None, true) => false,
+                (Some((key, _)), true) => self
+                    .txn
+                    .core
+                    .options
+                    .comparator
+                    .compare(key, self.iter.key())
+                    .is_le(),
+            };
+            if !from_local {
+                let key = Bytes::copy_from_slice(self.iter.key());
+                self.current = Some((key, Bytes::copy_from_slice(self.iter.value())));
+                self.iter.next()?;
+                return Ok(());
+            }
+            let (key, value) = self.local[self.local_idx].clone();
+            self.local_idx += 1;
+            if self.iter.is_valid() && self.iter.key() == &key[..] {
+                self.iter.next()?;
+            }
+            if let Some(value) = value {
+                self.current = Some((key, value));
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl StorageIterator for TxnIterator<'_> {
+    fn value(&self) -> &[u8] {
+        &self.current.as_ref().unwrap().1
+    }
+
+    fn key(&self) -> &[u8] {
+        &self.current.as_ref().unwrap().0
+    }
+
+    fn is_valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.move_to_next()
+    }
+}
@@ -0,0 +1,61 @@
+use std::fmt;
+
+/// Extracts the prefix of a key, the keys sharing a prefix are read together by
+/// `LsmStorage::scan_prefix`. SSTs put the prefixes of their keys into the bloom filter, and
+/// memtables keep track of them, so a prefix scan skips the ones without the prefix.
+///
+/// The keys with the same prefix must be contiguous in the order of the comparator, and not
+/// before the prefix itself, opening a column family with a comparator that doesn't
+/// [support prefix scans](crate::comparator::Comparator::supports_prefix_scan) fails.
+pub trait PrefixExtractor: Send + Sync {
+    /// The name of the extractor, it is recorded in the SSTs, a filter built by an extractor of
+    /// another name is not used.
+    fn name(&self) -> &str;
+
+    /// Whether `key` has a prefix.
+    fn in_domain(&self, key: &[u8]) -> bool;
+
+    /// The prefix of a key in the domain.
+    fn transform<'a>(&self, key: &'a [u8]) -> &'a [u8];
+}
+
+impl fmt::Debug for dyn PrefixExtractor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PrefixExtractor({})", self.name())
+    }
+}
+
+/// The prefix of `key`, `None` if it is not in the domain of `extractor`.
+pub(crate) fn prefix_of<'a>(extractor: &dyn PrefixExtractor, key: &'a [u8]) -> Option<&'a [u8]> {
+    extractor.in_domain(key).then(|| extractor.transform(key))
+}
+
+/// The prefix is the first `len` bytes of the key, shorter keys have no prefix.
+#[derive(Clone, Debug)]
+pub struct FixedPrefixExtractor {
+    len: usize,
+    name: String,
+}
+
+impl FixedPrefixExtractor {
+    pub fn new(len: usize) -> Self {
+        Self {
+            len,
+            name: format!("lsm.FixedPrefix.{}", len),
+        }
+    }
+}
+
+impl PrefixExtractor for FixedPrefixExtractor {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn in_domain(&self, key: &[u8]) -> bool {
+        key.len() >= self.len
+    }
+
+    fn transform<'a>(&self, key: &'a [u8]) -> &'a [u8] {
+        &key[..self.len]
+    }
+}
@@ -0,0 +1,193 @@
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut, Bytes};
+
+use crate::comparator::{BytewiseComparator, Comparator};
+use crate::utils::{get_length_prefixed, put_varint, varint_len, SIZEOF_U64};
+
+/// Deletes the versions of the keys in `[start, end)` whose sequence numbers are smaller than
+/// `seq`. A version written at `seq` itself, by the same batch, is not deleted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeTombstone {
+    pub start: Bytes,
+    pub end: Bytes,
+    pub seq: u64,
+}
+
+impl RangeTombstone {
+    pub fn new(start: &[u8], end: &[u8], seq: u64) -> Self {
+        Self {
+            start: Bytes::copy_from_slice(start),
+            end: Bytes::copy_from_slice(end),
+            seq,
+        }
+    }
+
+    /// Check if `key` is in the range of the tombstone, in the order of `comparator`.
+    pub fn contains(&self, comparator: &dyn Comparator, key: &[u8]) -> bool {
+        comparator.compare(&self.start, key).is_le() && comparator.compare(key, &self.end).is_lt()
+    }
+
+    /// Check if the range of the tombstone overlaps with the key range `[first_key, last_key]`, in
+    /// the order of `comparator`.
+    pub fn overlaps(&self, comparator: &dyn Comparator, first_key: &[u8], last_key: &[u8]) -> bool {
+        comparator.compare(&self.start, last_key).is_le()
+            && comparator.compare(first_key, &self.end).is_lt()
+    }
+
+    /// Encode range tombstones to a buffer.
+    /// | start_len(varint) | start | end_len(varint) | end | seq(8B) | ...
+    pub fn encode_all(tombstones: &[RangeTombstone], buf: &mut Vec<u8>) {
+        let size: usize = tombstones
+            .iter()
+            .map(|tombstone| {
+                varint_len(tombstone.start.len() as u64)
+                    + tombstone.start.len()
+                    + varint_len(tombstone.end.len() as u64)
+                    + tombstone.end.len()
+                    + SIZEOF_U64
+            })
+            .sum();
+        buf.reserve(size);
+        for tombstone in tombstones {
+            put_varint(buf, tombstone.start.len() as u64);
+            buf.put_slice(&tombstone.start);
+            put_varint(buf, tombstone.end.len() as u64);
+            buf.put_slice(&tombstone.end);
+            buf.put_u64(tombstone.seq);
+        }
+    }
+
+    /// Decode range tombstones from a buffer.
+    pub fn decode_all(mut buf: impl Buf) -> Result<Vec<RangeTombstone>> {
+        let mut tombstones = Vec::new();
+        while buf.has_remaining() {
+            let start = get_length_prefixed(&mut buf)?;
+            let end = get_length_prefixed(&mut buf)?;
+            if buf.remaining() < SIZEOF_U64 {
+                bail!("truncated range tombstone");
+            }
+            let seq = buf.get_u64();
+            tombstones.push(RangeTombstone { start, end, seq })
+        }
+        Ok(tombstones)
+    }
+}
+
+/// Range tombstones split into non-overlapping fragments, so the tombstones covering a key are
+/// found with a binary search.
+pub struct FragmentedRangeTombstones {
+    /// Sorted by key range, each with the sequence numbers of the tombstones covering the whole
+    /// fragment, descending.
+    fragments: Vec<(Bytes, Bytes, Vec<u64>)>,
+    comparator: Arc<dyn Comparator>,
+}
+
+impl Default for FragmentedRangeTombstones {
+    fn default() -> Self {
+        Self {
+            fragments: Vec::new(),
+            comparator: Arc::new(BytewiseComparator),
+        }
+    }
+}
+
+impl FragmentedRangeTombstones {
+    /// Fragment the tombstones, keys are ordered by `comparator`.
+    pub fn new<'a>(
+        comparator: Arc<dyn Comparator>,
+        tombstones: impl IntoIterator<Item = &'a RangeTombstone>,
+    ) -> Self {
+        let ranges = tombstones
+            .into_iter()
+            .map(|t| (&t.start, &t.end, std::slice::from_ref(&t.seq)));
+        Self::fragment(comparator, ranges)
+    }
+
+    /// Fragment the tombstones of several fragmented sets together, keys are ordered by
+    /// `comparator`.
+    pub fn merge<'a>(
+        comparator: Arc<dyn Comparator>,
+        sets: impl IntoIterator<Item = &'a FragmentedRangeTombstones>,
+    ) -> Self {
+        let ranges = sets.into_iter().flat_map(|set| {
+            set.fragments
+                .iter()
+                .map(|(start, end, seqs)| (start, end, &seqs[..]))
+        });
+        Self::fragment(comparator, ranges)
+    }
+
+    /// Split the key ranges, each with the sequence numbers of the tombstones covering it, at
+    /// every bound in one pass over the bounds in order.
+    fn fragment<'a>(
+        comparator: Arc<dyn Comparator>,
+        ranges: impl IntoIterator<Item = (&'a Bytes, &'a Bytes, &'a [u64])>,
+    ) -> Self {
+        let mut ranges: Vec<(&Bytes, &Bytes, &[u64])> = ranges
+            .into_iter()
+            .filter(|(start, end, _)| comparator.compare(start, end).is_lt())
+            .collect();
+        ranges.sort_by(|a, b| comparator.compare(a.0, b.0));
+        let mut bounds: Vec<&Bytes> = ranges
+            .iter()
+            .flat_map(|(start, end, _)| [*start, *end])
+            .collect();
+        bounds.sort_by(|a, b| comparator.compare(a, b));
+        bounds.dedup();
+        // the ranges covering the current fragment, their ends are bounds after its start
+        let mut active: Vec<(&Bytes, &[u64])> = Vec::new();
+        let mut next = 0;
+        let mut fragments = Vec::new();
+        for pair in bounds.windows(2) {
+            let (start, end) = (pair[0], pair[1]);
+            active.retain(|(range_end, _)| comparator.compare(range_end, start).is_gt());
+            while let Some(&(range_start, range_end, seqs)) = ranges.get(next) {
+                if comparator.compare(range_start, start).is_gt() {
+                    break;
+                }
+                active.push((range_end, seqs));
+                next += 1;
+            }
+            if active.is_empty() {
+                continue;
+            }
+            let mut seqs: Vec<u64> = active
+                .iter()
+                .flat_map(|(_, seqs)| seqs.iter().copied())
+                .collect();
+            seqs.sort_unstable_by(|a, b| b.cmp(a));
+            fragments.push((start.clone(), end.clone(), seqs));
+        }
+        Self {
+            fragments,
+            comparator,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fragments.is_empty()
+    }
+
+    /// Sequence numbers of the tombstones covering `key`, descending.
+    pub fn covering_seqs(&self, key: &[u8]) -> &[u64] {
+        let idx = self
+            .fragments
+            .partition_point(|(_, end, _)| self.comparator.compare(end, key).is_le());
+        match self.fragments.get(idx) {
+            Some((start, _, seqs)) if self.comparator.compare(start, key).is_le() => seqs,
+            _ => &[],
+        }
+    }
+
+    /// The largest sequence number of the tombstones covering `key` that is not larger than
+    /// `read_seq`, 0 if there is none. A version of the key older than it is deleted.
+    pub fn max_covering_seq(&self, key: &[u8], read_seq: u64) -> u64 {
+        self.covering_seqs(key)
+            .iter()
+            .copied()
+            .find(|&seq| seq <= read_seq)
+            .unwrap_or(0)
+    }
+}
@@ -1,92 +1,302 @@
+mod bloom;
 mod builder;
 mod iterator;
+mod properties;
 
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::fs::FileExt;
 use std::path::Path;
 use std::sync::Arc;
 
-use anyhow::{anyhow, Result};
-pub use builder::SsTableBuilder;
+use anyhow::{anyhow, bail, Context, Result};
+pub use bloom::Bloom;
+pub use builder::{SsTableBuilder, DEFAULT_BLOOM_BITS_PER_KEY};
 use bytes::{Buf, BufMut, Bytes};
 pub use iterator::SsTableIterator;
+pub use properties::SsTableProperties;
 
-use crate::block::Block;
+use crate::block::{Block, BlockIterator};
+use crate::comparator::{BytewiseComparator, Comparator};
+use crate::key::{self, InternalKey, ValueType};
 use crate::lsm_storage::BlockCache;
-use crate::utils::{SIZEOF_U16, SIZEOF_USIZE, two_u8_to_u16};
+use crate::prefix_extractor::PrefixExtractor;
+use crate::range_tombstone::{FragmentedRangeTombstones, RangeTombstone};
+use crate::utils::{
+    get_length_prefixed, put_varint, varint_len, SIZEOF_U32, SIZEOF_U64, SIZEOF_USIZE,
+};
+
+/// The format version of the SSTables written by `SsTableBuilder`, it is recorded in the footer.
+/// Version 1 has 32-bit offsets and no version in the footer, version 2 has 64-bit offsets, and
+/// version 3 has a properties block and a footer of a fixed size.
+pub const SST_FORMAT_VERSION: u32 = 3;
+
+/// The last 8 bytes of an SSTable with a format version in its footer.
+const SST_MAGIC: u64 = 0x6c73_6d2e_7373_7462;
+
+/// The footer since format version 3: the offsets of the bloom filter, the range tombstones, the
+/// block meta and the properties, the format version and the magic.
+const FOOTER_SIZE: usize = SIZEOF_U64 * 4 + SIZEOF_U32 + SIZEOF_U64;
+
+/// The sections of an SSTable, located by its footer.
+struct Footer {
+    format_version: u32,
+    bloom_offset: usize,
+    range_tombstones_offset: usize,
+    block_meta_offset: usize,
+    /// The start of the properties, it is the comparator name before format version 3.
+    properties_offset: usize,
+    /// The end of the properties, where the footer starts.
+    properties_end: usize,
+    /// `max_seq` and the offset of the prefix extractor name, they are in the footer before
+    /// format version 3.
+    legacy: Option<(u64, usize)>,
+}
+
+impl Footer {
+    /// Read the footer and check the sections are in order within the file. A file without the
+    /// magic is read as format version 1 only if its footer of 32-bit offsets checks out.
+    fn read(file: &FileObject) -> Result<Self> {
+        let len = file.size() as usize;
+        if len < SIZEOF_U64 {
+            bail!("file of {} bytes is too short for an SST", len);
+        }
+        // the footer of format version 1 has no magic, it ends with the offsets
+        let magic = (&file.read((len - SIZEOF_U64) as u64, SIZEOF_U64 as u64)?[..]).get_u64();
+        let format_version = if magic == SST_MAGIC {
+            if len < SIZEOF_U64 + SIZEOF_U32 {
+                bail!("file of {} bytes is too short for an SST", len);
+            }
+            let format_version =
+                file.read((len - SIZEOF_U64 - SIZEOF_U32) as u64, SIZEOF_U32 as u64)?;
+            (&format_version[..]).get_u32()
+        } else {
+            1
+        };
+        let footer_size = match format_version {
+            1 => SIZEOF_U64 + SIZEOF_USIZE * 5,
+            2 => SIZEOF_U64 + SIZEOF_U64 * 5 + SIZEOF_U32 + SIZEOF_U64,
+            SST_FORMAT_VERSION => FOOTER_SIZE,
+            _ => bail!(
+                "unsupported format version {}, the latest is {}",
+                format_version,
+                SST_FORMAT_VERSION
+            ),
+        };
+        let bad_magic = || {
+            anyhow!(
+                "not an SST: bad magic {:#018x}, and no valid footer of format version 1",
+                magic
+            )
+        };
+        if len < footer_size {
+            if format_version == 1 {
+                return Err(bad_magic());
+            }
+            bail!(
+                "file of {} bytes is shorter than the footer of format version {}",
+                len,
+                format_version
+            );
+        }
+        let footer = file.read((len - footer_size) as u64, footer_size as u64)?;
+        let mut footer = &footer[..];
+        let max_seq = if format_version < 3 {
+            footer.get_u64()
+        } else {
+            0
+        };
+        let num_of_offsets = if format_version < 3 { 5 } else { 4 };
+        let mut offsets: Vec<usize> = (0..num_of_offsets)
+            .map(|_| get_offset(&mut footer, format_version))
+            .collect();
+        offsets.push(len - footer_size);
+        if !offsets.windows(2).all(|pair| pair[0] <= pair[1]) {
+            if format_version == 1 {
+                return Err(bad_magic());
+            }
+            bail!(
+                "the offsets {:?} in the footer are not in order within {} bytes",
+                offsets,
+                len
+            );
+        }
+        Ok(Self {
+            format_version,
+            bloom_offset: offsets[0],
+            range_tombstones_offset: offsets[1],
+            block_meta_offset: offsets[2],
+            properties_offset: offsets[3],
+            properties_end: len - footer_size,
+            legacy: (format_version < 3).then(|| (max_seq, offsets[4])),
+        })
+    }
+}
+
+/// Read an offset in the SSTable, it is 64-bit since format version 2.
+fn get_offset(buf: &mut impl Buf, format_version: u32) -> usize {
+    if format_version == 1 {
+        buf.get_u32() as usize
+    } else {
+        buf.get_u64() as usize
+    }
+}
+
+/// The size of an offset in the SSTable of `format_version`.
+fn offset_size(format_version: u32) -> usize {
+    if format_version == 1 {
+        SIZEOF_USIZE
+    } else {
+        SIZEOF_U64
+    }
+}
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct BlockMeta {
     /// Offset of this data block.
     pub offset: usize,
-    /// The first key of the data block.
+    /// The first internal key of the data block.
     pub first_key: Bytes,
+    /// The last internal key of the data block.
+    pub last_key: Bytes,
 }
 
 impl BlockMeta {
-    /// Encode block meta to a buffer.
+    /// Encode block meta to a buffer, in the current format version.
     pub fn encode_block_meta(
         block_meta: &[BlockMeta],
         buf: &mut Vec<u8>,
     ) {
         let mut estimated_size = 0;
         for meta in block_meta {
-            estimated_size += SIZEOF_USIZE; // offset
-            estimated_size += SIZEOF_U16; // first_key_len
+            estimated_size += SIZEOF_U64; // offset
+            estimated_size += varint_len(meta.first_key.len() as u64); // first_key_len
             estimated_size += meta.first_key.len();
+            estimated_size += varint_len(meta.last_key.len() as u64); // last_key_len
+            estimated_size += meta.last_key.len();
         }
         buf.reserve(estimated_size);
         for meta in block_meta {
-            buf.put_u32(meta.offset as u32);
-            buf.put_u16(meta.first_key.len() as u16);
+            buf.put_u64(meta.offset as u64);
+            put_varint(buf, meta.first_key.len() as u64);
             buf.put_slice(&meta.first_key);
+            put_varint(buf, meta.last_key.len() as u64);
+            buf.put_slice(&meta.last_key);
         }
     }
 
-    /// Decode block meta from a buffer.
-    pub fn decode_block_meta(mut buf: impl Buf) -> Vec<BlockMeta> {
+    /// Decode block meta of an SSTable of `format_version` from a buffer.
+    pub fn decode_block_meta(mut buf: impl Buf, format_version: u32) -> Result<Vec<BlockMeta>> {
         let mut metas = Vec::new();
         while buf.has_remaining() {
-            let offset = buf.get_u32() as usize;
-            let first_key_len = buf.get_u16() as usize;
-            let first_key = buf.copy_to_bytes(first_key_len);
+            if buf.remaining() < offset_size(format_version) {
+                bail!("truncated block meta");
+            }
+            let offset = get_offset(&mut buf, format_version);
+            let first_key = get_length_prefixed(&mut buf)?;
+            let last_key = get_length_prefixed(&mut buf)?;
+            if first_key.len() <= SIZEOF_U64 || last_key.len() <= SIZEOF_U64 {
+                bail!("block meta has a key shorter than an internal key");
+            }
             metas.push(BlockMeta {
                 offset,
-                first_key
+                first_key,
+                last_key,
             })
         }
-        metas
+        Ok(metas)
     }
 }
 
 /// A file object.
-pub struct FileObject(Bytes, u64);
+pub struct FileObject(File, u64);
 
 impl FileObject {
+    /// Read `len` bytes at `offset` with a positional read.
     pub fn read(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
-        Ok(self.0[offset as usize..(offset + len) as usize].to_vec())
+        let mut data = vec![0; len as usize];
+        self.0.read_exact_at(&mut data[..], offset)?;
+        Ok(data)
     }
 
     pub fn size(&self) -> u64 {
-        self.0.len() as u64
+        self.1
     }
 
     /// Create a new file object (day 2) and write the file to the disk (day 4).
+    ///
+    /// The data is written to a temporary file first, then renamed to `path`, so a crash never
+    /// leaves a partially written SST behind.
     pub fn create(path: &Path, data: Vec<u8>) -> Result<Self> {
-        let len = data.len() as u64;
-        let object = FileObject(Bytes::from(data), len);
-        Ok(object)
+        let mut file = Self::create_tmp(path)?;
+        file.write_all(&data)?;
+        Self::persist_tmp(file, path)
+    }
+
+    /// Create the temporary file which is renamed to `path` by [`FileObject::persist_tmp`].
+    pub(crate) fn create_tmp(path: &Path) -> Result<File> {
+        let tmp_path = path.with_extension("tmp");
+        File::create(&tmp_path).with_context(|| format!("failed to create {:?}", tmp_path))
+    }
+
+    /// Sync the temporary file written for `path`, and rename it to `path`.
+    pub(crate) fn persist_tmp(file: File, path: &Path) -> Result<Self> {
+        file.sync_all()?;
+        drop(file);
+        std::fs::rename(path.with_extension("tmp"), path)?;
+        if let Some(dir) = path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        Self::open(path)
     }
 
     pub fn open(path: &Path) -> Result<Self> {
-        unimplemented!()
+        let file = File::open(path).with_context(|| format!("failed to open {:?}", path))?;
+        let size = file.metadata()?.len();
+        Ok(FileObject(file, size))
     }
 }
 
+/// The result of a point lookup in an SSTable.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SsTableLookup {
+    /// The key is in the SSTable with this value.
+    Found(Bytes),
+    /// The key is deleted by a tombstone in the SSTable.
+    Deleted,
+    /// The latest version in the SSTable is this merge operand, older versions are needed to
+    /// resolve it.
+    Merge(Bytes),
+    /// The SSTable doesn't contain the key, older SSTables need to be searched.
+    NotFound,
+}
+
 pub struct SsTable {
     pub file: FileObject,
     pub block_metas: Vec<BlockMeta>,
     pub block_meta_offset: usize,
+    /// The bloom filter of the keys, `None` if the SSTable is built without it.
+    pub bloom: Option<Bloom>,
+    /// Offset of the bloom filter, it is also the end of the last data block.
+    pub bloom_offset: usize,
+    /// The range tombstones, they are loaded when the SSTable is opened.
+    pub range_tombstones: Vec<RangeTombstone>,
+    /// The range tombstones split into fragments sorted by start key, built once when the
+    /// SSTable is opened.
+    range_tombstone_fragments: FragmentedRangeTombstones,
+    /// The largest sequence number in the SSTable.
+    pub max_seq: u64,
+    /// The format version the SSTable is written in.
+    format_version: u32,
+    /// The properties of the SSTable, only the comparator, the prefix extractor and `max_seq` are
+    /// known before format version 3.
+    properties: SsTableProperties,
     id: usize,
+    /// Orders the user keys, its name is recorded in the SSTable.
+    comparator: Arc<dyn Comparator>,
     block_cache: Option<Arc<BlockCache>>,
+    first_key: Bytes,
+    last_key: Bytes,
 }
 
 impl SsTable {
@@ -95,31 +305,159 @@ impl SsTable {
         Self::open(0, None, file)
     }
 
-    /// Open SSTable from a file.
+    /// Open SSTable from a file, its keys must be ordered bytewise.
     pub fn open(id: usize, block_cache: Option<Arc<BlockCache>>, file: FileObject) -> Result<Self> {
-        let len = file.size() as usize;
-        let mut offset_bytes = file.read((len - SIZEOF_USIZE) as u64, SIZEOF_USIZE as u64)?;
-        let block_meta_offset = (&offset_bytes[..]).get_u32() as usize;
-        let meta_bytes = file.read(block_meta_offset as u64, (len - SIZEOF_USIZE - block_meta_offset) as u64)?;
-        Ok(Self {
+        Self::open_with_comparator(id, block_cache, file, Arc::new(BytewiseComparator))
+    }
+
+    /// Open SSTable from a file, fails if it is not built with a comparator of the same name as
+    /// `comparator`.
+    pub fn open_with_comparator(
+        id: usize,
+        block_cache: Option<Arc<BlockCache>>,
+        file: FileObject,
+        comparator: Arc<dyn Comparator>,
+    ) -> Result<Self> {
+        let footer = Footer::read(&file).with_context(|| format!("SST {} is corrupted", id))?;
+        let properties_bytes = file.read(
+            footer.properties_offset as u64,
+            (footer.properties_end - footer.properties_offset) as u64,
+        )?;
+        let properties = match footer.legacy {
+            None => SsTableProperties::decode(&properties_bytes)
+                .with_context(|| format!("SST {} has corrupted properties", id))?,
+            // only the comparator name and the prefix extractor name
+            Some((max_seq, prefix_extractor_offset)) => {
+                let (comparator_name, prefix_extractor) =
+                    properties_bytes.split_at(prefix_extractor_offset - footer.properties_offset);
+                SsTableProperties {
+                    max_seq,
+                    comparator: String::from_utf8_lossy(comparator_name).into_owned(),
+                    prefix_extractor: String::from_utf8(prefix_extractor.to_vec()).with_context(
+                        || format!("SST {} has an invalid prefix extractor name", id),
+                    )?,
+                    ..Default::default()
+                }
+            }
+        };
+        if properties.comparator != comparator.name() {
+            bail!(
+                "SST {} is ordered by comparator {:?}, but opened with {:?}",
+                id,
+                properties.comparator,
+                comparator.name()
+            );
+        }
+        let bloom_bytes = file.read(
+            footer.bloom_offset as u64,
+            (footer.range_tombstones_offset - footer.bloom_offset) as u64,
+        )?;
+        let bloom = if bloom_bytes.is_empty() {
+            None
+        } else {
+            Some(
+                Bloom::decode(&bloom_bytes)
+                    .with_context(|| format!("SST {} has a corrupted bloom filter", id))?,
+            )
+        };
+        let range_tombstone_bytes = file.read(
+            footer.range_tombstones_offset as u64,
+            (footer.block_meta_offset - footer.range_tombstones_offset) as u64,
+        )?;
+        let meta_bytes = file.read(
+            footer.block_meta_offset as u64,
+            (footer.properties_offset - footer.block_meta_offset) as u64,
+        )?;
+        let block_metas = BlockMeta::decode_block_meta(&meta_bytes[..], footer.format_version)
+            .with_context(|| format!("SST {} has corrupted block meta", id))?;
+        let block_ends = block_metas
+            .iter()
+            .skip(1)
+            .map(|meta| meta.offset)
+            .chain([footer.bloom_offset]);
+        if block_metas.first().is_some_and(|meta| meta.offset != 0)
+            || !block_metas
+                .iter()
+                .zip(block_ends)
+                .all(|(meta, end)| meta.offset < end)
+        {
+            bail!(
+                "SST {} is corrupted: the offsets of the blocks are not increasing within the data",
+                id
+            );
+        }
+        Ok(Self::new(
+            id,
+            block_cache,
+            comparator,
+            file,
+            block_metas,
+            footer.block_meta_offset,
+            bloom,
+            footer.bloom_offset,
+            RangeTombstone::decode_all(&range_tombstone_bytes[..])
+                .with_context(|| format!("SST {} has corrupted range tombstones", id))?,
+            footer.format_version,
+            properties,
+        ))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        id: usize,
+        block_cache: Option<Arc<BlockCache>>,
+        comparator: Arc<dyn Comparator>,
+        file: FileObject,
+        block_metas: Vec<BlockMeta>,
+        block_meta_offset: usize,
+        bloom: Option<Bloom>,
+        bloom_offset: usize,
+        range_tombstones: Vec<RangeTombstone>,
+        format_version: u32,
+        properties: SsTableProperties,
+    ) -> Self {
+        let user_key = |key: &Bytes| key.slice(..key.len() - SIZEOF_U64);
+        let first_key = block_metas
+            .first()
+            .map(|meta| user_key(&meta.first_key))
+            .unwrap_or_default();
+        let last_key = block_metas
+            .last()
+            .map(|meta| user_key(&meta.last_key))
+            .unwrap_or_default();
+        let range_tombstone_fragments =
+            FragmentedRangeTombstones::new(comparator.clone(), &range_tombstones);
+        Self {
             file,
-            block_metas: BlockMeta::decode_block_meta(&meta_bytes[..]),
+            block_metas,
             block_meta_offset,
+            bloom,
+            bloom_offset,
+            range_tombstones,
+            range_tombstone_fragments,
+            max_seq: properties.max_seq,
+            format_version,
+            properties,
             id,
+            comparator,
             block_cache,
-        })
+            first_key,
+            last_key,
+        }
     }
 
     /// Read a block from the disk.
     pub fn read_block(&self, block_idx: usize) -> Result<Arc<Block>> {
         let start_offset = self.block_metas[block_idx].offset;
         let end_offset = if block_idx + 1 == self.block_metas.len() {
-            self.block_meta_offset
+            self.bloom_offset
         } else {
             self.block_metas[block_idx + 1].offset
         };
         let block_data = self.file.read(start_offset as u64, (end_offset - start_offset) as u64)?;
-        Ok(Arc::new(Block::decode(&block_data[..])))
+        let block = Block::decode(&block_data[..])
+            .with_context(|| format!("corrupted block {} of SST {}", block_idx, self.id))?;
+        Ok(Arc::new(block))
     }
 
     /// Read a block from disk, with block cache. (Day 4)
@@ -134,9 +472,102 @@ impl SsTable {
         }
     }
 
-    /// Find the block that may contain `key`.
+    /// Create an iterator over a block, with block cache, it is invalid until positioned.
+    pub fn block_iter(&self, block_idx: usize) -> Result<BlockIterator> {
+        Ok(BlockIterator::new_with_comparator(
+            self.read_block_cached(block_idx)?,
+            self.comparator.clone(),
+        ))
+    }
+
+    /// Point lookup of the latest version of `key` whose sequence number is not larger than
+    /// `read_seq`, range tombstones and expiry times are not checked.
+    pub fn get(&self, key: &[u8], read_seq: u64) -> Result<SsTableLookup> {
+        Ok(match self.get_version(key, read_seq)? {
+            Some((_, ValueType::Put, value)) => SsTableLookup::Found(value),
+            Some((_, ValueType::PutWithTtl, value)) => {
+                SsTableLookup::Found(value.slice(SIZEOF_U64..))
+            }
+            Some((_, ValueType::Merge, operand)) => SsTableLookup::Merge(operand),
+            Some(_) => SsTableLookup::Deleted,
+            None => SsTableLookup::NotFound,
+        })
+    }
+
+    /// Get the latest version of `key` whose sequence number is not larger than `read_seq`, with
+    /// its sequence number and value type. Only the block that may contain it is read, or the
+    /// next one if the versions of the key cross a block boundary. No block is read if the bloom
+    /// filter rules out the key.
+    pub fn get_version(
+        &self,
+        key: &[u8],
+        read_seq: u64,
+    ) -> Result<Option<(u64, ValueType, Bytes)>> {
+        if self.block_metas.is_empty()
+            || self.comparator.compare(key, &self.first_key).is_lt()
+            || self.comparator.compare(key, &self.last_key).is_gt()
+        {
+            return Ok(None);
+        }
+        if let Some(bloom) = &self.bloom {
+            if !bloom.may_contain(Bloom::hash(key)) {
+                return Ok(None);
+            }
+        }
+        let seek_key = InternalKey::seek(key, read_seq);
+        let block_idx = self.find_block_idx(seek_key.as_bytes());
+        let mut iter = self.block_iter(block_idx)?;
+        iter.seek_to_key(seek_key.as_bytes());
+        if !iter.is_valid() && block_idx + 1 < self.num_of_blocks() {
+            iter = self.block_iter(block_idx + 1)?;
+            iter.seek_to_first();
+        }
+        if !iter.is_valid() || key::user_key(iter.key()) != key {
+            return Ok(None);
+        }
+        Ok(Some((
+            key::seq(iter.key()),
+            key::value_type(iter.key()),
+            Bytes::copy_from_slice(iter.value()),
+        )))
+    }
+
+    /// Check if the SSTable may have a key with `prefix`, a prefix extracted by
+    /// `prefix_extractor`. The bloom filter is used only if it is built with the prefixes of an
+    /// extractor of the same name.
+    pub fn may_contain_prefix(
+        &self,
+        prefix_extractor: &dyn PrefixExtractor,
+        prefix: &[u8],
+    ) -> bool {
+        if self.block_metas.is_empty() || self.comparator.compare(&self.last_key, prefix).is_lt() {
+            return false;
+        }
+        match &self.bloom {
+            Some(bloom) if self.properties.prefix_extractor == prefix_extractor.name() => {
+                bloom.may_contain(Bloom::hash(prefix))
+            }
+            _ => true,
+        }
+    }
+
+    /// The largest sequence number of the range tombstones covering `key` that is not larger
+    /// than `read_seq`, 0 if there is none.
+    pub fn max_covering_seq(&self, key: &[u8], read_seq: u64) -> u64 {
+        self.range_tombstone_fragments
+            .max_covering_seq(key, read_seq)
+    }
+
+    /// The range tombstones of the SSTable, fragmented.
+    pub fn range_tombstone_fragments(&self) -> &FragmentedRangeTombstones {
+        &self.range_tombstone_fragments
+    }
+
+    /// Find the block that may contain the internal key `key`.
     pub fn find_block_idx(&self, key: &[u8]) -> usize {
-        let i = self.block_metas.partition_point(|meta| meta.first_key <= key);
+        let i = self
+            .block_metas
+            .partition_point(|meta| key::compare(&*self.comparator, &meta.first_key, key).is_le());
         if i == 0 {
             i
         } else {
@@ -148,4 +579,65 @@ impl SsTable {
     pub fn num_of_blocks(&self) -> usize {
         self.block_metas.len()
     }
+
+    pub fn sst_id(&self) -> usize {
+        self.id
+    }
+
+    /// The format version the SSTable is written in.
+    pub fn format_version(&self) -> u32 {
+        self.format_version
+    }
+
+    /// The properties of the SSTable, `None` if it is written before the properties block is
+    /// added in format version 3.
+    pub fn properties(&self) -> Option<&SsTableProperties> {
+        (self.format_version >= 3).then_some(&self.properties)
+    }
+
+    /// The comparator ordering the user keys.
+    pub fn comparator(&self) -> &Arc<dyn Comparator> {
+        &self.comparator
+    }
+
+    /// The smallest user key in the SSTable.
+    pub fn first_key(&self) -> &Bytes {
+        &self.first_key
+    }
+
+    /// The largest user key in the SSTable.
+    pub fn last_key(&self) -> &Bytes {
+        &self.last_key
+    }
+
+    /// The key range of the point keys and the range tombstones in the SSTable, the end of a
+    /// range tombstone is included. `None` if the SSTable is empty.
+    pub fn key_range(&self) -> Option<(&[u8], &[u8])> {
+        let points = (self.num_of_blocks() > 0).then(|| (&self.first_key[..], &self.last_key[..]));
+        let tombstones = self
+            .range_tombstones
+            .iter()
+            .map(|tombstone| (&tombstone.start[..], &tombstone.end[..]));
+        points
+            .into_iter()
+            .chain(tombstones)
+            .reduce(|(first, last), (start, end)| {
+                let first = if self.comparator.compare(start, first).is_lt() {
+                    start
+                } else {
+                    first
+                };
+                let last = if self.comparator.compare(end, last).is_gt() {
+                    end
+                } else {
+                    last
+                };
+                (first, last)
+            })
+    }
+
+    /// Size of the SSTable file in bytes.
+    pub fn table_size(&self) -> u64 {
+        self.file.size()
+    }
 }
@@ -0,0 +1,113 @@
+use anyhow::{bail, Result};
+use bytes::{BufMut, Bytes};
+
+/// Implements a bloom filter, a key is inserted as its 32-bit hash.
+pub struct Bloom {
+    /// data of filter in bits
+    pub filter: Bytes,
+    /// number of hash functions
+    pub k: u8,
+}
+
+pub trait BitSlice {
+    fn get_bit(&self, idx: usize) -> bool;
+    fn bit_len(&self) -> usize;
+}
+
+pub trait BitSliceMut {
+    fn set_bit(&mut self, idx: usize, val: bool);
+}
+
+impl<T: AsRef<[u8]>> BitSlice for T {
+    fn get_bit(&self, idx: usize) -> bool {
+        let pos = idx / 8;
+        let offset = idx % 8;
+        (self.as_ref()[pos] & (1 << offset)) != 0
+    }
+
+    fn bit_len(&self) -> usize {
+        self.as_ref().len() * 8
+    }
+}
+
+impl<T: AsMut<[u8]>> BitSliceMut for T {
+    fn set_bit(&mut self, idx: usize, val: bool) {
+        let pos = idx / 8;
+        let offset = idx % 8;
+        if val {
+            self.as_mut()[pos] |= 1 << offset;
+        } else {
+            self.as_mut()[pos] &= !(1 << offset);
+        }
+    }
+}
+
+impl Bloom {
+    /// Decode a bloom filter, `[filter, k(1B)]`, fails if the filter has no bits.
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        let Some((&k, filter)) = buf.split_last() else {
+            bail!("empty bloom filter");
+        };
+        if filter.is_empty() {
+            bail!("bloom filter has no bits");
+        }
+        Ok(Self {
+            filter: Bytes::copy_from_slice(filter),
+            k,
+        })
+    }
+
+    /// Encode a bloom filter, `[filter, k(1B)]`
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend(&self.filter);
+        buf.put_u8(self.k);
+    }
+
+    /// Build bloom filter from key hashes
+    pub fn build_from_key_hashes(keys: &[u32], bits_per_key: usize) -> Self {
+        // k = ln(2) * bits_per_key minimizes the false positive rate
+        let k = ((bits_per_key as f64 * 0.69) as u32).clamp(1, 30);
+        let nbits = (keys.len() * bits_per_key).max(64);
+        let nbytes = nbits.div_ceil(8);
+        let nbits = nbytes * 8;
+        let mut filter = vec![0u8; nbytes];
+        for h in keys {
+            let mut h = *h;
+            // double hashing, the following hashes are derived from the first one
+            let delta = h.rotate_left(15);
+            for _ in 0..k {
+                let bit_pos = (h as usize) % nbits;
+                filter.set_bit(bit_pos, true);
+                h = h.wrapping_add(delta);
+            }
+        }
+        Self {
+            filter: filter.into(),
+            k: k as u8,
+        }
+    }
+
+    /// Check if a bloom filter may contain some data
+    pub fn may_contain(&self, mut h: u32) -> bool {
+        if self.k > 30 {
+            // potential new encoding for short bloom filters
+            true
+        } else {
+            let nbits = self.filter.bit_len();
+            let delta = h.rotate_left(15);
+            for _ in 0..self.k {
+                let bit_pos = (h as usize) % nbits;
+                if !self.filter.get_bit(bit_pos) {
+                    return false;
+                }
+                h = h.wrapping_add(delta);
+            }
+            true
+        }
+    }
+
+    /// Hash of a key inserted into the filter.
+    pub fn hash(key: &[u8]) -> u32 {
+        farmhash::fingerprint32(key)
+    }
+}
@@ -1,85 +1,291 @@
-use std::ops::Deref;
-use std::path::Path;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use anyhow::Result;
 use bytes::{BufMut, Bytes};
-use crate::block::{Block, BlockBuilder, BlockIterator};
+use crate::block::BlockBuilder;
+use crate::comparator::{BytewiseComparator, Comparator};
+use crate::clock::{Clock, SystemClock};
+use crate::key::{self, ValueType};
+use crate::prefix_extractor::{prefix_of, PrefixExtractor};
+use crate::range_tombstone::RangeTombstone;
 
-use super::{BlockMeta, SsTable};
+use super::bloom::Bloom;
+use super::{BlockMeta, SsTable, SsTableProperties, SST_FORMAT_VERSION, SST_MAGIC};
 use crate::lsm_storage::BlockCache;
 use crate::table::FileObject;
+use crate::utils::SIZEOF_U64;
 
-/// Builds an SSTable from key-value pairs.
+/// About 1% false positive rate.
+pub const DEFAULT_BLOOM_BITS_PER_KEY: usize = 10;
+
+/// Builds an SSTable from key-value pairs, keys are internal keys. Finished blocks are written to
+/// a temporary file next to the SSTable, only their offsets and metadata are kept in memory.
 pub struct SsTableBuilder {
     pub meta: Vec<BlockMeta>,
-    pub data: Vec<u8>,
+    /// The path of the SSTable, it is renamed from the temporary file when built.
+    path: PathBuf,
+    /// The temporary file, created when the first block is finished.
+    file: Option<BufWriter<File>>,
+    /// The number of bytes written to the temporary file.
+    offset: usize,
+    /// The first error of writing the temporary file, reported when building.
+    error: Option<anyhow::Error>,
     block_builder: BlockBuilder,
     block_size: usize,
+    /// The last key added to the current block.
+    last_key: Vec<u8>,
+    /// Hashes of all the user keys, for building the bloom filter.
+    key_hashes: Vec<u32>,
+    range_tombstones: Vec<RangeTombstone>,
+    /// The statistics collected while adding, the rest is filled in when building.
+    properties: SsTableProperties,
+    /// Bits per key of the bloom filter, 0 builds no bloom filter.
+    bloom_bits_per_key: usize,
+    comparator: Arc<dyn Comparator>,
+    /// The prefixes of the keys are put into the bloom filter too.
+    prefix_extractor: Option<Arc<dyn PrefixExtractor>>,
+    /// The creation time in the properties is taken from it.
+    clock: Arc<dyn Clock>,
 }
 
 impl SsTableBuilder {
-    /// Create a builder based on target block size.
-    pub fn new(block_size: usize) -> Self {
+    /// Create a builder of the SSTable at `path` based on target block size.
+    pub fn new(path: impl AsRef<Path>, block_size: usize) -> Self {
+        Self::new_with_bloom(path, block_size, DEFAULT_BLOOM_BITS_PER_KEY)
+    }
+
+    /// Create a builder based on target block size and bits per key of the bloom filter.
+    pub fn new_with_bloom(
+        path: impl AsRef<Path>,
+        block_size: usize,
+        bloom_bits_per_key: usize,
+    ) -> Self {
+        Self::new_with_comparator(
+            path,
+            block_size,
+            bloom_bits_per_key,
+            Arc::new(BytewiseComparator),
+        )
+    }
+
+    /// Create a builder of an SSTable whose keys are ordered by `comparator`.
+    pub fn new_with_comparator(
+        path: impl AsRef<Path>,
+        block_size: usize,
+        bloom_bits_per_key: usize,
+        comparator: Arc<dyn Comparator>,
+    ) -> Self {
         Self {
             meta: Vec::new(),
-            data: Vec::new(),
+            path: path.as_ref().to_path_buf(),
+            file: None,
+            offset: 0,
+            error: None,
             block_builder: BlockBuilder::new(block_size),
             block_size,
+            last_key: Vec::new(),
+            key_hashes: Vec::new(),
+            range_tombstones: Vec::new(),
+            properties: SsTableProperties::default(),
+            bloom_bits_per_key,
+            comparator,
+            prefix_extractor: None,
+            clock: Arc::new(SystemClock),
         }
     }
 
-    /// Adds a key-value pair to SSTable
+    /// Put the prefixes extracted by `prefix_extractor` into the bloom filter, so a prefix scan
+    /// can skip the SSTable.
+    pub fn with_prefix_extractor(mut self, prefix_extractor: Arc<dyn PrefixExtractor>) -> Self {
+        self.prefix_extractor = Some(prefix_extractor);
+        self
+    }
+
+    /// Take the creation time of the SSTable from `clock` instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Adds a key-value pair to SSTable, keys must be added in the order of internal keys, by the
+    /// comparator of the builder.
     pub fn add(&mut self, key: &[u8], value: &[u8]) {
         if self.block_builder.is_empty() {
             self.meta.push(BlockMeta {
-                offset: self.data.len(),
+                offset: self.offset,
                 first_key: Bytes::copy_from_slice(key),
+                last_key: Bytes::new(),
             })
         }
         let r = self.block_builder.add(key, value);
         if !r {
             self.finish_block();
             self.add(key, value);
+            return;
+        }
+        // versions of the same user key are hashed once
+        let is_new_user_key =
+            self.last_key.is_empty() || key::user_key(&self.last_key) != key::user_key(key);
+        if self.bloom_bits_per_key > 0 && is_new_user_key {
+            self.key_hashes.push(Bloom::hash(key::user_key(key)));
+            // keys with the same prefix are contiguous, the prefix is hashed once
+            if let Some(extractor) = &self.prefix_extractor {
+                match prefix_of(&**extractor, key::user_key(key)) {
+                    Some(prefix)
+                        if self.last_key.is_empty()
+                            || prefix_of(&**extractor, key::user_key(&self.last_key))
+                                != Some(prefix) =>
+                    {
+                        self.key_hashes.push(Bloom::hash(prefix));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        self.properties.num_entries += 1;
+        if key::value_type(key) == ValueType::Delete {
+            self.properties.num_deletions += 1;
         }
+        self.properties.raw_key_size += key.len() as u64;
+        self.properties.raw_value_size += value.len() as u64;
+        self.properties.max_seq = self.properties.max_seq.max(key::seq(key));
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+    }
+
+    /// Adds a range tombstone to the SSTable, they are kept in their own block.
+    pub fn add_range_tombstone(&mut self, tombstone: RangeTombstone) {
+        self.properties.num_range_deletions += 1;
+        self.properties.max_seq = self.properties.max_seq.max(tombstone.seq);
+        self.range_tombstones.push(tombstone);
     }
 
     fn finish_block(&mut self) {
+        if self.block_builder.is_empty() {
+            return;
+        }
         let block_builder = std::mem::replace(&mut self.block_builder, BlockBuilder::new(self.block_size));
-        self.data.extend(block_builder.build().encode());
+        if let Some(meta) = self.meta.last_mut() {
+            meta.last_key = Bytes::copy_from_slice(&self.last_key);
+        }
+        self.write(&block_builder.build().encode());
+    }
+
+    /// Append `data` to the temporary file, an error is kept until the SSTable is built.
+    fn write(&mut self, data: &[u8]) {
+        if self.error.is_some() {
+            return;
+        }
+        let result = match &mut self.file {
+            Some(file) => file.write_all(data).map_err(anyhow::Error::from),
+            None => FileObject::create_tmp(&self.path).and_then(|file| {
+                let file = self.file.insert(BufWriter::new(file));
+                Ok(file.write_all(data)?)
+            }),
+        };
+        match result {
+            Ok(()) => self.offset += data.len(),
+            Err(e) => self.error = Some(e),
+        }
+    }
+
+    /// Check if there is no key-value pair or range tombstone in the SSTable.
+    pub fn is_empty(&self) -> bool {
+        self.meta.is_empty() && self.range_tombstones.is_empty()
     }
 
     /// Get the estimated size of the SSTable.
     pub fn estimated_size(&self) -> usize {
-        self.data.len()
+        self.offset
     }
 
-    /// Builds the SSTable and writes it to the given path. No need to actually write to disk until
-    /// chapter 4 block cache.
-    /// | block1 | ... | block99 | block meta | block meta offset |
-    pub fn build(
-        mut self,
-        id: usize,
-        block_cache: Option<Arc<BlockCache>>,
-        path: impl AsRef<Path>,
-    ) -> Result<SsTable> {
+    /// Builds the SSTable and moves it to the path of the builder.
+    /// | block1 | ... | block99 | bloom filter | range tombstones | block meta | properties |
+    /// | bloom offset | range tombstones offset | block meta offset | properties offset |
+    /// | format version | magic |
+    ///
+    /// The footer after the properties has a fixed size, the offsets are 64-bit.
+    pub fn build(mut self, id: usize, block_cache: Option<Arc<BlockCache>>) -> Result<SsTable> {
         self.finish_block();
-        let block_meta_offset = self.data.len();
-        let mut buf = self.data;
+        // the sections after the blocks are small, they are written at once
+        let bloom_offset = self.offset;
+        let mut buf = Vec::new();
+        let bloom = if self.bloom_bits_per_key > 0 {
+            let bloom = Bloom::build_from_key_hashes(&self.key_hashes, self.bloom_bits_per_key);
+            bloom.encode(&mut buf);
+            Some(bloom)
+        } else {
+            None
+        };
+        let range_tombstones_offset = bloom_offset + buf.len();
+        RangeTombstone::encode_all(&self.range_tombstones, &mut buf);
+        let block_meta_offset = bloom_offset + buf.len();
         BlockMeta::encode_block_meta(&self.meta, &mut buf);
-        buf.put_u32(block_meta_offset as u32);
-        let file = FileObject::create(path.as_ref(), buf)?;
-        Ok(SsTable {
-            file,
-            block_metas: self.meta,
-            block_meta_offset,
+        let mut properties = std::mem::take(&mut self.properties);
+        let user_key = |key: &Bytes| key.slice(..key.len() - SIZEOF_U64);
+        properties.smallest_key = self
+            .meta
+            .first()
+            .map(|meta| user_key(&meta.first_key))
+            .unwrap_or_default();
+        properties.largest_key = self
+            .meta
+            .last()
+            .map(|meta| user_key(&meta.last_key))
+            .unwrap_or_default();
+        properties.creation_time = self.clock.now();
+        properties.comparator = self.comparator.name().to_string();
+        // empty if the bloom filter has no prefix
+        properties.prefix_extractor = match (&self.prefix_extractor, &bloom) {
+            (Some(extractor), Some(_)) => extractor.name().to_string(),
+            _ => String::new(),
+        };
+        properties.block_size = self.block_size as u64;
+        properties.bloom_bits_per_key = self.bloom_bits_per_key as u64;
+        let properties_offset = bloom_offset + buf.len();
+        properties.encode(&mut buf);
+        buf.put_u64(bloom_offset as u64);
+        buf.put_u64(range_tombstones_offset as u64);
+        buf.put_u64(block_meta_offset as u64);
+        buf.put_u64(properties_offset as u64);
+        buf.put_u32(SST_FORMAT_VERSION);
+        buf.put_u64(SST_MAGIC);
+        self.write(&buf);
+        if let Some(e) = self.error.take() {
+            return Err(e.context(format!("failed to write {:?}", self.path)));
+        }
+        let file = self.file.take().expect("the footer is written to the file");
+        let file =
+            FileObject::persist_tmp(file.into_inner().map_err(|e| e.into_error())?, &self.path)?;
+        Ok(SsTable::new(
             id,
             block_cache,
-        })
+            self.comparator.clone(),
+            file,
+            std::mem::take(&mut self.meta),
+            block_meta_offset,
+            bloom,
+            bloom_offset,
+            std::mem::take(&mut self.range_tombstones),
+            SST_FORMAT_VERSION,
+            properties,
+        ))
     }
 
     // #[cfg(test)]
-    pub fn build_for_test(self, path: impl AsRef<Path>) -> Result<SsTable> {
-        self.build(0, None, path)
+    pub fn build_for_test(self) -> Result<SsTable> {
+        self.build(0, None)
+    }
+}
+
+impl Drop for SsTableBuilder {
+    /// Remove the temporary file of a builder which is dropped before it's built.
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = std::fs::remove_file(self.path.with_extension("tmp"));
+        }
     }
 }
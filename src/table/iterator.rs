@@ -1,12 +1,11 @@
-use std::process::id;
 use std::sync::Arc;
 
-use anyhow::{Error, Result};
-use bytes::Buf;
-use crate::block::{Block, BlockIterator};
+use anyhow::Result;
+use crate::block::BlockIterator;
 
 use super::SsTable;
 use crate::iterators::StorageIterator;
+use crate::key::{self, InternalKey, ValueType, MAX_SEQ};
 
 /// An iterators over the contents of an SSTable.
 pub struct SsTableIterator {
@@ -16,10 +15,19 @@ pub struct SsTableIterator {
 }
 
 impl SsTableIterator {
+    /// Create a new iterators, it is invalid until positioned by a seek.
+    pub fn new(table: Arc<SsTable>) -> Self {
+        Self {
+            table,
+            block_idx: 0,
+            block_iter: BlockIterator::empty(),
+        }
+    }
+
     /// Create a new iterators and seek to the first key-value pair.
     pub fn create_and_seek_to_first(table: Arc<SsTable>) -> Result<Self> {
         let (block_idx, block_iter) = Self::seek_to_first_inner(&table)?;
-        let mut iter = Self {
+        let iter = Self {
             table,
             block_idx,
             block_iter,
@@ -35,7 +43,7 @@ impl SsTableIterator {
         Ok(())
     }
 
-    /// Create a new iterators and seek to the first key-value pair which >= `key`.
+    /// Create a new iterators and seek to the first version of the first key which >= `key`.
     pub fn create_and_seek_to_key(table: Arc<SsTable>, key: &[u8]) -> Result<Self> {
         let (block_idx, block_iter) = Self::seek_to_key_inner(&table, key)?;
         Ok(Self {
@@ -45,7 +53,7 @@ impl SsTableIterator {
         })
     }
 
-    /// Seek to the first key-value pair which >= `key`.
+    /// Seek to the first version of the first key which >= `key`.
     pub fn seek_to_key(&mut self, key: &[u8]) -> Result<()> {
         let (block_idx, block_iter) = Self::seek_to_key_inner(&self.table, key)?;
         self.block_idx = block_idx;
@@ -53,23 +61,92 @@ impl SsTableIterator {
         Ok(())
     }
 
+    /// Create a new iterators and seek to the last (oldest) version of the last key.
+    pub fn create_and_seek_to_last(table: Arc<SsTable>) -> Result<Self> {
+        let (block_idx, block_iter) = Self::seek_to_last_inner(&table)?;
+        Ok(Self {
+            table,
+            block_idx,
+            block_iter,
+        })
+    }
+
+    /// Seek to the last (oldest) version of the last key.
+    pub fn seek_to_last(&mut self) -> Result<()> {
+        let (block_idx, block_iter) = Self::seek_to_last_inner(&self.table)?;
+        self.block_idx = block_idx;
+        self.block_iter = block_iter;
+        Ok(())
+    }
+
+    /// Create a new iterators and seek to the last (oldest) version of the last key which <= `key`.
+    pub fn create_and_seek_for_prev(table: Arc<SsTable>, key: &[u8]) -> Result<Self> {
+        let (block_idx, block_iter) = Self::seek_for_prev_inner(&table, key)?;
+        Ok(Self {
+            table,
+            block_idx,
+            block_iter,
+        })
+    }
+
+    /// Seek to the last (oldest) version of the last key which <= `key`.
+    pub fn seek_for_prev(&mut self, key: &[u8]) -> Result<()> {
+        let (block_idx, block_iter) = Self::seek_for_prev_inner(&self.table, key)?;
+        self.block_idx = block_idx;
+        self.block_iter = block_iter;
+        Ok(())
+    }
+
     fn seek_to_first_inner(table: &Arc<SsTable>) -> Result<(usize, BlockIterator)> {
-        Ok(
-            (0, BlockIterator::create_and_seek_to_first(table.read_block_cached(0)?))
-        )
+        if table.num_of_blocks() == 0 {
+            return Ok((0, BlockIterator::empty()));
+        }
+        let mut block_iter = table.block_iter(0)?;
+        block_iter.seek_to_first();
+        Ok((0, block_iter))
     }
 
     fn seek_to_key_inner(table: &Arc<SsTable>, key: &[u8]) -> Result<(usize, BlockIterator)> {
+        if table.num_of_blocks() == 0 {
+            return Ok((0, BlockIterator::empty()));
+        }
+        let key = InternalKey::seek(key, MAX_SEQ);
+        let key = key.as_bytes();
         let mut block_idx = table.find_block_idx(key);
-        let mut block_iter = BlockIterator::create_and_seek_to_key(table.read_block_cached(block_idx)?, key);
+        let mut block_iter = table.block_iter(block_idx)?;
+        block_iter.seek_to_key(key);
         // not find key in block[idx], return block[idx + 1] first key
         if !block_iter.is_valid() && block_idx + 1 < table.num_of_blocks() {
             block_idx += 1;
-            block_iter = BlockIterator::create_and_seek_to_first(table.read_block_cached(block_idx)?);
+            block_iter = table.block_iter(block_idx)?;
+            block_iter.seek_to_first();
+        }
+        Ok((block_idx, block_iter))
+    }
+
+    fn seek_to_last_inner(table: &Arc<SsTable>) -> Result<(usize, BlockIterator)> {
+        if table.num_of_blocks() == 0 {
+            return Ok((0, BlockIterator::empty()));
         }
+        let block_idx = table.num_of_blocks() - 1;
+        let mut block_iter = table.block_iter(block_idx)?;
+        block_iter.seek_to_last();
         Ok((block_idx, block_iter))
     }
 
+    fn seek_for_prev_inner(table: &Arc<SsTable>, key: &[u8]) -> Result<(usize, BlockIterator)> {
+        if table.num_of_blocks() == 0 {
+            return Ok((0, BlockIterator::empty()));
+        }
+        let key = InternalKey::after_all_versions(key);
+        let key = key.as_bytes();
+        // the last block starting at or before the key, the key is in it unless it is the first
+        // block and every key is after the key
+        let block_idx = table.find_block_idx(key);
+        let mut block_iter = table.block_iter(block_idx)?;
+        block_iter.seek_for_prev(key);
+        Ok((block_idx, block_iter))
+    }
 }
 
 impl StorageIterator for SsTableIterator {
@@ -78,7 +155,15 @@ impl StorageIterator for SsTableIterator {
     }
 
     fn key(&self) -> &[u8] {
-        self.block_iter.key()
+        key::user_key(self.block_iter.key())
+    }
+
+    fn seq(&self) -> u64 {
+        key::seq(self.block_iter.key())
+    }
+
+    fn value_type(&self) -> ValueType {
+        key::value_type(self.block_iter.key())
     }
 
     fn is_valid(&self) -> bool {
@@ -89,8 +174,35 @@ impl StorageIterator for SsTableIterator {
         self.block_iter.next();
         if !self.block_iter.is_valid() && self.block_idx + 1 < self.table.num_of_blocks() {
             self.block_idx += 1;
-            self.block_iter = BlockIterator::create_and_seek_to_first(self.table.read_block_cached(self.block_idx)?);
+            self.block_iter = self.table.block_iter(self.block_idx)?;
+            self.block_iter.seek_to_first();
         }
         Ok(())
     }
+
+    fn prev(&mut self) -> Result<()> {
+        self.block_iter.prev();
+        if !self.block_iter.is_valid() && self.block_idx > 0 {
+            self.block_idx -= 1;
+            self.block_iter = self.table.block_iter(self.block_idx)?;
+            self.block_iter.seek_to_last();
+        }
+        Ok(())
+    }
+
+    fn seek_to_first(&mut self) -> Result<()> {
+        SsTableIterator::seek_to_first(self)
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        SsTableIterator::seek_to_last(self)
+    }
+
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        self.seek_to_key(key)
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()> {
+        SsTableIterator::seek_for_prev(self, key)
+    }
 }
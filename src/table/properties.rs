@@ -0,0 +1,102 @@
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut, Bytes};
+
+use crate::utils::{get_length_prefixed, get_varint, put_varint, SIZEOF_U64};
+
+/// Statistics of an SSTable and the options it is built with, kept in its properties block.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SsTableProperties {
+    /// Number of versions in the data blocks, tombstones and merge operands included.
+    pub num_entries: u64,
+    /// Number of point tombstones.
+    pub num_deletions: u64,
+    /// Number of range tombstones.
+    pub num_range_deletions: u64,
+    /// Total size of the internal keys in the data blocks.
+    pub raw_key_size: u64,
+    /// Total size of the values in the data blocks.
+    pub raw_value_size: u64,
+    /// The smallest user key in the data blocks, empty if there is none.
+    pub smallest_key: Bytes,
+    /// The largest user key in the data blocks, empty if there is none.
+    pub largest_key: Bytes,
+    /// The largest sequence number of the keys and range tombstones.
+    pub max_seq: u64,
+    /// Milliseconds since the UNIX epoch when the SSTable is built.
+    pub creation_time: u64,
+    /// The name of the comparator ordering the user keys.
+    pub comparator: String,
+    /// The name of the prefix extractor whose prefixes are in the bloom filter, empty if there is
+    /// none.
+    pub prefix_extractor: String,
+    /// The target size of the data blocks.
+    pub block_size: u64,
+    /// Bits per key of the bloom filter, 0 if there is none.
+    pub bloom_bits_per_key: u64,
+}
+
+impl SsTableProperties {
+    /// Encode the properties, integers are varints and byte strings are prefixed with their
+    /// length, `max_seq` and `creation_time` are 8 bytes.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        for value in [
+            self.num_entries,
+            self.num_deletions,
+            self.num_range_deletions,
+            self.raw_key_size,
+            self.raw_value_size,
+        ] {
+            put_varint(buf, value);
+        }
+        for bytes in [&self.smallest_key[..], &self.largest_key[..]] {
+            put_varint(buf, bytes.len() as u64);
+            buf.put_slice(bytes);
+        }
+        buf.put_u64(self.max_seq);
+        buf.put_u64(self.creation_time);
+        for name in [&self.comparator, &self.prefix_extractor] {
+            put_varint(buf, name.len() as u64);
+            buf.put_slice(name.as_bytes());
+        }
+        put_varint(buf, self.block_size);
+        put_varint(buf, self.bloom_bits_per_key);
+    }
+
+    /// Decode the properties, fails if the buffer is truncated or has trailing bytes.
+    pub fn decode(mut buf: &[u8]) -> Result<Self> {
+        let num_entries = get_varint(&mut buf)?;
+        let num_deletions = get_varint(&mut buf)?;
+        let num_range_deletions = get_varint(&mut buf)?;
+        let raw_key_size = get_varint(&mut buf)?;
+        let raw_value_size = get_varint(&mut buf)?;
+        let smallest_key = get_length_prefixed(&mut buf)?;
+        let largest_key = get_length_prefixed(&mut buf)?;
+        if buf.remaining() < SIZEOF_U64 * 2 {
+            bail!("truncated properties");
+        }
+        let max_seq = buf.get_u64();
+        let creation_time = buf.get_u64();
+        let comparator = String::from_utf8(get_length_prefixed(&mut buf)?.to_vec())?;
+        let prefix_extractor = String::from_utf8(get_length_prefixed(&mut buf)?.to_vec())?;
+        let block_size = get_varint(&mut buf)?;
+        let bloom_bits_per_key = get_varint(&mut buf)?;
+        if buf.has_remaining() {
+            bail!("{} trailing bytes after the properties", buf.remaining());
+        }
+        Ok(Self {
+            num_entries,
+            num_deletions,
+            num_range_deletions,
+            raw_key_size,
+            raw_value_size,
+            smallest_key,
+            largest_key,
+            max_seq,
+            creation_time,
+            comparator,
+            prefix_extractor,
+            block_size,
+            bloom_bits_per_key,
+        })
+    }
+}
@@ -1,9 +1,71 @@
-/// [u8,2] -> u16
-pub fn two_u8_to_u16(slice: &[u8]) -> u16 {
-    assert_eq!(slice.len(), 2, "slice size not 2");
-    ((slice[0] as u16) << 8) | slice[1] as u16
-}
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut, Bytes};
 
 pub const SIZEOF_USIZE: usize = 4;
 
-pub const SIZEOF_U16: usize = std::mem::size_of::<u16>();
+pub const SIZEOF_U32: usize = std::mem::size_of::<u32>();
+
+pub const SIZEOF_U64: usize = std::mem::size_of::<u64>();
+
+/// Append `value` as a varint, 7 bits per byte from the lowest, the high bit of a byte is set
+/// when more bytes follow.
+pub fn put_varint(buf: &mut impl BufMut, mut value: u64) {
+    while value >= 0x80 {
+        buf.put_u8((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+/// Number of bytes of `value` encoded as a varint.
+pub fn varint_len(value: u64) -> usize {
+    (64 - (value | 1).leading_zeros() as usize).div_ceil(7)
+}
+
+/// Read a varint from the front of `buf`.
+pub fn get_varint(buf: &mut impl Buf) -> Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        if !buf.has_remaining() {
+            bail!("truncated varint");
+        }
+        let byte = buf.get_u8();
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("varint is longer than 10 bytes")
+}
+
+/// Read a slice prefixed with its length as a varint from the front of `buf`.
+pub fn get_length_prefixed(buf: &mut impl Buf) -> Result<Bytes> {
+    let len = get_varint(buf)?;
+    if len > buf.remaining() as u64 {
+        bail!(
+            "length {} is beyond the end of the buffer, {} bytes left",
+            len,
+            buf.remaining()
+        );
+    }
+    Ok(buf.copy_to_bytes(len as usize))
+}
+
+/// Whether a complete record of a log with a valid checksum starts at any offset of `buf`, the
+/// records are `[len, body, checksum(4B)]` with a big-endian length of `len_size` bytes and a
+/// non-empty body. A torn write leaves none after it, so finding one means the log is corrupted
+/// in the middle.
+pub(crate) fn contains_valid_record(buf: &[u8], len_size: usize) -> bool {
+    (0..buf.len()).any(|offset| {
+        let mut rest = &buf[offset..];
+        if rest.len() < len_size + SIZEOF_U32 {
+            return false;
+        }
+        let len = rest.get_uint(len_size);
+        if len == 0 || len > (rest.len() - SIZEOF_U32) as u64 {
+            return false;
+        }
+        let (body, mut checksum) = rest.split_at(len as usize);
+        crc32fast::hash(body) == checksum.get_u32()
+    })
+}
@@ -0,0 +1,230 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use bytes::{Buf, BufMut, Bytes};
+use parking_lot::Mutex;
+
+use crate::key::{self, InternalKey};
+use crate::utils::{contains_valid_record, SIZEOF_U32};
+
+/// When the write-ahead log calls `fsync`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WalSyncPolicy {
+    /// `fsync` after every write, an acknowledged write survives power loss.
+    EveryWrite,
+    /// `fsync` once every `n` writes, records in between only reach the OS page cache.
+    Group(usize),
+    /// Never `fsync`, every write is only handed to the OS. An acknowledged write survives a
+    /// process crash, but not an OS crash.
+    Buffered,
+}
+
+/// The write-ahead log shared by the column families. Every put / delete is appended here before
+/// it is applied to the memtable of its family, so the memtables can be rebuilt after a crash.
+/// A new WAL is started whenever a memtable is frozen, the old ones are deleted once every
+/// family has flushed its entries in them.
+pub struct Wal {
+    writer: Mutex<WalWriter>,
+    sync_policy: WalSyncPolicy,
+}
+
+struct WalWriter {
+    file: BufWriter<File>,
+    /// Number of records written since the last `fsync`.
+    unsynced: usize,
+}
+
+impl Wal {
+    /// Create a new empty WAL file.
+    pub fn create(path: impl AsRef<Path>, sync_policy: WalSyncPolicy) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(path.as_ref())
+            .with_context(|| format!("failed to create WAL {:?}", path.as_ref()))?;
+        Ok(Self::new(file, sync_policy))
+    }
+
+    /// Replay an existing WAL file, `apply` is called on every entry in order with the ID of its
+    /// column family. A torn record at the tail (the process crashed while appending it) is
+    /// dropped and the file is truncated before it. A corrupted record followed by more data
+    /// fails the replay, the records after it are kept.
+    pub fn replay(
+        path: impl AsRef<Path>,
+        mut apply: impl FnMut(u32, InternalKey, Bytes),
+    ) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(path.as_ref())
+            .with_context(|| format!("failed to recover WAL {:?}", path.as_ref()))?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+
+        let mut rbuf = &buf[..];
+        while let Some(entries) = Self::decode_record(&mut rbuf).with_context(|| {
+            format!(
+                "WAL {:?} is corrupted at offset {}",
+                path.as_ref(),
+                buf.len() - rbuf.len()
+            )
+        })? {
+            for (column_family, key, value) in entries {
+                apply(column_family, InternalKey::from_bytes(key), value);
+            }
+        }
+        let valid_len = buf.len() - rbuf.len();
+        if valid_len < buf.len() {
+            file.set_len(valid_len as u64)?;
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn new(file: File, sync_policy: WalSyncPolicy) -> Self {
+        Self {
+            writer: Mutex::new(WalWriter {
+                file: BufWriter::new(file),
+                unsynced: 0,
+            }),
+            sync_policy,
+        }
+    }
+
+    /// Append a key-value pair of a column family to the log, `key` is an internal key.
+    pub fn put(&self, column_family: u32, key: &[u8], value: &[u8]) -> Result<()> {
+        self.put_batch(&[(column_family, key, value)])
+    }
+
+    /// Append key-value pairs, each with the ID of its column family, to the log as one record,
+    /// they are all recovered or none.
+    pub fn put_batch(&self, entries: &[(u32, &[u8], &[u8])]) -> Result<()> {
+        if let Some((_, key, value)) = entries.iter().find(|(_, key, value)| {
+            key.len() > u32::MAX as usize || value.len() > u32::MAX as usize
+        }) {
+            bail!(
+                "WAL entry with a key of {} bytes and a value of {} bytes exceeds the 4 GiB limit",
+                key.len(),
+                value.len()
+            );
+        }
+        let size = entries
+            .iter()
+            .map(|(_, key, value)| SIZEOF_U32 + SIZEOF_U32 + key.len() + SIZEOF_U32 + value.len())
+            .sum();
+        let mut body = Vec::with_capacity(size);
+        for (column_family, key, value) in entries {
+            Self::encode_entry(&mut body, *column_family, key, value);
+        }
+        self.append_record(&body)
+    }
+
+    /// Flush buffered records and `fsync` the file.
+    pub fn sync(&self) -> Result<()> {
+        let mut writer = self.writer.lock();
+        writer.file.flush()?;
+        writer.file.get_mut().sync_all()?;
+        writer.unsynced = 0;
+        Ok(())
+    }
+
+    /// record: `[body_len(4B), body, checksum(4B)]`, body is a list of entries
+    fn append_record(&self, body: &[u8]) -> Result<()> {
+        if body.len() > u32::MAX as usize {
+            bail!("WAL record of {} bytes exceeds the 4 GiB limit", body.len());
+        }
+        let mut record = Vec::with_capacity(SIZEOF_U32 + body.len() + SIZEOF_U32);
+        record.put_u32(body.len() as u32);
+        record.put_slice(body);
+        record.put_u32(crc32fast::hash(body));
+
+        let mut writer = self.writer.lock();
+        writer.file.write_all(&record)?;
+        writer.file.flush()?;
+        writer.unsynced += 1;
+        let need_sync = match self.sync_policy {
+            WalSyncPolicy::EveryWrite => true,
+            WalSyncPolicy::Group(n) => writer.unsynced >= n,
+            WalSyncPolicy::Buffered => false,
+        };
+        if need_sync {
+            writer.file.get_mut().sync_data()?;
+            writer.unsynced = 0;
+        }
+        Ok(())
+    }
+
+    /// entry: `[column_family(4B), key_len(4B), key, value_len(4B), value]`
+    fn encode_entry(buf: &mut Vec<u8>, column_family: u32, key: &[u8], value: &[u8]) {
+        buf.put_u32(column_family);
+        buf.put_u32(key.len() as u32);
+        buf.put_slice(key);
+        buf.put_u32(value.len() as u32);
+        buf.put_slice(value);
+    }
+
+    /// Decode the next record, returns `None` if the rest of the buffer is a torn record: it is
+    /// incomplete, or the checksum of the last record doesn't match. Fails if the checksum of a
+    /// record followed by more data doesn't match, or a valid record follows an incomplete one,
+    /// which has a corrupted length.
+    fn decode_record(buf: &mut &[u8]) -> Result<Option<Vec<(u32, Bytes, Bytes)>>> {
+        if buf.len() < SIZEOF_U32 {
+            return Ok(None);
+        }
+        let body_len = (&buf[..SIZEOF_U32]).get_u32() as usize;
+        let record_len = SIZEOF_U32 + body_len + SIZEOF_U32;
+        if buf.len() < record_len {
+            if contains_valid_record(&buf[1..], SIZEOF_U32) {
+                bail!(
+                    "record of {} bytes runs past the end, but a valid record follows",
+                    body_len
+                );
+            }
+            return Ok(None);
+        }
+        let body = &buf[SIZEOF_U32..(SIZEOF_U32 + body_len)];
+        let checksum = (&buf[(SIZEOF_U32 + body_len)..]).get_u32();
+        if crc32fast::hash(body) != checksum {
+            if buf.len() == record_len {
+                return Ok(None);
+            }
+            bail!(
+                "checksum mismatch of a record followed by {} bytes",
+                buf.len() - record_len
+            );
+        }
+
+        let mut entries = Vec::new();
+        let mut body = body;
+        while body.has_remaining() {
+            let column_family = Self::get_u32(&mut body)?;
+            let key_len = Self::get_u32(&mut body)? as usize;
+            let key = Self::get_bytes(&mut body, key_len)?;
+            let value_len = Self::get_u32(&mut body)? as usize;
+            let value = Self::get_bytes(&mut body, value_len)?;
+            key::check_entry(&key, &value)?;
+            entries.push((column_family, key, value));
+        }
+        buf.advance(record_len);
+        Ok(Some(entries))
+    }
+
+    fn get_u32(body: &mut &[u8]) -> Result<u32> {
+        if body.remaining() < SIZEOF_U32 {
+            bail!("truncated entry");
+        }
+        Ok(body.get_u32())
+    }
+
+    fn get_bytes(body: &mut &[u8], len: usize) -> Result<Bytes> {
+        if body.remaining() < len {
+            bail!(
+                "entry of {} bytes is longer than the rest of the record",
+                len
+            );
+        }
+        Ok(body.copy_to_bytes(len))
+    }
+}
@@ -0,0 +1,130 @@
+use bytes::Bytes;
+
+use crate::lsm_storage::{ColumnFamily, DEFAULT_COLUMN_FAMILY_ID};
+
+/// An operation in a [`WriteBatch`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteBatchEntry {
+    Put(Bytes, Bytes),
+    Delete(Bytes),
+    /// A merge operand of the key.
+    Merge(Bytes, Bytes),
+    /// Delete the keys in `[start, end)`.
+    DeleteRange(Bytes, Bytes),
+}
+
+/// A list of writes applied atomically by `LsmStorage::write`, later entries override earlier
+/// ones on the same key. The `_cf` methods write to a column family, the others to the default
+/// one.
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    /// Entries with the IDs of their column families.
+    entries: Vec<(u32, WriteBatchEntry)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.put_to(DEFAULT_COLUMN_FAMILY_ID, key, value)
+    }
+
+    pub fn put_cf(&mut self, column_family: &ColumnFamily, key: &[u8], value: &[u8]) -> &mut Self {
+        self.put_to(column_family.id(), key, value)
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> &mut Self {
+        self.delete_to(DEFAULT_COLUMN_FAMILY_ID, key)
+    }
+
+    pub fn delete_cf(&mut self, column_family: &ColumnFamily, key: &[u8]) -> &mut Self {
+        self.delete_to(column_family.id(), key)
+    }
+
+    /// Write a merge operand of a key. It is combined with an earlier entry of the batch on the
+    /// same key when the batch is written.
+    pub fn merge(&mut self, key: &[u8], operand: &[u8]) -> &mut Self {
+        self.merge_to(DEFAULT_COLUMN_FAMILY_ID, key, operand)
+    }
+
+    pub fn merge_cf(
+        &mut self,
+        column_family: &ColumnFamily,
+        key: &[u8],
+        operand: &[u8],
+    ) -> &mut Self {
+        self.merge_to(column_family.id(), key, operand)
+    }
+
+    /// Delete the keys in `[start, end)`.
+    pub fn delete_range(&mut self, start: &[u8], end: &[u8]) -> &mut Self {
+        self.delete_range_to(DEFAULT_COLUMN_FAMILY_ID, start, end)
+    }
+
+    pub fn delete_range_cf(
+        &mut self,
+        column_family: &ColumnFamily,
+        start: &[u8],
+        end: &[u8],
+    ) -> &mut Self {
+        self.delete_range_to(column_family.id(), start, end)
+    }
+
+    fn put_to(&mut self, column_family: u32, key: &[u8], value: &[u8]) -> &mut Self {
+        assert!(!value.is_empty(), "value cannot be empty");
+        assert!(!key.is_empty(), "key cannot be empty");
+        self.entries.push((
+            column_family,
+            WriteBatchEntry::Put(Bytes::copy_from_slice(key), Bytes::copy_from_slice(value)),
+        ));
+        self
+    }
+
+    fn delete_to(&mut self, column_family: u32, key: &[u8]) -> &mut Self {
+        assert!(!key.is_empty(), "key cannot be empty");
+        self.entries.push((
+            column_family,
+            WriteBatchEntry::Delete(Bytes::copy_from_slice(key)),
+        ));
+        self
+    }
+
+    fn merge_to(&mut self, column_family: u32, key: &[u8], operand: &[u8]) -> &mut Self {
+        assert!(!key.is_empty(), "key cannot be empty");
+        self.entries.push((
+            column_family,
+            WriteBatchEntry::Merge(Bytes::copy_from_slice(key), Bytes::copy_from_slice(operand)),
+        ));
+        self
+    }
+
+    fn delete_range_to(&mut self, column_family: u32, start: &[u8], end: &[u8]) -> &mut Self {
+        self.entries.push((
+            column_family,
+            WriteBatchEntry::DeleteRange(
+                Bytes::copy_from_slice(start),
+                Bytes::copy_from_slice(end),
+            ),
+        ));
+        self
+    }
+
+    /// The entries of the batch, each with the ID of its column family.
+    pub fn entries(&self) -> &[(u32, WriteBatchEntry)] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
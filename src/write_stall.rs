@@ -0,0 +1,168 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Error, Result};
+use parking_lot::{Condvar, Mutex};
+
+use crate::compact::CompactionController;
+use crate::lsm_storage::{LsmStorageCore, LsmStorageInner, SharedState};
+
+/// How long a write is delayed when the storage is slowing down writes.
+const WRITE_DELAY: Duration = Duration::from_millis(1);
+
+/// Why writes are delayed or stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteStallCause {
+    /// Too many immutable memtables are waiting to be flushed.
+    MemtableLimit,
+    /// Too many SSTs in L0.
+    L0FileCount,
+    /// Too many bytes are waiting to be compacted.
+    PendingCompactionBytes,
+}
+
+/// Whether the storage is holding back writes until the flush and the compaction catch up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteStallStatus {
+    Normal,
+    /// Every write is delayed a little.
+    Delayed(WriteStallCause),
+    /// Writes are blocked.
+    Stopped(WriteStallCause),
+}
+
+/// Wakes up the writers blocked by a write stop.
+#[derive(Default)]
+pub(crate) struct WriteController {
+    mutex: Mutex<()>,
+    condvar: Condvar,
+}
+
+/// A job run by the background threads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BackgroundJob {
+    Flush,
+    Compaction,
+}
+
+impl fmt::Display for BackgroundJob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackgroundJob::Flush => write!(f, "flush"),
+            BackgroundJob::Compaction => write!(f, "compaction"),
+        }
+    }
+}
+
+/// The last errors of the background jobs. While a job keeps failing, writes fail with its error
+/// instead of filling the memtables and L0 that the job can't drain.
+#[derive(Default)]
+pub(crate) struct BackgroundErrors {
+    flush: Mutex<Option<Arc<Error>>>,
+    compaction: Mutex<Option<Arc<Error>>>,
+}
+
+impl BackgroundErrors {
+    fn of(&self, job: BackgroundJob) -> &Mutex<Option<Arc<Error>>> {
+        match job {
+            BackgroundJob::Flush => &self.flush,
+            BackgroundJob::Compaction => &self.compaction,
+        }
+    }
+
+    /// Record the result of a run of `job`, a success clears its error.
+    fn record(&self, job: BackgroundJob, result: Result<()>) {
+        *self.of(job).lock() = result.err().map(Arc::new);
+    }
+
+    /// Fails with the error of a failing background job, if any.
+    pub(crate) fn check(&self) -> Result<()> {
+        for job in [BackgroundJob::Flush, BackgroundJob::Compaction] {
+            if let Some(error) = &*self.of(job).lock() {
+                return Err(anyhow!("background {} failed: {:#}", job, error));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SharedState {
+    /// Record the result of a background job, writers see its error before their next write,
+    /// and the writers stopped waiting for the job are woken up to fail with it.
+    pub(crate) fn record_background_result(&self, job: BackgroundJob, result: Result<()>) {
+        let failed = result.is_err();
+        self.background_errors.record(job, result);
+        if failed {
+            for column_family in self.column_families() {
+                column_family.notify_write_stall_change();
+            }
+        }
+    }
+}
+
+impl LsmStorageCore {
+    pub(crate) fn write_stall_status(&self) -> WriteStallStatus {
+        let snapshot = {
+            let guard = self.inner.read();
+            guard.clone()
+        };
+        self.write_stall_status_of(&snapshot)
+    }
+
+    fn write_stall_status_of(&self, snapshot: &LsmStorageInner) -> WriteStallStatus {
+        let options = &self.options;
+        if snapshot.imm_memtables.len() >= options.max_imm_memtables {
+            return WriteStallStatus::Stopped(WriteStallCause::MemtableLimit);
+        }
+        // L0 only shrinks by compaction
+        if let CompactionController::NoCompaction = self.compaction_controller {
+            return WriteStallStatus::Normal;
+        }
+        let num_of_l0_sstables = snapshot.l0_sstables.len();
+        if num_of_l0_sstables >= options.level0_stop_writes_trigger {
+            return WriteStallStatus::Stopped(WriteStallCause::L0FileCount);
+        }
+        let pending_compaction_bytes = self
+            .compaction_controller
+            .estimate_pending_compaction_bytes(snapshot);
+        if pending_compaction_bytes >= options.hard_pending_compaction_bytes_limit {
+            return WriteStallStatus::Stopped(WriteStallCause::PendingCompactionBytes);
+        }
+        if num_of_l0_sstables >= options.level0_slowdown_writes_trigger {
+            return WriteStallStatus::Delayed(WriteStallCause::L0FileCount);
+        }
+        if pending_compaction_bytes >= options.soft_pending_compaction_bytes_limit {
+            return WriteStallStatus::Delayed(WriteStallCause::PendingCompactionBytes);
+        }
+        WriteStallStatus::Normal
+    }
+
+    /// Called before a write, blocks while writes are stopped and sleeps a little while they
+    /// are delayed. Fails if a background job is failing.
+    pub(crate) fn stall_writes(&self) -> Result<()> {
+        self.shared.background_errors.check()?;
+        match self.write_stall_status() {
+            WriteStallStatus::Normal => return Ok(()),
+            WriteStallStatus::Delayed(_) => {
+                std::thread::sleep(WRITE_DELAY);
+                return Ok(());
+            }
+            WriteStallStatus::Stopped(_) => (),
+        }
+        let mut guard = self.write_controller.mutex.lock();
+        // checked with the mutex held, the background threads notify with it held after they
+        // change the state or fail, so no notification is missed
+        while let WriteStallStatus::Stopped(_) = self.write_stall_status() {
+            self.shared.background_errors.check()?;
+            self.write_controller.condvar.wait(&mut guard);
+        }
+        Ok(())
+    }
+
+    /// Wake up the stopped writers after a flush or a compaction changes the state.
+    pub(crate) fn notify_write_stall_change(&self) {
+        let _guard = self.write_controller.mutex.lock();
+        self.write_controller.condvar.notify_all();
+    }
+}
@@ -1,24 +1,35 @@
 use std::sync::Arc;
 use bytes::Bytes;
 use lsm::block::{Block, BlockBuilder, BlockIterator};
+use lsm::key::{InternalKey, ValueType, MAX_SEQ};
+
+fn internal_key(user_key: &[u8]) -> Vec<u8> {
+    InternalKey::new(user_key, 0, ValueType::Put)
+        .as_bytes()
+        .to_vec()
+}
+
+fn seek_key(user_key: &[u8]) -> Vec<u8> {
+    InternalKey::seek(user_key, MAX_SEQ).as_bytes().to_vec()
+}
 
 #[test]
 fn test_block_build_single_key() {
     let mut builder = BlockBuilder::new(16);
-    assert!(builder.add(b"233", b"233333"));
+    assert!(builder.add(&internal_key(b"233"), b"233333"));
     builder.build();
 }
 
 #[test]
 fn test_block_build_full() {
     let mut builder = BlockBuilder::new(16);
-    assert!(builder.add(b"11", b"11"));
-    assert!(!builder.add(b"22", b"22"));
+    assert!(builder.add(&internal_key(b"11"), b"11"));
+    assert!(!builder.add(&internal_key(b"22"), b"22"));
     builder.build();
 }
 
 fn key_of(idx: usize) -> Vec<u8> {
-    format!("key_{:03}", idx * 5).into_bytes()
+    internal_key(format!("key_{:03}", idx * 5).as_bytes())
 }
 
 fn value_of(idx: usize) -> Vec<u8> {
@@ -54,11 +65,71 @@ fn test_block_encode() {
 fn test_block_decode() {
     let block = generate_block();
     let encoded = block.encode();
-    let decoded_block = Block::decode(&encoded);
+    let decoded_block = Block::decode(&encoded).unwrap();
     assert_eq!(block.offsets, decoded_block.offsets);
     assert_eq!(block.data, decoded_block.data);
 }
 
+#[test]
+fn test_block_large_entries() {
+    // lengths and offsets past 64 KiB
+    let key = internal_key(&vec![b'k'; 70000]);
+    let value = vec![b'v'; 3 << 20];
+    let mut builder = BlockBuilder::new(4096);
+    assert!(builder.add(&key, &value));
+    assert!(!builder.add(&key_of(0), b"233"));
+    let block = builder.build();
+    let mut builder = BlockBuilder::new(1 << 20);
+    for idx in 0..num_of_keys() {
+        assert!(builder.add(&key_of(idx), &vec![b'v'; 1000 + idx]));
+    }
+    assert!(!builder.add(&key_of(num_of_keys()), &value));
+
+    let decoded = Block::decode(&block.encode()).unwrap();
+    let iter = BlockIterator::create_and_seek_to_first(Arc::new(decoded));
+    assert_eq!(iter.key(), key);
+    assert_eq!(iter.value(), value);
+    let mut iter = BlockIterator::create_and_seek_to_last(Arc::new(
+        Block::decode(&builder.build().encode()).unwrap(),
+    ));
+    for idx in (0..num_of_keys()).rev() {
+        assert_eq!(iter.key(), key_of(idx));
+        assert_eq!(iter.value(), vec![b'v'; 1000 + idx]);
+        iter.prev();
+    }
+}
+
+#[test]
+fn test_block_decode_corrupted() {
+    let encoded = generate_block().encode();
+    assert!(Block::decode(&encoded[..2]).is_err());
+    // the entry count is larger than the block
+    let mut corrupted = encoded.to_vec();
+    let len = corrupted.len();
+    corrupted[len - 4] = 0xff;
+    assert!(Block::decode(&corrupted).is_err());
+    // the last entry is cut off
+    let mut corrupted = encoded[..(len - 4 - num_of_keys() * 4 - 1)].to_vec();
+    corrupted.extend_from_slice(&encoded[(len - 4 - num_of_keys() * 4)..]);
+    assert!(Block::decode(&corrupted).is_err());
+    // the value type of the first key is unknown
+    let mut corrupted = encoded.to_vec();
+    corrupted[key_of(0).len()] = 0xff;
+    assert!(Block::decode(&corrupted).is_err());
+    // the value of a `PutWithTtl` version has no expiry time
+    let mut builder = BlockBuilder::new(10000);
+    assert!(builder.add(
+        InternalKey::new(b"233", 1, ValueType::PutWithTtl).as_bytes(),
+        b"2333"
+    ));
+    let err = Block::decode(&builder.build().encode()).err().unwrap();
+    assert!(
+        format!("{:#}", err).contains("too short to have an expiry time"),
+        "{:#}",
+        err
+    );
+}
+
 fn as_bytes(x: &[u8]) -> Bytes {
     Bytes::copy_from_slice(x)
 }
@@ -113,8 +184,36 @@ fn test_block_seek_key() {
                 as_bytes(&value_of(i)),
                 as_bytes(value)
             );
-            iter.seek_to_key(&format!("key_{:03}", i * 5 + offset).into_bytes());
+            iter.seek_to_key(&seek_key(format!("key_{:03}", i * 5 + offset).as_bytes()));
         }
-        iter.seek_to_key(b"k");
+        iter.seek_to_key(&seek_key(b"k"));
+    }
+}
+
+#[test]
+fn test_block_iterator_backward() {
+    let block = Arc::new(generate_block());
+    let mut iter = BlockIterator::create_and_seek_to_last(block);
+    for i in (0..num_of_keys()).rev() {
+        assert_eq!(
+            iter.key(),
+            key_of(i),
+            "expected key: {:?}, actual key: {:?}",
+            as_bytes(&key_of(i)),
+            as_bytes(iter.key())
+        );
+        assert_eq!(iter.value(), value_of(i));
+        iter.prev();
+    }
+    assert!(!iter.is_valid());
+
+    for i in 0..num_of_keys() {
+        // between the key and the next one
+        iter.seek_for_prev(&seek_key(format!("key_{:03}", i * 5 + 1).as_bytes()));
+        assert_eq!(iter.key(), key_of(i));
+        iter.seek_for_prev(&key_of(i));
+        assert_eq!(iter.key(), key_of(i));
     }
+    iter.seek_for_prev(&seek_key(b"k"));
+    assert!(!iter.is_valid());
 }
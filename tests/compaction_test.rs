@@ -0,0 +1,549 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::Path;
+use std::time::Duration;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use tempfile::tempdir;
+use lsm::clock::Clock;
+use lsm::compact::{
+    CompactionDecision, CompactionFilter, CompactionFilterContext, CompactionOptions,
+    LeveledCompactionOptions, TieredCompactionOptions,
+};
+use lsm::iterators::StorageIterator;
+use lsm::lsm_storage::{LsmStorage, LsmStorageOptions};
+use lsm::merge_operator::MergeOperator;
+
+fn key_of(idx: usize) -> Vec<u8> {
+    format!("key_{:05}", idx).into_bytes()
+}
+
+fn value_of(idx: usize, round: usize) -> Vec<u8> {
+    format!("value_{:05}_{:010}", idx, round).into_bytes()
+}
+
+fn num_of_sst_files(path: &Path) -> usize {
+    std::fs::read_dir(path)
+        .unwrap()
+        .filter(|entry| {
+            entry
+                .as_ref()
+                .unwrap()
+                .path()
+                .extension()
+                .unwrap_or_default()
+                == "sst"
+        })
+        .count()
+}
+
+fn check_storage(storage: &LsmStorage, expected: &BTreeMap<Vec<u8>, Vec<u8>>, num_of_keys: usize) {
+    for idx in 0..num_of_keys {
+        let key = key_of(idx);
+        assert_eq!(
+            storage.get(&key).unwrap().map(|v| v.to_vec()),
+            expected.get(&key).cloned(),
+            "key: {:?}",
+            Bytes::from(key)
+        );
+    }
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    for (key, value) in expected {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key(), &key[..]);
+        assert_eq!(iter.value(), &value[..]);
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+    let mut iter = storage
+        .scan(Bound::Excluded(&key_of(100)), Bound::Included(&key_of(200)))
+        .unwrap();
+    for (key, value) in expected.range(key_of(101)..=key_of(200)) {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key(), &key[..]);
+        assert_eq!(iter.value(), &value[..]);
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+    let mut iter = storage
+        .scan_rev(Bound::Included(&key_of(50)), Bound::Excluded(&key_of(150)))
+        .unwrap();
+    for (key, value) in expected.range(key_of(50)..key_of(150)).rev() {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key(), &key[..]);
+        assert_eq!(iter.value(), &value[..]);
+        iter.prev().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+fn leveled_options() -> LsmStorageOptions {
+    LsmStorageOptions {
+        block_size: 256,
+        target_sst_size: 1024,
+        compaction_options: CompactionOptions::Leveled(LeveledCompactionOptions {
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 4,
+            base_level_size: 4096,
+            level_size_multiplier: 2,
+        }),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_leveled_compaction() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open_with_options(&dir, leveled_options()).unwrap();
+    let num_of_keys = 500;
+    let mut expected = BTreeMap::new();
+    for round in 0..10 {
+        for idx in (round..num_of_keys).step_by(3) {
+            storage.put(&key_of(idx), &value_of(idx, round)).unwrap();
+            expected.insert(key_of(idx), value_of(idx, round));
+        }
+        for idx in (round..num_of_keys).step_by(7) {
+            storage.delete(&key_of(idx)).unwrap();
+            expected.remove(&key_of(idx));
+        }
+        storage.sync().unwrap();
+    }
+    storage.compact().unwrap();
+    check_storage(&storage, &expected, num_of_keys);
+    drop(storage);
+
+    let storage = LsmStorage::open_with_options(&dir, leveled_options()).unwrap();
+    check_storage(&storage, &expected, num_of_keys);
+}
+
+#[test]
+fn test_leveled_compaction_removes_obsolete_files() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open_with_options(&dir, leveled_options()).unwrap();
+    let mut expected = BTreeMap::new();
+    for round in 0..20 {
+        for idx in 0..50 {
+            storage.put(&key_of(idx), &value_of(idx, round)).unwrap();
+            expected.insert(key_of(idx), value_of(idx, round));
+        }
+        storage.sync().unwrap();
+    }
+    storage.compact().unwrap();
+    // all the versions are overwritten, only about one round of data is left
+    assert!(num_of_sst_files(dir.path()) <= 3);
+    check_storage(&storage, &expected, 50);
+}
+
+fn tiered_options() -> LsmStorageOptions {
+    LsmStorageOptions {
+        block_size: 256,
+        target_sst_size: 1024,
+        compaction_options: CompactionOptions::Tiered(TieredCompactionOptions {
+            num_tiers: 3,
+            max_size_amplification_percent: 200,
+            size_ratio: 1,
+            min_merge_width: 2,
+        }),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_tiered_compaction() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open_with_options(&dir, tiered_options()).unwrap();
+    let num_of_keys = 500;
+    let mut expected = BTreeMap::new();
+    for round in 0..10 {
+        for idx in (round..num_of_keys).step_by(3) {
+            storage.put(&key_of(idx), &value_of(idx, round)).unwrap();
+            expected.insert(key_of(idx), value_of(idx, round));
+        }
+        for idx in (round..num_of_keys).step_by(7) {
+            storage.delete(&key_of(idx)).unwrap();
+            expected.remove(&key_of(idx));
+        }
+        storage.sync().unwrap();
+        storage.compact().unwrap();
+        check_storage(&storage, &expected, num_of_keys);
+    }
+    drop(storage);
+
+    let storage = LsmStorage::open_with_options(&dir, tiered_options()).unwrap();
+    check_storage(&storage, &expected, num_of_keys);
+}
+
+#[test]
+fn test_tiered_compaction_space_amplification() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open_with_options(&dir, tiered_options()).unwrap();
+    let mut expected = BTreeMap::new();
+    for round in 0..20 {
+        for idx in 0..50 {
+            storage.put(&key_of(idx), &value_of(idx, round)).unwrap();
+            expected.insert(key_of(idx), value_of(idx, round));
+        }
+        storage.sync().unwrap();
+        storage.compact().unwrap();
+    }
+    // overwritten versions are compacted away once the space amplification is too large
+    assert!(num_of_sst_files(dir.path()) <= 9);
+    check_storage(&storage, &expected, 50);
+}
+
+#[test]
+fn test_compaction_keeps_snapshot_versions() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open_with_options(&dir, leveled_options()).unwrap();
+    let num_of_keys = 200;
+    let mut expected = BTreeMap::new();
+    for idx in 0..num_of_keys {
+        storage.put(&key_of(idx), &value_of(idx, 0)).unwrap();
+        expected.insert(key_of(idx), value_of(idx, 0));
+    }
+    let snapshot = storage.snapshot();
+    let snapshot_expected = expected.clone();
+    for round in 1..10 {
+        for idx in (round..num_of_keys).step_by(3) {
+            storage.put(&key_of(idx), &value_of(idx, round)).unwrap();
+            expected.insert(key_of(idx), value_of(idx, round));
+        }
+        for idx in (round..num_of_keys).step_by(7) {
+            storage.delete(&key_of(idx)).unwrap();
+            expected.remove(&key_of(idx));
+        }
+        storage.sync().unwrap();
+    }
+    storage.compact().unwrap();
+    check_storage(&storage, &expected, num_of_keys);
+    for idx in 0..num_of_keys {
+        assert_eq!(
+            snapshot.get(&key_of(idx)).unwrap().map(|v| v.to_vec()),
+            snapshot_expected.get(&key_of(idx)).cloned()
+        );
+    }
+    let mut iter = snapshot.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    for (key, value) in &snapshot_expected {
+        assert_eq!(iter.key(), &key[..]);
+        assert_eq!(iter.value(), &value[..]);
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+
+    drop(snapshot);
+    check_storage(&storage, &expected, num_of_keys);
+}
+
+#[test]
+fn test_compaction_drops_range_deleted_keys() {
+    let dir = tempdir().unwrap();
+    // all the keys stay in L1
+    let options = || LsmStorageOptions {
+        compaction_options: CompactionOptions::Leveled(LeveledCompactionOptions {
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 4,
+            base_level_size: 1 << 20,
+            level_size_multiplier: 2,
+        }),
+        ..leveled_options()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options()).unwrap();
+    let num_of_keys = 200;
+    let mut expected = BTreeMap::new();
+    for round in 0..3 {
+        for idx in 0..num_of_keys {
+            storage.put(&key_of(idx), &value_of(idx, round)).unwrap();
+            expected.insert(key_of(idx), value_of(idx, round));
+        }
+        storage.sync().unwrap();
+    }
+    storage.compact().unwrap();
+    storage.delete_range(&key_of(20), &key_of(180)).unwrap();
+    for idx in 20..180 {
+        expected.remove(&key_of(idx));
+    }
+    storage.put(&key_of(100), &value_of(100, 3)).unwrap();
+    expected.insert(key_of(100), value_of(100, 3));
+    storage.sync().unwrap();
+    check_storage(&storage, &expected, num_of_keys);
+
+    // the files of the deleted keys are dropped by compaction, rewriting the rest
+    let dir_size = |path: &Path| -> u64 {
+        std::fs::read_dir(path)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().unwrap_or_default() == "sst")
+            .map(|path| std::fs::metadata(path).unwrap().len())
+            .sum()
+    };
+    let size_before = dir_size(dir.path());
+    for round in 4..8 {
+        for idx in (0..20).chain(180..num_of_keys) {
+            storage.put(&key_of(idx), &value_of(idx, round)).unwrap();
+            expected.insert(key_of(idx), value_of(idx, round));
+        }
+        storage.sync().unwrap();
+        storage.compact().unwrap();
+    }
+    check_storage(&storage, &expected, num_of_keys);
+    assert!(dir_size(dir.path()) < size_before / 2);
+
+    drop(storage);
+    let storage = LsmStorage::open_with_options(&dir, options()).unwrap();
+    check_storage(&storage, &expected, num_of_keys);
+}
+
+/// Adds the operands to a decimal counter, counting the full merges.
+struct CounterOperator {
+    full_merges: Arc<AtomicUsize>,
+}
+
+fn parse_counter(value: &[u8]) -> u64 {
+    std::str::from_utf8(value).unwrap().parse().unwrap()
+}
+
+impl MergeOperator for CounterOperator {
+    fn name(&self) -> &str {
+        "counter"
+    }
+
+    fn full_merge(
+        &self,
+        _key: &[u8],
+        existing_value: Option<&[u8]>,
+        operands: &[&[u8]],
+    ) -> anyhow::Result<Bytes> {
+        self.full_merges.fetch_add(1, Ordering::SeqCst);
+        let sum: u64 = existing_value
+            .into_iter()
+            .chain(operands.iter().copied())
+            .map(parse_counter)
+            .sum();
+        Ok(Bytes::from(sum.to_string()))
+    }
+
+    fn partial_merge(&self, _key: &[u8], operands: &[&[u8]]) -> Option<Bytes> {
+        let sum: u64 = operands.iter().copied().map(parse_counter).sum();
+        Some(Bytes::from(sum.to_string()))
+    }
+}
+
+#[test]
+fn test_compaction_merges_operands() {
+    let dir = tempdir().unwrap();
+    let full_merges = Arc::new(AtomicUsize::new(0));
+    // all the keys stay in L1
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::Leveled(LeveledCompactionOptions {
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 4,
+            base_level_size: 1 << 20,
+            level_size_multiplier: 2,
+        }),
+        merge_operator: Some(Arc::new(CounterOperator {
+            full_merges: full_merges.clone(),
+        })),
+        ..leveled_options()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    let num_of_keys = 100;
+    let mut snapshot = None;
+    for round in 0..10 {
+        for idx in 0..num_of_keys {
+            storage.merge(&key_of(idx), b"1").unwrap();
+        }
+        if round == 4 {
+            snapshot = Some(storage.snapshot());
+        }
+        storage.sync().unwrap();
+    }
+    storage.compact().unwrap();
+    let snapshot = snapshot.unwrap();
+    for idx in 0..num_of_keys {
+        assert_eq!(&storage.get(&key_of(idx)).unwrap().unwrap()[..], b"10");
+        assert_eq!(&snapshot.get(&key_of(idx)).unwrap().unwrap()[..], b"5");
+    }
+
+    drop(snapshot);
+    for _ in 0..2 {
+        for idx in 0..num_of_keys {
+            storage.merge(&key_of(idx), b"1").unwrap();
+        }
+        storage.sync().unwrap();
+    }
+    storage.compact().unwrap();
+    // every chain of operands is resolved in the bottom level
+    full_merges.store(0, Ordering::SeqCst);
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    for idx in 0..num_of_keys {
+        assert_eq!(&storage.get(&key_of(idx)).unwrap().unwrap()[..], b"12");
+        assert_eq!(iter.key(), &key_of(idx)[..]);
+        assert_eq!(iter.value(), b"12");
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+    assert_eq!(full_merges.load(Ordering::SeqCst), 0);
+}
+
+/// Removes the expired values and rewrites the stale ones, recording the contexts it is called
+/// with.
+struct ExpiryFilter {
+    contexts: Arc<Mutex<Vec<CompactionFilterContext>>>,
+}
+
+impl CompactionFilter for ExpiryFilter {
+    fn name(&self) -> &str {
+        "expiry"
+    }
+
+    fn filter(
+        &self,
+        context: &CompactionFilterContext,
+        _key: &[u8],
+        value: &[u8],
+    ) -> CompactionDecision {
+        self.contexts.lock().unwrap().push(context.clone());
+        if value.starts_with(b"expired") {
+            CompactionDecision::Remove
+        } else if value.starts_with(b"stale") {
+            CompactionDecision::ChangeValue(Bytes::from_static(b"refreshed"))
+        } else {
+            CompactionDecision::Keep
+        }
+    }
+}
+
+#[test]
+fn test_compaction_filter() {
+    let dir = tempdir().unwrap();
+    let contexts = Arc::new(Mutex::new(Vec::new()));
+    // all the keys stay in L1, so every compaction rewrites all of them
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::Leveled(LeveledCompactionOptions {
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 4,
+            base_level_size: 1 << 20,
+            level_size_multiplier: 2,
+        }),
+        compaction_filter: Some(Arc::new(ExpiryFilter {
+            contexts: contexts.clone(),
+        })),
+        ..leveled_options()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    let num_of_keys = 200;
+    let mut expected = BTreeMap::new();
+    for idx in 0..num_of_keys {
+        storage.put(&key_of(idx), &value_of(idx, 0)).unwrap();
+        expected.insert(key_of(idx), value_of(idx, 0));
+    }
+    storage.sync().unwrap();
+    for idx in (0..num_of_keys).step_by(5) {
+        storage.put(&key_of(idx), b"expired").unwrap();
+        expected.remove(&key_of(idx));
+    }
+    // the values a snapshot sees are not filtered
+    let snapshot = storage.snapshot();
+    for idx in (1..num_of_keys).step_by(5) {
+        storage.put(&key_of(idx), b"stale").unwrap();
+        expected.insert(key_of(idx), b"refreshed".to_vec());
+    }
+    storage.sync().unwrap();
+    storage.compact().unwrap();
+    assert!(!contexts.lock().unwrap().is_empty());
+    assert!(contexts
+        .lock()
+        .unwrap()
+        .iter()
+        .all(|context| context.output_level >= 1));
+    assert_eq!(
+        &snapshot.get(&key_of(1)).unwrap().unwrap()[..],
+        &value_of(1, 0)[..]
+    );
+    assert_eq!(&storage.get(&key_of(1)).unwrap().unwrap()[..], b"refreshed");
+    assert_eq!(&storage.get(&key_of(0)).unwrap().unwrap()[..], b"expired");
+
+    drop(snapshot);
+    for round in 1..5 {
+        for idx in (2..num_of_keys).step_by(5) {
+            storage.put(&key_of(idx), &value_of(idx, round)).unwrap();
+            expected.insert(key_of(idx), value_of(idx, round));
+        }
+        storage.sync().unwrap();
+        storage.compact().unwrap();
+    }
+    check_storage(&storage, &expected, num_of_keys);
+    assert!(contexts
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|context| context.is_bottommost));
+}
+
+/// A clock moved forward by the test, in milliseconds.
+#[derive(Default)]
+struct ManualClock(AtomicU64);
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+#[test]
+fn test_compaction_drops_expired_keys() {
+    let dir = tempdir().unwrap();
+    let clock = Arc::new(ManualClock::default());
+    // all the keys stay in L1
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::Leveled(LeveledCompactionOptions {
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 4,
+            base_level_size: 1 << 20,
+            level_size_multiplier: 2,
+        }),
+        clock: clock.clone(),
+        ..leveled_options()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    let num_of_keys = 200;
+    let mut expected = BTreeMap::new();
+    for round in 0..3 {
+        for idx in 0..num_of_keys {
+            if idx % 10 == 0 {
+                storage.put(&key_of(idx), &value_of(idx, round)).unwrap();
+                expected.insert(key_of(idx), value_of(idx, round));
+            } else {
+                storage
+                    .put_with_ttl(&key_of(idx), &value_of(idx, round), Duration::from_secs(60))
+                    .unwrap();
+            }
+        }
+        storage.sync().unwrap();
+    }
+    storage.compact().unwrap();
+    let sst_size = |path: &Path| -> u64 {
+        std::fs::read_dir(path)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().unwrap_or_default() == "sst")
+            .map(|path| std::fs::metadata(path).unwrap().len())
+            .sum()
+    };
+    let size_before = sst_size(dir.path());
+
+    clock.0.fetch_add(60_000, Ordering::SeqCst);
+    check_storage(&storage, &expected, num_of_keys);
+    for round in 3..5 {
+        for idx in (0..num_of_keys).step_by(10) {
+            storage.put(&key_of(idx), &value_of(idx, round)).unwrap();
+            expected.insert(key_of(idx), value_of(idx, round));
+        }
+        storage.sync().unwrap();
+    }
+    storage.compact().unwrap();
+    check_storage(&storage, &expected, num_of_keys);
+    assert!(sst_size(dir.path()) < size_before / 4);
+}
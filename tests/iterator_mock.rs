@@ -33,4 +33,31 @@ impl StorageIterator for MockIterator {
         }
         Ok(())
     }
+
+    // moving before the first entry makes the iterator invalid too
+    fn prev(&mut self) -> Result<()> {
+        self.index = self.index.checked_sub(1).unwrap_or(self.data.len());
+        Ok(())
+    }
+
+    fn seek_to_first(&mut self) -> Result<()> {
+        self.index = 0;
+        Ok(())
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        self.index = self.data.len().checked_sub(1).unwrap_or(self.data.len());
+        Ok(())
+    }
+
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        self.index = self.data.partition_point(|(k, _)| &k[..] < key);
+        Ok(())
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()> {
+        let idx = self.data.partition_point(|(k, _)| &k[..] <= key);
+        self.index = idx.checked_sub(1).unwrap_or(self.data.len());
+        Ok(())
+    }
 }
\ No newline at end of file
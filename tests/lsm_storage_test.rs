@@ -1,10 +1,22 @@
 use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 use bytes::Bytes;
-use parking_lot::RwLock;
 use tempfile::tempdir;
+use lsm::clock::Clock;
+use lsm::compact::{CompactionOptions, LeveledCompactionOptions};
+use lsm::comparator::Comparator;
 use lsm::iterators::StorageIterator;
-use lsm::lsm_storage::LsmStorage;
+use lsm::key::ValueType;
+use lsm::lsm_storage::{LsmStorage, LsmStorageOptions};
+use lsm::merge_operator::MergeOperator;
+use lsm::prefix_extractor::FixedPrefixExtractor;
+use lsm::table::{FileObject, SsTable};
+use lsm::wal::WalSyncPolicy;
+use lsm::write_batch::WriteBatch;
+use lsm::write_stall::{WriteStallCause, WriteStallStatus};
 
 fn as_bytes(x: &[u8]) -> Bytes {
     Bytes::copy_from_slice(x)
@@ -180,3 +192,1666 @@ fn test_storage_scan_memtable_2_after_sync() {
         vec![(Bytes::from("2"), Bytes::from("2333"))],
     );
 }
+
+#[test]
+fn test_storage_recover_from_wal() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    storage.put(b"2", b"2333").unwrap();
+    storage.put(b"3", b"23333").unwrap();
+    storage.delete(b"2").unwrap();
+    drop(storage);
+
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"233");
+    assert!(storage.get(b"2").unwrap().is_none());
+    assert_eq!(&storage.get(b"3").unwrap().unwrap()[..], b"23333");
+    storage.put(b"4", b"233333").unwrap();
+    drop(storage);
+
+    let storage = LsmStorage::open(&dir).unwrap();
+    check_iter_result(
+        storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![
+            (Bytes::from("1"), Bytes::from("233")),
+            (Bytes::from("3"), Bytes::from("23333")),
+            (Bytes::from("4"), Bytes::from("233333")),
+        ],
+    );
+    storage.sync().unwrap();
+    assert_eq!(&storage.get(b"4").unwrap().unwrap()[..], b"233333");
+}
+
+#[test]
+fn test_storage_recover_from_torn_wal() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        wal_sync_policy: WalSyncPolicy::EveryWrite,
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    storage.put(b"2", b"2333").unwrap();
+    drop(storage);
+
+    // simulate a crash in the middle of appending the last record
+    let wal_path = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().unwrap() == "wal")
+        .unwrap();
+    let len = std::fs::metadata(&wal_path).unwrap().len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&wal_path)
+        .unwrap()
+        .set_len(len - 3)
+        .unwrap();
+
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"233");
+    assert!(storage.get(b"2").unwrap().is_none());
+}
+
+#[test]
+fn test_storage_recover_from_corrupted_wal() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        wal_sync_policy: WalSyncPolicy::EveryWrite,
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    storage.put(b"2", b"2333").unwrap();
+    storage.put(b"3", b"23333").unwrap();
+    drop(storage);
+
+    let wal_path = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().unwrap() == "wal")
+        .unwrap();
+    let data = std::fs::read(&wal_path).unwrap();
+    // a bad checksum in the middle is not a torn write, the records after it are kept
+    let mut corrupted = data.clone();
+    corrupted[10] ^= 0xff;
+    std::fs::write(&wal_path, &corrupted).unwrap();
+    let err = LsmStorage::open_with_options(&dir, options.clone())
+        .err()
+        .unwrap();
+    assert!(
+        format!("{:#}", err).contains("checksum mismatch"),
+        "{:#}",
+        err
+    );
+    assert_eq!(std::fs::read(&wal_path).unwrap(), corrupted);
+
+    // the length of the first record runs past the end, the records after it are not torn
+    let mut corrupted = data.clone();
+    corrupted[1] = 0xff;
+    std::fs::write(&wal_path, &corrupted).unwrap();
+    let err = LsmStorage::open_with_options(&dir, options.clone())
+        .err()
+        .unwrap();
+    assert!(
+        format!("{:#}", err).contains("a valid record follows"),
+        "{:#}",
+        err
+    );
+    assert_eq!(std::fs::read(&wal_path).unwrap(), corrupted);
+
+    // an unknown value type in a record with a valid checksum
+    let mut corrupted = data.clone();
+    let body_len = u32::from_be_bytes(corrupted[..4].try_into().unwrap()) as usize;
+    // body length, column family, key length, then the key "1" and its trailer
+    corrupted[4 + 4 + 4 + 1 + 7] = 0xff;
+    let checksum = crc32fast::hash(&corrupted[4..(4 + body_len)]);
+    corrupted[(4 + body_len)..(4 + body_len + 4)].copy_from_slice(&checksum.to_be_bytes());
+    std::fs::write(&wal_path, &corrupted).unwrap();
+    let err = LsmStorage::open_with_options(&dir, options.clone())
+        .err()
+        .unwrap();
+    assert!(
+        format!("{:#}", err).contains("unknown value type 255"),
+        "{:#}",
+        err
+    );
+
+    // the value "233" of a `PutWithTtl` version in a record with a valid checksum
+    corrupted[4 + 4 + 4 + 1 + 7] = ValueType::PutWithTtl as u8;
+    let checksum = crc32fast::hash(&corrupted[4..(4 + body_len)]);
+    corrupted[(4 + body_len)..(4 + body_len + 4)].copy_from_slice(&checksum.to_be_bytes());
+    std::fs::write(&wal_path, &corrupted).unwrap();
+    let err = LsmStorage::open_with_options(&dir, options.clone())
+        .err()
+        .unwrap();
+    assert!(
+        format!("{:#}", err).contains("too short to have an expiry time"),
+        "{:#}",
+        err
+    );
+
+    // a bad checksum of the last record is a torn write
+    let mut corrupted = data;
+    let len = corrupted.len();
+    corrupted[len - 6] ^= 0xff;
+    std::fs::write(&wal_path, &corrupted).unwrap();
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"233");
+    assert_eq!(&storage.get(b"2").unwrap().unwrap()[..], b"2333");
+    assert!(storage.get(b"3").unwrap().is_none());
+}
+
+#[test]
+fn test_storage_recover_from_manifest() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    storage.put(b"2", b"2333").unwrap();
+    storage.sync().unwrap();
+    storage.put(b"3", b"23333").unwrap();
+    storage.sync().unwrap();
+    storage.put(b"4", b"233333").unwrap();
+    drop(storage);
+
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.put(b"5", b"2333333").unwrap();
+    // the new SST must not overwrite the ones written before the restart
+    storage.sync().unwrap();
+    drop(storage);
+
+    let storage = LsmStorage::open(&dir).unwrap();
+    check_iter_result(
+        storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![
+            (Bytes::from("1"), Bytes::from("233")),
+            (Bytes::from("2"), Bytes::from("2333")),
+            (Bytes::from("3"), Bytes::from("23333")),
+            (Bytes::from("4"), Bytes::from("233333")),
+            (Bytes::from("5"), Bytes::from("2333333")),
+        ],
+    );
+}
+
+#[test]
+fn test_storage_delete_orphan_ssts() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    storage.sync().unwrap();
+    drop(storage);
+
+    // left by a flush or a compaction that crashed before recording them in the manifest
+    let sst_path = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().unwrap() == "sst")
+        .unwrap();
+    std::fs::copy(&sst_path, dir.path().join("99999.sst")).unwrap();
+    std::fs::write(dir.path().join("99998.sst"), b"half written").unwrap();
+    assert_eq!(num_of_files(dir.path(), "sst"), 3);
+    // left by an SST build or a manifest rollover that crashed before the rename
+    std::fs::write(dir.path().join("99997.tmp"), b"half written").unwrap();
+    std::fs::write(dir.path().join("MANIFEST.tmp"), b"half written").unwrap();
+    // only files are removed
+    std::fs::create_dir(dir.path().join("99996.tmp")).unwrap();
+
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert_eq!(num_of_files(dir.path(), "sst"), 1);
+    assert!(sst_path.exists());
+    assert_eq!(num_of_files(dir.path(), "tmp"), 1);
+    assert!(dir.path().join("99996.tmp").is_dir());
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"233");
+}
+
+#[test]
+fn test_storage_recover_from_corrupted_manifest() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    for i in 0..4 {
+        storage.put(format!("{}", i).as_bytes(), b"233").unwrap();
+        storage.sync().unwrap();
+    }
+    drop(storage);
+    assert_eq!(num_of_files(dir.path(), "sst"), 4);
+
+    let manifest_path = dir.path().join("MANIFEST");
+    let data = std::fs::read(&manifest_path).unwrap();
+    // a bad checksum in the middle
+    let mut bad_checksum = data.clone();
+    bad_checksum[data.len() / 2] ^= 0xff;
+    // the length of the first record runs past the end
+    let mut bad_length = data.clone();
+    bad_length[4] = 0xff;
+    for corrupted in [bad_checksum, bad_length] {
+        std::fs::write(&manifest_path, &corrupted).unwrap();
+        let err = LsmStorage::open(&dir).err().unwrap();
+        assert!(format!("{:#}", err).contains("manifest"), "{:#}", err);
+        assert_eq!(std::fs::read(&manifest_path).unwrap(), corrupted);
+        assert_eq!(num_of_files(dir.path(), "sst"), 4);
+    }
+
+    // a torn record at the tail is dropped, no SST is deleted after it
+    std::fs::write(&manifest_path, &data[..(data.len() - 2)]).unwrap();
+    drop(LsmStorage::open(&dir).unwrap());
+    assert!(std::fs::metadata(&manifest_path).unwrap().len() < data.len() as u64 - 2);
+    assert_eq!(num_of_files(dir.path(), "sst"), 4);
+
+    std::fs::write(&manifest_path, &data).unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    for i in 0..4 {
+        assert_eq!(
+            &storage.get(format!("{}", i).as_bytes()).unwrap().unwrap()[..],
+            b"233"
+        );
+    }
+}
+
+#[test]
+fn test_storage_manifest_rollover() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        max_manifest_size: 256,
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+    for i in 0..20 {
+        storage.put(format!("{:02}", i).as_bytes(), b"233").unwrap();
+        storage.sync().unwrap();
+    }
+    drop(storage);
+    assert!(
+        std::fs::metadata(dir.path().join("MANIFEST"))
+            .unwrap()
+            .len()
+            <= 512
+    );
+
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    for i in 0..20 {
+        assert_eq!(iter.key(), format!("{:02}", i).as_bytes());
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_storage_get_missing_key_after_sync() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    storage.put(b"3", b"23333").unwrap();
+    storage.sync().unwrap();
+    storage.put(b"5", b"2333333").unwrap();
+    storage.sync().unwrap();
+    assert!(storage.get(b"0").unwrap().is_none());
+    assert!(storage.get(b"2").unwrap().is_none());
+    assert!(storage.get(b"4").unwrap().is_none());
+    assert!(storage.get(b"6").unwrap().is_none());
+    assert_eq!(&storage.get(b"3").unwrap().unwrap()[..], b"23333");
+}
+
+#[test]
+fn test_storage_get_deleted_key_after_sync() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    storage.put(b"2", b"2333").unwrap();
+    storage.sync().unwrap();
+    storage.delete(b"1").unwrap();
+    storage.sync().unwrap();
+    assert!(storage.get(b"1").unwrap().is_none());
+    assert_eq!(&storage.get(b"2").unwrap().unwrap()[..], b"2333");
+    storage.put(b"1", b"23").unwrap();
+    storage.sync().unwrap();
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"23");
+}
+
+fn num_of_files(path: &std::path::Path, extension: &str) -> usize {
+    std::fs::read_dir(path)
+        .unwrap()
+        .filter(|entry| {
+            entry
+                .as_ref()
+                .unwrap()
+                .path()
+                .extension()
+                .unwrap_or_default()
+                == extension
+        })
+        .count()
+}
+
+#[test]
+fn test_storage_freeze_and_flush_by_size() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        write_buffer_size: 1024,
+        compaction_options: CompactionOptions::NoCompaction,
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+    for i in 0..1000 {
+        storage
+            .put(format!("{:04}", i).as_bytes(), b"2333333333")
+            .unwrap();
+    }
+    // wait for the flush thread, only the current memtable is left
+    for _ in 0..100 {
+        if num_of_files(dir.path(), "wal") == 1 {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    assert_eq!(num_of_files(dir.path(), "wal"), 1);
+    assert!(num_of_files(dir.path(), "sst") > 1);
+    for i in 0..1000 {
+        assert_eq!(
+            &storage
+                .get(format!("{:04}", i).as_bytes())
+                .unwrap()
+                .unwrap()[..],
+            b"2333333333"
+        );
+    }
+    drop(storage);
+
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    for i in 0..1000 {
+        assert_eq!(iter.key(), format!("{:04}", i).as_bytes());
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_storage_write_stall_by_l0_file_count() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        level0_slowdown_writes_trigger: 2,
+        level0_stop_writes_trigger: 3,
+        compaction_options: CompactionOptions::Leveled(LeveledCompactionOptions {
+            level0_file_num_compaction_trigger: 3,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    assert_eq!(storage.write_stall_status(), WriteStallStatus::Normal);
+    storage.put(b"1", b"233").unwrap();
+    storage.sync().unwrap();
+    storage.put(b"2", b"2333").unwrap();
+    storage.sync().unwrap();
+    assert_eq!(
+        storage.write_stall_status(),
+        WriteStallStatus::Delayed(WriteStallCause::L0FileCount)
+    );
+    // delayed writes still go through
+    storage.put(b"3", b"23333").unwrap();
+    storage.sync().unwrap();
+    // writes are stopped until the compaction thread compacts L0
+    storage.put(b"4", b"233333").unwrap();
+    assert_eq!(storage.write_stall_status(), WriteStallStatus::Normal);
+    for (key, value) in [
+        (b"1", &b"233"[..]),
+        (b"2", b"2333"),
+        (b"3", b"23333"),
+        (b"4", b"233333"),
+    ] {
+        assert_eq!(&storage.get(key).unwrap().unwrap()[..], value);
+    }
+}
+
+#[test]
+fn test_storage_write_batch() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    storage.put(b"2", b"2333").unwrap();
+    storage.sync().unwrap();
+    storage.put(b"3", b"23333").unwrap();
+    storage.put(b"5", b"2333333").unwrap();
+
+    let mut batch = WriteBatch::new();
+    batch
+        .put(b"0", b"23")
+        .put(b"4", b"233333")
+        .delete(b"1")
+        .delete_range(b"2", b"5")
+        .put(b"3", b"233")
+        .put(b"6", b"23333333");
+    storage.write(&batch).unwrap();
+    check_iter_result(
+        storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![
+            (Bytes::from("0"), Bytes::from("23")),
+            (Bytes::from("3"), Bytes::from("233")),
+            (Bytes::from("5"), Bytes::from("2333333")),
+            (Bytes::from("6"), Bytes::from("23333333")),
+        ],
+    );
+    assert!(storage.get(b"2").unwrap().is_none());
+    assert!(storage.get(b"4").unwrap().is_none());
+    drop(storage);
+
+    let storage = LsmStorage::open(&dir).unwrap();
+    check_iter_result(
+        storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![
+            (Bytes::from("0"), Bytes::from("23")),
+            (Bytes::from("3"), Bytes::from("233")),
+            (Bytes::from("5"), Bytes::from("2333333")),
+            (Bytes::from("6"), Bytes::from("23333333")),
+        ],
+    );
+}
+
+#[test]
+fn test_storage_recover_torn_write_batch() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    let mut batch = WriteBatch::new();
+    batch.put(b"2", b"2333").put(b"3", b"23333").delete(b"1");
+    storage.write(&batch).unwrap();
+    drop(storage);
+
+    // the batch is one WAL record, a crash in the middle of appending it loses all of it
+    let wal_path = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().unwrap() == "wal")
+        .unwrap();
+    let len = std::fs::metadata(&wal_path).unwrap().len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&wal_path)
+        .unwrap()
+        .set_len(len - 3)
+        .unwrap();
+
+    let storage = LsmStorage::open(&dir).unwrap();
+    check_iter_result(
+        storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![(Bytes::from("1"), Bytes::from("233"))],
+    );
+}
+
+#[test]
+fn test_storage_snapshot() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    storage.put(b"2", b"2333").unwrap();
+    storage.sync().unwrap();
+    storage.put(b"3", b"23333").unwrap();
+    let snapshot = storage.snapshot();
+
+    storage.put(b"1", b"2").unwrap();
+    storage.delete(b"2").unwrap();
+    storage.delete(b"3").unwrap();
+    storage.put(b"4", b"233333").unwrap();
+    for _ in 0..2 {
+        assert_eq!(&snapshot.get(b"1").unwrap().unwrap()[..], b"233");
+        assert_eq!(&snapshot.get(b"2").unwrap().unwrap()[..], b"2333");
+        assert_eq!(&snapshot.get(b"3").unwrap().unwrap()[..], b"23333");
+        assert!(snapshot.get(b"4").unwrap().is_none());
+        check_iter_result(
+            snapshot
+                .scan(Bound::Excluded(b"1"), Bound::Unbounded)
+                .unwrap(),
+            vec![
+                (Bytes::from("2"), Bytes::from("2333")),
+                (Bytes::from("3"), Bytes::from("23333")),
+            ],
+        );
+        check_iter_result(
+            storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+            vec![
+                (Bytes::from("1"), Bytes::from("2")),
+                (Bytes::from("4"), Bytes::from("233333")),
+            ],
+        );
+        // the snapshot keeps seeing the same data after the memtable is flushed
+        storage.sync().unwrap();
+    }
+
+    // a batch is visible to a snapshot as a whole
+    let mut batch = WriteBatch::new();
+    batch.put(b"5", b"2333333").delete(b"1");
+    storage.write(&batch).unwrap();
+    let after_batch = storage.snapshot();
+    assert_eq!(after_batch.seq(), snapshot.seq() + 5);
+    assert!(after_batch.get(b"1").unwrap().is_none());
+    assert_eq!(&after_batch.get(b"5").unwrap().unwrap()[..], b"2333333");
+}
+
+#[test]
+fn test_storage_recover_sequence_number() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    storage.sync().unwrap();
+    storage.put(b"2", b"2333").unwrap();
+    let seq = storage.snapshot().seq();
+    drop(storage);
+
+    // new writes must be newer than the ones in the SSTs and the recovered WAL
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert_eq!(storage.snapshot().seq(), seq);
+    storage.put(b"1", b"23").unwrap();
+    storage.put(b"2", b"23").unwrap();
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"23");
+    assert_eq!(&storage.get(b"2").unwrap().unwrap()[..], b"23");
+}
+
+#[test]
+fn test_storage_delete_range() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    for key in [b"1", b"2", b"3", b"4", b"5"] {
+        storage.put(key, b"233").unwrap();
+    }
+    storage.sync().unwrap();
+    storage.put(b"3", b"2333").unwrap();
+    let snapshot = storage.snapshot();
+    // deletes the keys both in the memtable and in the SST
+    storage.delete_range(b"2", b"4").unwrap();
+    storage.put(b"2", b"23").unwrap();
+    for _ in 0..2 {
+        assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"233");
+        assert_eq!(&storage.get(b"2").unwrap().unwrap()[..], b"23");
+        assert!(storage.get(b"3").unwrap().is_none());
+        assert_eq!(&storage.get(b"4").unwrap().unwrap()[..], b"233");
+        check_iter_result(
+            storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+            vec![
+                (Bytes::from("1"), Bytes::from("233")),
+                (Bytes::from("2"), Bytes::from("23")),
+                (Bytes::from("4"), Bytes::from("233")),
+                (Bytes::from("5"), Bytes::from("233")),
+            ],
+        );
+        check_iter_result(
+            storage
+                .scan(Bound::Excluded(b"2"), Bound::Included(b"4"))
+                .unwrap(),
+            vec![(Bytes::from("4"), Bytes::from("233"))],
+        );
+        // the snapshot is taken before the range is deleted
+        assert_eq!(&snapshot.get(b"3").unwrap().unwrap()[..], b"2333");
+        check_iter_result(
+            snapshot
+                .scan(Bound::Included(b"2"), Bound::Excluded(b"4"))
+                .unwrap(),
+            vec![
+                (Bytes::from("2"), Bytes::from("233")),
+                (Bytes::from("3"), Bytes::from("2333")),
+            ],
+        );
+        // the range tombstone is flushed to the SST
+        storage.sync().unwrap();
+    }
+}
+
+#[test]
+fn test_storage_recover_delete_range() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    for key in [b"1", b"2", b"3", b"4", b"5"] {
+        storage.put(key, b"233").unwrap();
+    }
+    storage.sync().unwrap();
+    storage.delete_range(b"1", b"3").unwrap();
+    drop(storage);
+
+    // recovered from the WAL
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert!(storage.get(b"1").unwrap().is_none());
+    assert!(storage.get(b"2").unwrap().is_none());
+    storage.sync().unwrap();
+    storage.delete_range(b"4", b"6").unwrap();
+    storage.sync().unwrap();
+    drop(storage);
+
+    // recovered from the SSTs
+    let storage = LsmStorage::open(&dir).unwrap();
+    check_iter_result(
+        storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![(Bytes::from("3"), Bytes::from("233"))],
+    );
+}
+
+fn check_iter_result_backward(iter: &mut impl StorageIterator, expected: Vec<(Bytes, Bytes)>) {
+    for (k, v) in expected {
+        assert!(iter.is_valid());
+        assert_eq!(
+            k,
+            iter.key(),
+            "expected key: {:?}, actual key: {:?}",
+            k,
+            as_bytes(iter.key())
+        );
+        assert_eq!(
+            v,
+            iter.value(),
+            "expected value: {:?}, actual value: {:?}",
+            v,
+            as_bytes(iter.value())
+        );
+        iter.prev().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_storage_scan_rev() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    storage.put(b"2", b"2333").unwrap();
+    storage.put(b"3", b"23333").unwrap();
+    storage.sync().unwrap();
+    storage.put(b"4", b"233333").unwrap();
+    storage.put(b"5", b"2333333").unwrap();
+    storage.sync().unwrap();
+    let snapshot = storage.snapshot();
+    storage.put(b"1", b"2").unwrap();
+    storage.delete(b"2").unwrap();
+    storage.put(b"6", b"23").unwrap();
+    storage.delete_range(b"4", b"5").unwrap();
+
+    for _ in 0..2 {
+        check_iter_result_backward(
+            &mut storage
+                .scan_rev(Bound::Unbounded, Bound::Unbounded)
+                .unwrap(),
+            vec![
+                (Bytes::from("6"), Bytes::from("23")),
+                (Bytes::from("5"), Bytes::from("2333333")),
+                (Bytes::from("3"), Bytes::from("23333")),
+                (Bytes::from("1"), Bytes::from("2")),
+            ],
+        );
+        check_iter_result_backward(
+            &mut storage
+                .scan_rev(Bound::Excluded(b"1"), Bound::Excluded(b"5"))
+                .unwrap(),
+            vec![(Bytes::from("3"), Bytes::from("23333"))],
+        );
+        check_iter_result_backward(
+            &mut storage
+                .scan_rev(Bound::Included(b"1"), Bound::Included(b"5"))
+                .unwrap(),
+            vec![
+                (Bytes::from("5"), Bytes::from("2333333")),
+                (Bytes::from("3"), Bytes::from("23333")),
+                (Bytes::from("1"), Bytes::from("2")),
+            ],
+        );
+        check_iter_result_backward(
+            &mut snapshot
+                .scan_rev(Bound::Unbounded, Bound::Excluded(b"5"))
+                .unwrap(),
+            vec![
+                (Bytes::from("4"), Bytes::from("233333")),
+                (Bytes::from("3"), Bytes::from("23333")),
+                (Bytes::from("2"), Bytes::from("2333")),
+                (Bytes::from("1"), Bytes::from("233")),
+            ],
+        );
+
+        // the latest keys before a key, then forward again
+        let mut iter = storage
+            .scan_rev(Bound::Unbounded, Bound::Unbounded)
+            .unwrap();
+        iter.seek_for_prev(b"4").unwrap();
+        assert_eq!(iter.key(), b"3");
+        iter.prev().unwrap();
+        assert_eq!(iter.key(), b"1");
+        iter.next().unwrap();
+        check_iter_result(
+            iter,
+            vec![
+                (Bytes::from("3"), Bytes::from("23333")),
+                (Bytes::from("5"), Bytes::from("2333333")),
+                (Bytes::from("6"), Bytes::from("23")),
+            ],
+        );
+        let mut iter = storage
+            .scan(Bound::Unbounded, Bound::Included(b"5"))
+            .unwrap();
+        iter.next().unwrap();
+        assert_eq!(iter.key(), b"3");
+        iter.prev().unwrap();
+        assert_eq!(iter.key(), b"1");
+        iter.prev().unwrap();
+        assert!(!iter.is_valid());
+        iter.seek_to_last().unwrap();
+        assert_eq!(iter.key(), b"5");
+
+        storage.sync().unwrap();
+    }
+}
+
+#[test]
+fn test_storage_scan_seek() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    for idx in 0..10 {
+        storage.put(format!("{}", idx).as_bytes(), b"233").unwrap();
+        if idx % 3 == 0 {
+            storage.sync().unwrap();
+        }
+    }
+    storage.delete(b"4").unwrap();
+    let mut iter = storage
+        .scan(Bound::Excluded(b"1"), Bound::Excluded(b"8"))
+        .unwrap();
+    // written after the iterator is created, it is never visible to the iterator
+    storage.put(b"5", b"2").unwrap();
+    storage.delete(b"6").unwrap();
+    storage.sync().unwrap();
+
+    // paginate without creating a new iterator
+    let mut pages = Vec::new();
+    let mut start: Option<Bytes> = None;
+    loop {
+        match &start {
+            None => iter.seek_to_first().unwrap(),
+            Some(key) => iter.seek(key).unwrap(),
+        }
+        let mut page = Vec::new();
+        while iter.is_valid() && page.len() < 2 {
+            page.push((as_bytes(iter.key()), as_bytes(iter.value())));
+            iter.next().unwrap();
+        }
+        pages.push(page);
+        if !iter.is_valid() {
+            break;
+        }
+        start = Some(as_bytes(iter.key()));
+    }
+    assert_eq!(
+        pages,
+        vec![
+            vec![
+                (Bytes::from("2"), Bytes::from("233")),
+                (Bytes::from("3"), Bytes::from("233"))
+            ],
+            vec![
+                (Bytes::from("5"), Bytes::from("233")),
+                (Bytes::from("6"), Bytes::from("233"))
+            ],
+            vec![(Bytes::from("7"), Bytes::from("233"))],
+        ]
+    );
+
+    // the bounds are kept
+    iter.seek(b"0").unwrap();
+    assert_eq!(iter.key(), b"2");
+    iter.seek(b"4").unwrap();
+    assert_eq!(iter.key(), b"5");
+    iter.seek(b"8").unwrap();
+    assert!(!iter.is_valid());
+    iter.seek_to_first().unwrap();
+    check_iter_result(
+        iter,
+        vec![
+            (Bytes::from("2"), Bytes::from("233")),
+            (Bytes::from("3"), Bytes::from("233")),
+            (Bytes::from("5"), Bytes::from("233")),
+            (Bytes::from("6"), Bytes::from("233")),
+            (Bytes::from("7"), Bytes::from("233")),
+        ],
+    );
+}
+
+/// Joins the value and the operands of a key with commas.
+struct AppendOperator;
+
+impl MergeOperator for AppendOperator {
+    fn name(&self) -> &str {
+        "append"
+    }
+
+    fn full_merge(
+        &self,
+        _key: &[u8],
+        existing_value: Option<&[u8]>,
+        operands: &[&[u8]],
+    ) -> anyhow::Result<Bytes> {
+        let parts: Vec<&[u8]> = existing_value
+            .into_iter()
+            .chain(operands.iter().copied())
+            .collect();
+        Ok(Bytes::from(parts.join(&b","[..])))
+    }
+
+    fn partial_merge(&self, _key: &[u8], operands: &[&[u8]]) -> Option<Bytes> {
+        Some(Bytes::from(operands.join(&b","[..])))
+    }
+}
+
+fn merge_options() -> LsmStorageOptions {
+    LsmStorageOptions {
+        merge_operator: Some(Arc::new(AppendOperator)),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_storage_merge() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open_with_options(&dir, merge_options()).unwrap();
+    storage.put(b"1", b"a").unwrap();
+    storage.merge(b"1", b"b").unwrap();
+    storage.merge(b"2", b"x").unwrap();
+    let snapshot = storage.snapshot();
+    storage.merge(b"1", b"c").unwrap();
+    storage.merge(b"2", b"y").unwrap();
+    // a delete or a range tombstone ends the chain of operands
+    storage.put(b"3", b"z").unwrap();
+    storage.delete(b"3").unwrap();
+    storage.merge(b"3", b"y").unwrap();
+    storage.put(b"4", b"old").unwrap();
+    storage.delete_range(b"4", b"5").unwrap();
+    storage.merge(b"4", b"n").unwrap();
+    let mut batch = WriteBatch::new();
+    batch
+        .merge(b"5", b"p")
+        .merge(b"5", b"q")
+        .put(b"6", b"v")
+        .merge(b"6", b"w");
+    storage.write(&batch).unwrap();
+
+    let expected = vec![
+        (Bytes::from("1"), Bytes::from("a,b,c")),
+        (Bytes::from("2"), Bytes::from("x,y")),
+        (Bytes::from("3"), Bytes::from("y")),
+        (Bytes::from("4"), Bytes::from("n")),
+        (Bytes::from("5"), Bytes::from("p,q")),
+        (Bytes::from("6"), Bytes::from("v,w")),
+    ];
+    for _ in 0..2 {
+        for (key, value) in &expected {
+            assert_eq!(&storage.get(key).unwrap().unwrap(), value);
+        }
+        check_iter_result(
+            storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+            expected.clone(),
+        );
+        let mut iter = storage
+            .scan_rev(Bound::Unbounded, Bound::Unbounded)
+            .unwrap();
+        check_iter_result_backward(&mut iter, expected.iter().rev().cloned().collect());
+        // change direction on a resolved operand
+        let mut iter = storage
+            .scan(Bound::Included(b"2"), Bound::Unbounded)
+            .unwrap();
+        iter.next().unwrap();
+        iter.prev().unwrap();
+        assert_eq!(iter.key(), b"2");
+        assert_eq!(iter.value(), b"x,y");
+        iter.next().unwrap();
+        assert_eq!(iter.value(), b"y");
+        // the snapshot sees the operands written before it
+        assert_eq!(&snapshot.get(b"1").unwrap().unwrap()[..], b"a,b");
+        check_iter_result(
+            snapshot.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+            vec![
+                (Bytes::from("1"), Bytes::from("a,b")),
+                (Bytes::from("2"), Bytes::from("x")),
+            ],
+        );
+        // the operands are combined by the flush
+        storage.sync().unwrap();
+    }
+    storage.merge(b"1", b"d").unwrap();
+    drop(snapshot);
+    drop(storage);
+
+    let storage = LsmStorage::open_with_options(&dir, merge_options()).unwrap();
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"a,b,c,d");
+    assert_eq!(&storage.get(b"2").unwrap().unwrap()[..], b"x,y");
+}
+
+#[test]
+fn test_storage_merge_without_operator() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert!(storage.merge(b"1", b"a").is_err());
+    let mut batch = WriteBatch::new();
+    batch.put(b"1", b"a").merge(b"1", b"b");
+    assert!(storage.write(&batch).is_err());
+    assert!(storage.get(b"1").unwrap().is_none());
+    // an operand alone is not written either, neither are the other entries of the batch
+    let mut batch = WriteBatch::new();
+    batch.put(b"1", b"a").merge(b"2", b"b");
+    let err = storage.write(&batch).err().unwrap();
+    assert!(err.to_string().contains("no merge operator"), "{}", err);
+    assert!(storage.get(b"1").unwrap().is_none());
+    assert!(storage.get(b"2").unwrap().is_none());
+    drop(storage);
+
+    // nothing is in the WAL
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert!(storage.get(b"1").unwrap().is_none());
+    assert!(storage.get(b"2").unwrap().is_none());
+}
+
+/// A clock moved forward by the test, in milliseconds.
+#[derive(Default)]
+struct ManualClock(AtomicU64);
+
+impl ManualClock {
+    fn advance(&self, duration: Duration) {
+        self.0
+            .fetch_add(duration.as_millis() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+#[test]
+fn test_storage_sst_creation_time() {
+    let dir = tempdir().unwrap();
+    let clock = Arc::new(ManualClock::default());
+    clock.advance(Duration::from_secs(233));
+    let options = LsmStorageOptions {
+        clock: clock.clone(),
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    storage.sync().unwrap();
+    let sst_path = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().unwrap() == "sst")
+        .unwrap();
+    let sst = SsTable::open(0, None, FileObject::open(&sst_path).unwrap()).unwrap();
+    assert_eq!(sst.properties().unwrap().creation_time, 233_000);
+}
+
+#[test]
+fn test_storage_put_with_ttl() {
+    let dir = tempdir().unwrap();
+    let clock = Arc::new(ManualClock::default());
+    let options = || LsmStorageOptions {
+        clock: clock.clone(),
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options()).unwrap();
+    storage
+        .put_with_ttl(b"1", b"233", Duration::from_secs(10))
+        .unwrap();
+    storage.put(b"2", b"2333").unwrap();
+    // the older value doesn't come back once the new one expires
+    storage.put(b"3", b"23").unwrap();
+    storage
+        .put_with_ttl(b"3", b"23333", Duration::from_secs(20))
+        .unwrap();
+    storage
+        .put_with_ttl(b"4", b"2", Duration::from_secs(20))
+        .unwrap();
+    storage.put(b"4", b"23").unwrap();
+    storage.sync().unwrap();
+    storage
+        .put_with_ttl(b"5", b"233333", Duration::from_secs(10))
+        .unwrap();
+
+    clock.advance(Duration::from_secs(5));
+    let all = vec![
+        (Bytes::from("1"), Bytes::from("233")),
+        (Bytes::from("2"), Bytes::from("2333")),
+        (Bytes::from("3"), Bytes::from("23333")),
+        (Bytes::from("4"), Bytes::from("23")),
+        (Bytes::from("5"), Bytes::from("233333")),
+    ];
+    for (key, value) in &all {
+        assert_eq!(&storage.get(key).unwrap().unwrap(), value);
+    }
+    check_iter_result(
+        storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        all,
+    );
+
+    // expired values are invisible right away
+    clock.advance(Duration::from_secs(5));
+    assert!(storage.get(b"1").unwrap().is_none());
+    assert!(storage.get(b"5").unwrap().is_none());
+    let expected = vec![
+        (Bytes::from("2"), Bytes::from("2333")),
+        (Bytes::from("3"), Bytes::from("23333")),
+        (Bytes::from("4"), Bytes::from("23")),
+    ];
+    check_iter_result(
+        storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        expected.clone(),
+    );
+    let mut iter = storage
+        .scan_rev(Bound::Unbounded, Bound::Unbounded)
+        .unwrap();
+    check_iter_result_backward(&mut iter, expected.into_iter().rev().collect());
+    drop(storage);
+
+    // the expiry time is recovered from the WAL and the SSTs
+    let storage = LsmStorage::open_with_options(&dir, options()).unwrap();
+    assert!(storage.get(b"1").unwrap().is_none());
+    assert_eq!(&storage.get(b"3").unwrap().unwrap()[..], b"23333");
+    clock.advance(Duration::from_secs(10));
+    assert!(storage.get(b"3").unwrap().is_none());
+    check_iter_result(
+        storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![
+            (Bytes::from("2"), Bytes::from("2333")),
+            (Bytes::from("4"), Bytes::from("23")),
+        ],
+    );
+}
+
+#[test]
+fn test_storage_column_families() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    let index = storage
+        .create_column_family("index", LsmStorageOptions::default())
+        .unwrap();
+    assert!(storage
+        .create_column_family("index", LsmStorageOptions::default())
+        .is_err());
+    storage.put(b"1", b"233").unwrap();
+    index.put(b"1", b"2333").unwrap();
+    index.put(b"2", b"23333").unwrap();
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"233");
+    assert_eq!(&index.get(b"1").unwrap().unwrap()[..], b"2333");
+    assert!(storage.get(b"2").unwrap().is_none());
+
+    // one batch spanning both families
+    let mut batch = WriteBatch::new();
+    batch
+        .delete(b"1")
+        .put_cf(&index, b"3", b"233333")
+        .delete_cf(&index, b"2");
+    storage.write(&batch).unwrap();
+    let snapshot = storage.snapshot();
+    assert!(storage.get(b"1").unwrap().is_none());
+    check_iter_result(
+        index.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![
+            (Bytes::from("1"), Bytes::from("2333")),
+            (Bytes::from("3"), Bytes::from("233333")),
+        ],
+    );
+    storage.put(b"4", b"2333333").unwrap();
+    index.put(b"4", b"23333333").unwrap();
+    storage.sync().unwrap();
+    index.put(b"5", b"233333333").unwrap();
+    assert!(snapshot.get_cf(&index, b"4").unwrap().is_none());
+    assert_eq!(
+        &snapshot.get_cf(&index, b"3").unwrap().unwrap()[..],
+        b"233333"
+    );
+    drop(snapshot);
+    drop(index);
+    drop(storage);
+
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert_eq!(
+        storage.column_family_names(),
+        vec!["default".to_string(), "index".to_string()]
+    );
+    let index = storage.column_family("index").unwrap();
+    check_iter_result(
+        storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![(Bytes::from("4"), Bytes::from("2333333"))],
+    );
+    check_iter_result(
+        index.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![
+            (Bytes::from("1"), Bytes::from("2333")),
+            (Bytes::from("3"), Bytes::from("233333")),
+            (Bytes::from("4"), Bytes::from("23333333")),
+            (Bytes::from("5"), Bytes::from("233333333")),
+        ],
+    );
+}
+
+#[test]
+fn test_storage_recover_torn_cross_family_batch() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    let index = storage
+        .create_column_family("index", LsmStorageOptions::default())
+        .unwrap();
+    storage.put(b"1", b"233").unwrap();
+    let mut batch = WriteBatch::new();
+    batch.put(b"2", b"2333").put_cf(&index, b"2", b"23333");
+    storage.write(&batch).unwrap();
+    drop(index);
+    drop(storage);
+
+    // the families share the WAL, a torn batch is lost in all of them
+    let wal_path = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().unwrap() == "wal")
+        .unwrap();
+    let len = std::fs::metadata(&wal_path).unwrap().len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&wal_path)
+        .unwrap()
+        .set_len(len - 3)
+        .unwrap();
+
+    let storage = LsmStorage::open(&dir).unwrap();
+    let index = storage.column_family("index").unwrap();
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"233");
+    assert!(storage.get(b"2").unwrap().is_none());
+    assert!(index.get(b"2").unwrap().is_none());
+}
+
+#[test]
+fn test_storage_drop_column_family() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::NoCompaction,
+        ..Default::default()
+    };
+    let index = storage
+        .create_column_family("index", options.clone())
+        .unwrap();
+    index.put(b"1", b"233").unwrap();
+    storage.sync().unwrap();
+    index.put(b"2", b"2333").unwrap();
+    storage.put(b"1", b"23333").unwrap();
+    assert_eq!(num_of_files(dir.path(), "sst"), 1);
+
+    assert!(storage.drop_column_family("default").is_err());
+    storage.drop_column_family("index").unwrap();
+    assert!(storage.drop_column_family("index").is_err());
+    assert!(storage.column_family("index").is_none());
+    assert_eq!(num_of_files(dir.path(), "sst"), 0);
+    assert!(index.put(b"3", b"233333").is_err());
+    let mut batch = WriteBatch::new();
+    batch.put(b"2", b"2333").put_cf(&index, b"2", b"2333");
+    assert!(storage.write(&batch).is_err());
+    assert!(storage.get(b"2").unwrap().is_none());
+    drop(index);
+    drop(storage);
+
+    // the entries of the dropped family left in the WAL are not recovered
+    let storage = LsmStorage::open(&dir).unwrap();
+    assert_eq!(storage.column_family_names(), vec!["default".to_string()]);
+    let index = storage.create_column_family("index", options).unwrap();
+    assert!(index.get(b"2").unwrap().is_none());
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"23333");
+}
+
+/// Orders 8-byte keys as big-endian signed integers, negative keys come first.
+struct I64Comparator;
+
+impl Comparator for I64Comparator {
+    fn name(&self) -> &str {
+        "test.I64Comparator"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+        let decode = |key: &[u8]| i64::from_be_bytes(key.try_into().unwrap());
+        decode(a).cmp(&decode(b))
+    }
+}
+
+fn int_key(x: i64) -> Bytes {
+    Bytes::copy_from_slice(&x.to_be_bytes())
+}
+
+#[test]
+fn test_storage_comparator() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::Leveled(LeveledCompactionOptions {
+            level0_file_num_compaction_trigger: 2,
+            ..Default::default()
+        }),
+        comparator: Arc::new(I64Comparator),
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+    for x in [3, -1, 0] {
+        storage.put(&int_key(x), b"233").unwrap();
+    }
+    storage.sync().unwrap();
+    for x in [-3, 1, 2] {
+        storage.put(&int_key(x), b"2333").unwrap();
+    }
+    storage.sync().unwrap();
+    storage.compact().unwrap();
+    for x in [-2, 5] {
+        storage.put(&int_key(x), b"23333").unwrap();
+    }
+    // the range crosses zero, it is empty in the bytewise order
+    storage.delete_range(&int_key(-1), &int_key(2)).unwrap();
+    assert!(storage.delete_range(&int_key(2), &int_key(-1)).is_ok());
+
+    let check = |storage: &LsmStorage| {
+        check_iter_result(
+            storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+            vec![
+                (int_key(-3), Bytes::from("2333")),
+                (int_key(-2), Bytes::from("23333")),
+                (int_key(2), Bytes::from("2333")),
+                (int_key(3), Bytes::from("233")),
+                (int_key(5), Bytes::from("23333")),
+            ],
+        );
+        check_iter_result(
+            storage
+                .scan(Bound::Excluded(&int_key(-3)), Bound::Included(&int_key(2)))
+                .unwrap(),
+            vec![
+                (int_key(-2), Bytes::from("23333")),
+                (int_key(2), Bytes::from("2333")),
+            ],
+        );
+        check_iter_result_backward(
+            &mut storage
+                .scan_rev(Bound::Included(&int_key(-3)), Bound::Excluded(&int_key(3)))
+                .unwrap(),
+            vec![
+                (int_key(2), Bytes::from("2333")),
+                (int_key(-2), Bytes::from("23333")),
+                (int_key(-3), Bytes::from("2333")),
+            ],
+        );
+        assert!(storage.get(&int_key(-1)).unwrap().is_none());
+        assert!(storage.get(&int_key(1)).unwrap().is_none());
+        assert_eq!(&storage.get(&int_key(-3)).unwrap().unwrap()[..], b"2333");
+    };
+    check(&storage);
+    storage.sync().unwrap();
+    storage.compact().unwrap();
+    check(&storage);
+    drop(storage);
+
+    // the comparator is recorded, the storage can't be opened with another one
+    let err = LsmStorage::open(&dir).err().unwrap();
+    assert!(err.to_string().contains("test.I64Comparator"), "{}", err);
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    check(&storage);
+}
+
+#[test]
+fn test_storage_range_tombstone_only_sst() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::Leveled(LeveledCompactionOptions {
+            level0_file_num_compaction_trigger: 2,
+            ..Default::default()
+        }),
+        comparator: Arc::new(I64Comparator),
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options.clone()).unwrap();
+    for x in [1, 2, 3, 4] {
+        storage.put(&int_key(x), b"233").unwrap();
+        storage.sync().unwrap();
+    }
+    storage.compact().unwrap();
+    // the range tombstones are kept for the snapshot, they are compacted into an SST of L1
+    // without any point key, next to the one with the keys
+    let snapshot = storage.snapshot();
+    storage.delete_range(&int_key(10), &int_key(20)).unwrap();
+    storage.sync().unwrap();
+    storage.delete_range(&int_key(30), &int_key(40)).unwrap();
+    storage.sync().unwrap();
+    storage.compact().unwrap();
+
+    let check = |storage: &LsmStorage| {
+        for x in [1, 2, 3, 4] {
+            assert_eq!(&storage.get(&int_key(x)).unwrap().unwrap()[..], b"233");
+        }
+        assert!(storage.get(&int_key(15)).unwrap().is_none());
+        check_iter_result(
+            storage
+                .scan(Bound::Included(&int_key(2)), Bound::Unbounded)
+                .unwrap(),
+            vec![
+                (int_key(2), Bytes::from("233")),
+                (int_key(3), Bytes::from("233")),
+                (int_key(4), Bytes::from("233")),
+            ],
+        );
+        check_iter_result_backward(
+            &mut storage
+                .scan_rev(Bound::Unbounded, Bound::Excluded(&int_key(3)))
+                .unwrap(),
+            vec![
+                (int_key(2), Bytes::from("233")),
+                (int_key(1), Bytes::from("233")),
+            ],
+        );
+    };
+    check(&storage);
+    drop(snapshot);
+    drop(storage);
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    check(&storage);
+}
+
+#[test]
+fn test_storage_scan_prefix() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        prefix_extractor: Some(Arc::new(FixedPrefixExtractor::new(3))),
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    storage.put(b"aa1", b"233").unwrap();
+    storage.put(b"ab1", b"233").unwrap();
+    storage.put(b"ab2", b"233").unwrap();
+    storage.put(b"ab", b"233").unwrap();
+    storage.sync().unwrap();
+    storage.put(b"ab3", b"2333").unwrap();
+    storage.put(b"ab2", b"2333").unwrap();
+    storage.put(b"ac1", b"2333").unwrap();
+    storage.sync().unwrap();
+    storage.put(b"ab1", b"23333").unwrap();
+    storage.delete(b"ab3").unwrap();
+    storage.put(b"ad1", b"23333").unwrap();
+
+    let check = |storage: &LsmStorage| {
+        // a shorter prefix than the extracted one scans without the filters
+        check_iter_result(
+            storage.scan_prefix(b"ab").unwrap(),
+            vec![
+                (Bytes::from("ab"), Bytes::from("233")),
+                (Bytes::from("ab1"), Bytes::from("23333")),
+                (Bytes::from("ab2"), Bytes::from("2333")),
+            ],
+        );
+        check_iter_result(
+            storage.scan_prefix(b"ab2").unwrap(),
+            vec![(Bytes::from("ab2"), Bytes::from("2333"))],
+        );
+        check_iter_result(
+            storage.scan_prefix(b"ac1").unwrap(),
+            vec![(Bytes::from("ac1"), Bytes::from("2333"))],
+        );
+        check_iter_result(storage.scan_prefix(b"ab3").unwrap(), vec![]);
+        check_iter_result(storage.scan_prefix(b"ae1").unwrap(), vec![]);
+        check_iter_result(
+            storage.scan_prefix(b"").unwrap(),
+            vec![
+                (Bytes::from("aa1"), Bytes::from("233")),
+                (Bytes::from("ab"), Bytes::from("233")),
+                (Bytes::from("ab1"), Bytes::from("23333")),
+                (Bytes::from("ab2"), Bytes::from("2333")),
+                (Bytes::from("ac1"), Bytes::from("2333")),
+                (Bytes::from("ad1"), Bytes::from("23333")),
+            ],
+        );
+    };
+    check(&storage);
+    // a range tombstone hides the keys of the SSTs even if the memtable has none of the prefix
+    storage.delete_range(b"ac0", b"ac9").unwrap();
+    check_iter_result(storage.scan_prefix(b"ac1").unwrap(), vec![]);
+    storage.put(b"ac1", b"2333").unwrap();
+    storage.sync().unwrap();
+    check(&storage);
+    storage.compact().unwrap();
+    check(&storage);
+}
+
+#[test]
+fn test_storage_scan_prefix_non_bytewise_comparator() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        comparator: Arc::new(I64Comparator),
+        prefix_extractor: Some(Arc::new(FixedPrefixExtractor::new(1))),
+        ..Default::default()
+    };
+    let err = LsmStorage::open_with_options(&dir, options.clone())
+        .err()
+        .unwrap();
+    assert!(
+        err.to_string().contains("doesn't support prefix scans"),
+        "{}",
+        err
+    );
+
+    // the keys starting with 0xff are the negative ones, they aren't contiguous with the others
+    let storage = LsmStorage::open_with_options(
+        &dir,
+        LsmStorageOptions {
+            comparator: Arc::new(I64Comparator),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    storage.put(&int_key(-1), b"233").unwrap();
+    storage.put(&int_key(1), b"233").unwrap();
+    let err = storage.scan_prefix(&[0xff]).err().unwrap();
+    assert!(
+        err.to_string().contains("doesn't support prefix scans"),
+        "{}",
+        err
+    );
+    let err = storage.create_column_family("cf", options).err().unwrap();
+    assert!(
+        err.to_string().contains("doesn't support prefix scans"),
+        "{}",
+        err
+    );
+    assert!(storage.column_family("cf").is_none());
+}
+
+#[test]
+fn test_storage_large_entries() {
+    let dir = tempdir().unwrap();
+    let large_key = |x: u8| Bytes::from(vec![x; 100 << 10]);
+    let large_value = |x: u8| Bytes::from(vec![x; 3 << 20]);
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.put(&large_key(b'a'), &large_value(b'1')).unwrap();
+    storage.put(b"b", &large_value(b'2')).unwrap();
+    storage.put(&large_key(b'c'), b"233").unwrap();
+    storage.sync().unwrap();
+    storage.put(&large_key(b'd'), &large_value(b'3')).unwrap();
+    storage.put(&large_key(b'e'), b"2333").unwrap();
+    storage
+        .delete_range(&large_key(b'e'), &large_key(b'f'))
+        .unwrap();
+    drop(storage);
+
+    let check = |storage: &LsmStorage| {
+        check_iter_result(
+            storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+            vec![
+                (large_key(b'a'), large_value(b'1')),
+                (Bytes::from("b"), large_value(b'2')),
+                (large_key(b'c'), Bytes::from("233")),
+                (large_key(b'd'), large_value(b'3')),
+            ],
+        );
+        assert_eq!(
+            storage.get(&large_key(b'a')).unwrap().unwrap(),
+            large_value(b'1')
+        );
+        assert_eq!(&storage.get(&large_key(b'c')).unwrap().unwrap()[..], b"233");
+        assert!(storage.get(&large_key(b'e')).unwrap().is_none());
+    };
+    // recovered from the WAL
+    let storage = LsmStorage::open(&dir).unwrap();
+    check(&storage);
+    storage.sync().unwrap();
+    check(&storage);
+    storage.compact().unwrap();
+    check(&storage);
+    drop(storage);
+    let storage = LsmStorage::open(&dir).unwrap();
+    check(&storage);
+}
+
+/// Make every flush fail, a directory in place of the temporary file of an SST can't be created.
+fn break_sst_files(dir: &std::path::Path, broken: bool) {
+    for id in 0..200 {
+        let path = dir.join(format!("{:05}.tmp", id));
+        if broken {
+            std::fs::create_dir(path).unwrap();
+        } else {
+            std::fs::remove_dir(path).unwrap();
+        }
+    }
+}
+
+#[test]
+fn test_storage_background_flush_error() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        write_buffer_size: 1024,
+        max_imm_memtables: 1000,
+        compaction_options: CompactionOptions::NoCompaction,
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    break_sst_files(dir.path(), true);
+    // the writes fail once the flush thread fails
+    let mut written = 0;
+    let err = loop {
+        match storage.put(format!("{:04}", written).as_bytes(), b"2333333333") {
+            Ok(()) => written += 1,
+            Err(err) => break err,
+        }
+        assert!(written < 10000, "the flush error is not returned");
+        std::thread::sleep(Duration::from_millis(1));
+    };
+    assert!(
+        err.to_string().contains("background flush failed"),
+        "{:#}",
+        err
+    );
+
+    // the next successful flush clears the error
+    break_sst_files(dir.path(), false);
+    let mut retries = 0;
+    while storage
+        .put(format!("{:04}", written).as_bytes(), b"2333333333")
+        .is_err()
+    {
+        retries += 1;
+        assert!(retries < 100, "the flush error is not cleared");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    for i in 0..=written {
+        assert_eq!(
+            &storage
+                .get(format!("{:04}", i).as_bytes())
+                .unwrap()
+                .unwrap()[..],
+            b"2333333333"
+        );
+    }
+}
+
+#[test]
+fn test_storage_write_stop_on_background_error() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        write_buffer_size: 1024,
+        max_imm_memtables: 1,
+        compaction_options: CompactionOptions::NoCompaction,
+        ..Default::default()
+    };
+    let storage = Arc::new(LsmStorage::open_with_options(&dir, options).unwrap());
+    break_sst_files(dir.path(), true);
+    // the writers stopped by the memtable limit fail instead of waiting for a flush forever
+    let (tx, rx) = std::sync::mpsc::channel();
+    for thread in 0..4 {
+        let storage = storage.clone();
+        let tx = tx.clone();
+        std::thread::spawn(move || {
+            let mut i = 0;
+            let result = loop {
+                if let Err(err) =
+                    storage.put(format!("{}-{:04}", thread, i).as_bytes(), b"2333333333")
+                {
+                    break err;
+                }
+                i += 1;
+            };
+            tx.send(result.to_string()).unwrap();
+        });
+    }
+    for _ in 0..4 {
+        let err = rx
+            .recv_timeout(Duration::from_secs(10))
+            .expect("the writers are stopped forever");
+        assert!(err.contains("background flush failed"), "{}", err);
+    }
+    assert_eq!(
+        storage.write_stall_status(),
+        WriteStallStatus::Stopped(WriteStallCause::MemtableLimit)
+    );
+    break_sst_files(dir.path(), false);
+}
+
+struct FailingOperator;
+
+impl MergeOperator for FailingOperator {
+    fn name(&self) -> &str {
+        "failing"
+    }
+
+    fn full_merge(
+        &self,
+        _key: &[u8],
+        _existing_value: Option<&[u8]>,
+        _operands: &[&[u8]],
+    ) -> anyhow::Result<Bytes> {
+        anyhow::bail!("merge failed")
+    }
+}
+
+#[test]
+fn test_storage_background_compaction_error() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        compaction_options: CompactionOptions::Leveled(LeveledCompactionOptions {
+            level0_file_num_compaction_trigger: 2,
+            ..Default::default()
+        }),
+        merge_operator: Some(Arc::new(FailingOperator)),
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    // the operands are only resolved by the compaction, which keeps failing
+    for operand in [b"1", b"2"] {
+        storage.merge(b"233", operand).unwrap();
+        storage.sync().unwrap();
+    }
+    let mut retries = 0;
+    let err = loop {
+        match storage.put(b"2333", b"23333") {
+            Ok(()) => retries += 1,
+            Err(err) => break err,
+        }
+        assert!(retries < 100, "the compaction error is not returned");
+        std::thread::sleep(Duration::from_millis(50));
+    };
+    assert!(
+        err.to_string().contains("background compaction failed"),
+        "{:#}",
+        err
+    );
+    assert!(format!("{:#}", err).contains("merge failed"), "{:#}", err);
+}
@@ -1,7 +1,6 @@
 use bytes::Bytes;
 use lsm::iterators::merge_iterator::MergeIterator;
 use lsm::iterators::StorageIterator;
-use anyhow::Result;
 use crate::iterator_mock::MockIterator;
 
 mod iterator_mock;
@@ -140,3 +139,88 @@ fn test_merge_empty() {
     let iter = MergeIterator::<MockIterator>::create(vec![]);
     check_iter_result(iter, vec![]);
 }
+
+fn check_iter_result_backward(iter: &mut impl StorageIterator, expected: Vec<(Bytes, Bytes)>) {
+    for (k, v) in expected {
+        assert!(iter.is_valid());
+        assert_eq!(
+            k,
+            iter.key(),
+            "expected key: {:?}, actual key: {:?}",
+            k,
+            as_bytes(iter.key())
+        );
+        assert_eq!(
+            v,
+            iter.value(),
+            "expected value: {:?}, actual value: {:?}",
+            v,
+            as_bytes(iter.value())
+        );
+        iter.prev().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_merge_backward() {
+    let i1 = MockIterator::new(vec![
+        (Bytes::from("a"), Bytes::from("1.1")),
+        (Bytes::from("b"), Bytes::from("2.1")),
+        (Bytes::from("c"), Bytes::from("3.1")),
+    ]);
+    let i2 = MockIterator::new(vec![
+        (Bytes::from("a"), Bytes::from("1.2")),
+        (Bytes::from("b"), Bytes::from("2.2")),
+        (Bytes::from("c"), Bytes::from("3.2")),
+        (Bytes::from("d"), Bytes::from("4.2")),
+    ]);
+    let i3 = MockIterator::new(vec![
+        (Bytes::from("b"), Bytes::from("2.3")),
+        (Bytes::from("c"), Bytes::from("3.3")),
+        (Bytes::from("d"), Bytes::from("4.3")),
+    ]);
+    let i4 = MockIterator::new(vec![]);
+
+    let mut iter =
+        MergeIterator::create(vec![Box::new(i1), Box::new(i2), Box::new(i3), Box::new(i4)]);
+    iter.seek_to_last().unwrap();
+    check_iter_result_backward(
+        &mut iter,
+        vec![
+            (Bytes::from("d"), Bytes::from("4.2")),
+            (Bytes::from("c"), Bytes::from("3.1")),
+            (Bytes::from("b"), Bytes::from("2.1")),
+            (Bytes::from("a"), Bytes::from("1.1")),
+        ],
+    );
+
+    iter.seek_for_prev(b"bb").unwrap();
+    check_iter_result_backward(
+        &mut iter,
+        vec![
+            (Bytes::from("b"), Bytes::from("2.1")),
+            (Bytes::from("a"), Bytes::from("1.1")),
+        ],
+    );
+
+    // change the direction in the middle
+    iter.seek(b"b").unwrap();
+    assert_eq!(iter.key(), b"b");
+    iter.next().unwrap();
+    assert_eq!(iter.key(), b"c");
+    iter.prev().unwrap();
+    assert_eq!(iter.key(), b"b");
+    assert_eq!(iter.value(), b"2.1");
+    iter.prev().unwrap();
+    assert_eq!(iter.key(), b"a");
+    iter.next().unwrap();
+    check_iter_result(
+        iter,
+        vec![
+            (Bytes::from("b"), Bytes::from("2.1")),
+            (Bytes::from("c"), Bytes::from("3.1")),
+            (Bytes::from("d"), Bytes::from("4.2")),
+        ],
+    );
+}
@@ -1,30 +1,45 @@
+use std::os::unix::fs::FileExt;
 use std::sync::Arc;
 
-use bytes::Bytes;
+use bytes::{BufMut, Bytes};
 use tempfile::{tempdir, TempDir};
+use lsm::block::BlockBuilder;
+use lsm::comparator::Comparator;
 use lsm::iterators::StorageIterator;
-use lsm::table::{SsTable, SsTableBuilder, SsTableIterator};
+use lsm::key::{InternalKey, ValueType, MAX_SEQ};
+use lsm::prefix_extractor::FixedPrefixExtractor;
+use lsm::range_tombstone::{FragmentedRangeTombstones, RangeTombstone};
+use lsm::table::{
+    BlockMeta, Bloom, FileObject, SsTable, SsTableBuilder, SsTableIterator, SsTableLookup,
+    SsTableProperties, SST_FORMAT_VERSION,
+};
 
 #[test]
 fn test_sst_build_single_key() {
-    let mut builder = SsTableBuilder::new(16);
-    builder.add(b"233", b"233333");
     let dir = tempdir().unwrap();
-    builder.build_for_test(dir.path().join("1.sst")).unwrap();
+    let mut builder = SsTableBuilder::new(dir.path().join("1.sst"), 16);
+    builder.add(&internal_key(b"233"), b"233333");
+    builder.build_for_test().unwrap();
 }
 
 #[test]
 fn test_sst_build_two_blocks() {
-    let mut builder = SsTableBuilder::new(16);
-    builder.add(b"11", b"11");
-    builder.add(b"22", b"22");
-    builder.add(b"33", b"11");
-    builder.add(b"44", b"22");
-    builder.add(b"55", b"11");
-    builder.add(b"66", b"22");
-    assert!(builder.meta.len() >= 2);
     let dir = tempdir().unwrap();
-    builder.build_for_test(dir.path().join("1.sst")).unwrap();
+    let mut builder = SsTableBuilder::new(dir.path().join("1.sst"), 16);
+    builder.add(&internal_key(b"11"), b"11");
+    builder.add(&internal_key(b"22"), b"22");
+    builder.add(&internal_key(b"33"), b"11");
+    builder.add(&internal_key(b"44"), b"22");
+    builder.add(&internal_key(b"55"), b"11");
+    builder.add(&internal_key(b"66"), b"22");
+    assert!(builder.meta.len() >= 2);
+    builder.build_for_test().unwrap();
+}
+
+fn internal_key(user_key: &[u8]) -> Vec<u8> {
+    InternalKey::new(user_key, 0, ValueType::Put)
+        .as_bytes()
+        .to_vec()
 }
 
 fn key_of(idx: usize) -> Vec<u8> {
@@ -40,15 +55,15 @@ fn num_of_keys() -> usize {
 }
 
 fn generate_sst() -> (TempDir, SsTable) {
-    let mut builder = SsTableBuilder::new(128);
+    let dir = tempdir().unwrap();
+    let mut builder = SsTableBuilder::new(dir.path().join("1.sst"), 128);
     for idx in 0..num_of_keys() {
         let key = key_of(idx);
         let value = value_of(idx);
-        builder.add(&key[..], &value[..]);
+        builder.add(&internal_key(&key), &value[..]);
     }
-    let dir = tempdir().unwrap();
-    let path = dir.path().join("1.sst");
-    (dir, builder.build_for_test(path).unwrap())
+    let sst = builder.build_for_test().unwrap();
+    (dir, sst)
 }
 
 #[test]
@@ -126,3 +141,614 @@ fn test_sst_seek_key() {
         iter.seek_to_key(b"k").unwrap();
     }
 }
+
+#[test]
+fn test_sst_reopen_from_disk() {
+    let (dir, sst) = generate_sst();
+    let meta = sst.block_metas.clone();
+    drop(sst);
+    let path = dir.path().join("1.sst");
+    assert!(path.exists());
+    let sst = Arc::new(SsTable::open_for_test(FileObject::open(&path).unwrap()).unwrap());
+    assert_eq!(sst.block_metas, meta);
+    let mut iter = SsTableIterator::create_and_seek_to_first(sst).unwrap();
+    for i in 0..num_of_keys() {
+        assert_eq!(iter.key(), key_of(i));
+        assert_eq!(iter.value(), value_of(i));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_sst_get() {
+    let dir = tempdir().unwrap();
+    let mut builder = SsTableBuilder::new(dir.path().join("1.sst"), 128);
+    for idx in 0..num_of_keys() {
+        if idx % 10 == 0 {
+            builder.add(
+                InternalKey::new(&key_of(idx), 0, ValueType::Delete).as_bytes(),
+                b"",
+            );
+        } else {
+            builder.add(&internal_key(&key_of(idx)), &value_of(idx));
+        }
+    }
+    let sst = builder.build_for_test().unwrap();
+    for idx in 0..num_of_keys() {
+        let expected = if idx % 10 == 0 {
+            SsTableLookup::Deleted
+        } else {
+            SsTableLookup::Found(Bytes::from(value_of(idx)))
+        };
+        assert_eq!(sst.get(&key_of(idx), MAX_SEQ).unwrap(), expected);
+        assert_eq!(
+            sst.get(format!("key_{:03}", idx * 5 + 1).as_bytes(), MAX_SEQ)
+                .unwrap(),
+            SsTableLookup::NotFound
+        );
+    }
+    assert_eq!(sst.get(b"k", MAX_SEQ).unwrap(), SsTableLookup::NotFound);
+}
+
+#[test]
+fn test_sst_get_versions() {
+    let dir = tempdir().unwrap();
+    let mut builder = SsTableBuilder::new(dir.path().join("1.sst"), 128);
+    for idx in 0..num_of_keys() {
+        // seq 30 deletes the key, seq 20 and 10 put values
+        builder.add(
+            InternalKey::new(&key_of(idx), 30, ValueType::Delete).as_bytes(),
+            b"",
+        );
+        builder.add(
+            InternalKey::new(&key_of(idx), 20, ValueType::Put).as_bytes(),
+            &value_of(idx),
+        );
+        builder.add(
+            InternalKey::new(&key_of(idx), 10, ValueType::Put).as_bytes(),
+            b"233",
+        );
+    }
+    let sst = builder.build_for_test().unwrap();
+    assert_eq!(sst.max_seq, 30);
+    for idx in 0..num_of_keys() {
+        assert_eq!(
+            sst.get(&key_of(idx), MAX_SEQ).unwrap(),
+            SsTableLookup::Deleted
+        );
+        assert_eq!(
+            sst.get(&key_of(idx), 29).unwrap(),
+            SsTableLookup::Found(Bytes::from(value_of(idx)))
+        );
+        assert_eq!(
+            sst.get(&key_of(idx), 19).unwrap(),
+            SsTableLookup::Found(Bytes::from_static(b"233"))
+        );
+        assert_eq!(sst.get(&key_of(idx), 9).unwrap(), SsTableLookup::NotFound);
+    }
+
+    let mut iter = SsTableIterator::create_and_seek_to_key(Arc::new(sst), &key_of(1)).unwrap();
+    for seq in [30, 20, 10] {
+        assert_eq!(iter.key(), key_of(1));
+        assert_eq!(iter.seq(), seq);
+        iter.next().unwrap();
+    }
+    assert_eq!(iter.key(), key_of(2));
+}
+
+#[test]
+fn test_bloom_false_positive_rate() {
+    let key_hashes: Vec<u32> = (0..1000).map(|idx| Bloom::hash(&key_of(idx))).collect();
+    let bloom = Bloom::build_from_key_hashes(&key_hashes, 10);
+    for hash in &key_hashes {
+        assert!(bloom.may_contain(*hash));
+    }
+    let false_positives = (1000..11000)
+        .filter(|idx| bloom.may_contain(Bloom::hash(&key_of(*idx))))
+        .count();
+    // about 1% with 10 bits per key
+    assert!(
+        false_positives < 300,
+        "false positives: {}",
+        false_positives
+    );
+}
+
+#[test]
+fn test_sst_bloom_filter() {
+    let (_dir, sst) = generate_sst();
+    let bloom = sst.bloom.as_ref().unwrap();
+    for idx in 0..num_of_keys() {
+        assert!(bloom.may_contain(Bloom::hash(&key_of(idx))));
+    }
+
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    let mut builder = SsTableBuilder::new_with_bloom(&path, 128, 0);
+    for idx in 0..num_of_keys() {
+        builder.add(&internal_key(&key_of(idx)), &value_of(idx));
+    }
+    builder.build_for_test().unwrap();
+    let sst = SsTable::open_for_test(FileObject::open(&path).unwrap()).unwrap();
+    assert!(sst.bloom.is_none());
+    for idx in 0..num_of_keys() {
+        assert_eq!(
+            sst.get(&key_of(idx), MAX_SEQ).unwrap(),
+            SsTableLookup::Found(Bytes::from(value_of(idx)))
+        );
+    }
+}
+
+#[test]
+fn test_sst_range_tombstones() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    let mut builder = SsTableBuilder::new(&path, 128);
+    for idx in 0..num_of_keys() {
+        builder.add(
+            InternalKey::new(&key_of(idx), 10, ValueType::Put).as_bytes(),
+            &value_of(idx),
+        );
+    }
+    builder.add_range_tombstone(RangeTombstone::new(&key_of(10), &key_of(20), 20));
+    builder.add_range_tombstone(RangeTombstone::new(&key_of(15), &key_of(30), 5));
+    let sst = builder.build_for_test().unwrap();
+    assert_eq!(sst.max_seq, 20);
+    let sst = SsTable::open(0, None, FileObject::open(&path).unwrap()).unwrap();
+    assert_eq!(
+        sst.range_tombstones,
+        vec![
+            RangeTombstone::new(&key_of(10), &key_of(20), 20),
+            RangeTombstone::new(&key_of(15), &key_of(30), 5)
+        ]
+    );
+    assert_eq!(sst.max_covering_seq(&key_of(9), MAX_SEQ), 0);
+    assert_eq!(sst.max_covering_seq(&key_of(15), MAX_SEQ), 20);
+    assert_eq!(sst.max_covering_seq(&key_of(15), 19), 5);
+    assert_eq!(sst.max_covering_seq(&key_of(20), MAX_SEQ), 5);
+    assert_eq!(sst.max_covering_seq(&key_of(30), MAX_SEQ), 0);
+
+    // overlapping and nested tombstones across two SSTs, their fragments are merged
+    let tombstones: Vec<RangeTombstone> = (0..20)
+        .map(|idx| {
+            RangeTombstone::new(
+                &key_of(idx * 3),
+                &key_of(idx * 3 + (idx % 7) * 4 + 1),
+                idx as u64 + 1,
+            )
+        })
+        .collect();
+    let mut builder = SsTableBuilder::new(dir.path().join("3.sst"), 128);
+    let mut other = SsTableBuilder::new(dir.path().join("4.sst"), 128);
+    for (idx, tombstone) in tombstones.iter().enumerate() {
+        let builder = if idx % 2 == 0 {
+            &mut builder
+        } else {
+            &mut other
+        };
+        builder.add_range_tombstone(tombstone.clone());
+    }
+    let (sst, other) = (
+        builder.build_for_test().unwrap(),
+        other.build_for_test().unwrap(),
+    );
+    let comparator = sst.comparator().clone();
+    let merged = FragmentedRangeTombstones::merge(
+        comparator.clone(),
+        [
+            sst.range_tombstone_fragments(),
+            other.range_tombstone_fragments(),
+        ],
+    );
+    for idx in 0..num_of_keys() {
+        let key = key_of(idx);
+        let mut expected: Vec<u64> = tombstones
+            .iter()
+            .filter(|tombstone| tombstone.contains(&*comparator, &key))
+            .map(|tombstone| tombstone.seq)
+            .collect();
+        expected.sort_unstable_by(|a, b| b.cmp(a));
+        assert_eq!(merged.covering_seqs(&key), expected, "{}", idx);
+        for read_seq in [MAX_SEQ, 10] {
+            let expected = expected
+                .iter()
+                .copied()
+                .find(|&seq| seq <= read_seq)
+                .unwrap_or(0);
+            assert_eq!(
+                sst.max_covering_seq(&key, read_seq)
+                    .max(other.max_covering_seq(&key, read_seq)),
+                expected
+            );
+        }
+    }
+
+    // an SST with only range tombstones has no blocks
+    let mut builder = SsTableBuilder::new(dir.path().join("2.sst"), 128);
+    builder.add_range_tombstone(RangeTombstone::new(b"1", b"3", 1));
+    let sst = builder.build_for_test().unwrap();
+    assert_eq!(sst.num_of_blocks(), 0);
+    assert_eq!(sst.get(b"2", MAX_SEQ).unwrap(), SsTableLookup::NotFound);
+    assert!(!SsTableIterator::create_and_seek_to_first(Arc::new(sst))
+        .unwrap()
+        .is_valid());
+}
+
+#[test]
+fn test_sst_iterator_backward() {
+    let (_dir, sst) = generate_sst();
+    let sst = Arc::new(sst);
+    let mut iter = SsTableIterator::create_and_seek_to_last(sst.clone()).unwrap();
+    for i in (0..num_of_keys()).rev() {
+        assert_eq!(
+            iter.key(),
+            key_of(i),
+            "expected key: {:?}, actual key: {:?}",
+            as_bytes(&key_of(i)),
+            as_bytes(iter.key())
+        );
+        assert_eq!(iter.value(), value_of(i));
+        iter.prev().unwrap();
+    }
+    assert!(!iter.is_valid());
+
+    let mut iter = SsTableIterator::create_and_seek_for_prev(sst, b"z").unwrap();
+    assert_eq!(iter.key(), key_of(num_of_keys() - 1));
+    for i in 0..num_of_keys() {
+        iter.seek_for_prev(&format!("key_{:03}", i * 5 + 1).into_bytes())
+            .unwrap();
+        assert_eq!(iter.key(), key_of(i));
+        iter.seek_for_prev(&key_of(i)).unwrap();
+        assert_eq!(iter.key(), key_of(i));
+        // changes the direction
+        if i > 0 {
+            iter.prev().unwrap();
+            assert_eq!(iter.key(), key_of(i - 1));
+            iter.next().unwrap();
+            assert_eq!(iter.key(), key_of(i));
+        }
+    }
+    iter.seek_for_prev(b"k").unwrap();
+    assert!(!iter.is_valid());
+}
+
+/// Orders keys bytewise descending.
+struct ReverseComparator;
+
+impl Comparator for ReverseComparator {
+    fn name(&self) -> &str {
+        "test.ReverseComparator"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+        b.cmp(a)
+    }
+}
+
+#[test]
+fn test_sst_comparator() {
+    let comparator: Arc<dyn Comparator> = Arc::new(ReverseComparator);
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    let mut builder = SsTableBuilder::new_with_comparator(&path, 128, 10, comparator.clone());
+    for idx in (0..num_of_keys()).rev() {
+        builder.add(&internal_key(&key_of(idx)), &value_of(idx));
+    }
+    builder.build_for_test().unwrap();
+    assert!(SsTable::open_for_test(FileObject::open(&path).unwrap()).is_err());
+
+    let sst = SsTable::open_with_comparator(0, None, FileObject::open(&path).unwrap(), comparator)
+        .unwrap();
+    assert_eq!(sst.first_key(), &key_of(num_of_keys() - 1)[..]);
+    assert_eq!(
+        sst.key_range(),
+        Some((&key_of(num_of_keys() - 1)[..], &key_of(0)[..]))
+    );
+    for idx in 0..num_of_keys() {
+        assert_eq!(
+            sst.get(&key_of(idx), MAX_SEQ).unwrap(),
+            SsTableLookup::Found(Bytes::from(value_of(idx)))
+        );
+    }
+    // seeks to the first key which is after `key` in the order of the comparator
+    let sst = Arc::new(sst);
+    let mut iter = SsTableIterator::create_and_seek_to_key(
+        sst.clone(),
+        &format!("key_{:03}", 51).into_bytes(),
+    )
+    .unwrap();
+    for idx in (0..=10).rev() {
+        assert_eq!(iter.key(), key_of(idx));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+    let iter =
+        SsTableIterator::create_and_seek_for_prev(sst, &format!("key_{:03}", 51).into_bytes())
+            .unwrap();
+    assert_eq!(iter.key(), key_of(11));
+}
+
+#[test]
+fn test_sst_prefix_bloom() {
+    let extractor = Arc::new(FixedPrefixExtractor::new(6));
+    let dir = tempdir().unwrap();
+    let mut builder = SsTableBuilder::new_with_bloom(dir.path().join("1.sst"), 128, 10)
+        .with_prefix_extractor(extractor.clone());
+    for idx in 0..num_of_keys() {
+        builder.add(&internal_key(&key_of(idx)), &value_of(idx));
+    }
+    let sst = builder.build_for_test().unwrap();
+    for idx in 0..num_of_keys() {
+        assert!(sst.may_contain_prefix(&*extractor, &key_of(idx)[..6]));
+    }
+    // after the last key
+    assert!(!sst.may_contain_prefix(&*extractor, b"key_50"));
+    // between the keys, only ruled out by the filter
+    let missing = (0..50)
+        .map(|x| format!("key_{}{}", x / 10, (b'a' + (x % 10) as u8) as char))
+        .collect::<Vec<_>>();
+    let false_positives = missing
+        .iter()
+        .filter(|prefix| sst.may_contain_prefix(&*extractor, prefix.as_bytes()))
+        .count();
+    assert!(false_positives <= 5, "{} false positives", false_positives);
+    // a filter of another extractor is not used
+    assert!(missing.iter().all(
+        |prefix| sst.may_contain_prefix(&FixedPrefixExtractor::new(5), &prefix.as_bytes()[..5])
+    ));
+}
+
+#[test]
+fn test_sst_format_version_1() {
+    // blocks | bloom | range tombstones | block meta | comparator name | prefix extractor name |
+    // max seq | 32-bit offsets
+    let mut buf = Vec::new();
+    let mut block_metas = Vec::new();
+    let mut builder = BlockBuilder::new(128);
+    let mut first_key = Vec::new();
+    let mut last_key = Vec::new();
+    for idx in 0..num_of_keys() {
+        let key = InternalKey::new(&key_of(idx), 10, ValueType::Put)
+            .as_bytes()
+            .to_vec();
+        if !builder.add(&key, &value_of(idx)) {
+            let block = std::mem::replace(&mut builder, BlockBuilder::new(128)).build();
+            block_metas.push((buf.len(), std::mem::take(&mut first_key), last_key.clone()));
+            buf.extend(block.encode());
+            assert!(builder.add(&key, &value_of(idx)));
+        }
+        if first_key.is_empty() {
+            first_key = key.clone();
+        }
+        last_key = key;
+    }
+    block_metas.push((buf.len(), first_key, last_key));
+    buf.extend(builder.build().encode());
+    let bloom_offset = buf.len();
+    let range_tombstones_offset = buf.len();
+    RangeTombstone::encode_all(
+        &[RangeTombstone::new(&key_of(10), &key_of(20), 20)],
+        &mut buf,
+    );
+    let block_meta_offset = buf.len();
+    for (offset, first_key, last_key) in &block_metas {
+        buf.put_u32(*offset as u32);
+        buf.put_u8(first_key.len() as u8);
+        buf.put_slice(first_key);
+        buf.put_u8(last_key.len() as u8);
+        buf.put_slice(last_key);
+    }
+    let comparator_offset = buf.len();
+    buf.put_slice(b"lsm.BytewiseComparator");
+    let prefix_extractor_offset = buf.len();
+    buf.put_u64(20);
+    for offset in [
+        bloom_offset,
+        range_tombstones_offset,
+        block_meta_offset,
+        comparator_offset,
+        prefix_extractor_offset,
+    ] {
+        buf.put_u32(offset as u32);
+    }
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    std::fs::write(&path, buf).unwrap();
+
+    let sst = SsTable::open_for_test(FileObject::open(&path).unwrap()).unwrap();
+    assert_eq!(sst.format_version(), 1);
+    assert!(sst.properties().is_none());
+    assert!(sst.num_of_blocks() > 1);
+    assert_eq!(sst.max_seq, 20);
+    assert_eq!(sst.first_key(), &key_of(0)[..]);
+    assert_eq!(sst.last_key(), &key_of(num_of_keys() - 1)[..]);
+    for idx in 0..num_of_keys() {
+        assert_eq!(
+            sst.get(&key_of(idx), MAX_SEQ).unwrap(),
+            SsTableLookup::Found(Bytes::from(value_of(idx)))
+        );
+    }
+    assert_eq!(sst.max_covering_seq(&key_of(15), MAX_SEQ), 20);
+    let mut iter = SsTableIterator::create_and_seek_to_first(Arc::new(sst)).unwrap();
+    for idx in 0..num_of_keys() {
+        assert_eq!(iter.key(), key_of(idx));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+
+    let mut builder = SsTableBuilder::new(dir.path().join("2.sst"), 128);
+    builder.add(&internal_key(b"233"), b"233333");
+    assert_eq!(
+        builder.build_for_test().unwrap().format_version(),
+        SST_FORMAT_VERSION
+    );
+}
+
+#[test]
+fn test_sst_properties() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    let mut builder = SsTableBuilder::new_with_bloom(&path, 128, 10)
+        .with_prefix_extractor(Arc::new(FixedPrefixExtractor::new(4)));
+    for idx in 0..num_of_keys() {
+        builder.add(
+            InternalKey::new(&key_of(idx), 10, ValueType::Put).as_bytes(),
+            &value_of(idx),
+        );
+        builder.add(
+            InternalKey::new(&key_of(idx), 5, ValueType::Delete).as_bytes(),
+            b"",
+        );
+    }
+    builder.add_range_tombstone(RangeTombstone::new(&key_of(10), &key_of(20), 20));
+    let sst = builder.build_for_test().unwrap();
+    let properties = sst.properties().unwrap().clone();
+    assert!(properties.creation_time > 0);
+    assert_eq!(
+        properties,
+        SsTableProperties {
+            num_entries: num_of_keys() as u64 * 2,
+            num_deletions: num_of_keys() as u64,
+            num_range_deletions: 1,
+            raw_key_size: (key_of(0).len() as u64 + 8) * num_of_keys() as u64 * 2,
+            raw_value_size: value_of(0).len() as u64 * num_of_keys() as u64,
+            smallest_key: Bytes::from(key_of(0)),
+            largest_key: Bytes::from(key_of(num_of_keys() - 1)),
+            max_seq: 20,
+            creation_time: properties.creation_time,
+            comparator: "lsm.BytewiseComparator".to_string(),
+            prefix_extractor: "lsm.FixedPrefix.4".to_string(),
+            block_size: 128,
+            bloom_bits_per_key: 10,
+        }
+    );
+    let sst = SsTable::open_for_test(FileObject::open(&path).unwrap()).unwrap();
+    assert_eq!(sst.format_version(), SST_FORMAT_VERSION);
+    assert_eq!(sst.properties(), Some(&properties));
+}
+
+#[test]
+fn test_sst_offsets_beyond_4gib() {
+    let dir = tempdir().unwrap();
+    let mut builder = SsTableBuilder::new(dir.path().join("1.sst"), 128);
+    for idx in 0..num_of_keys() {
+        builder.add(&internal_key(&key_of(idx)), &value_of(idx));
+    }
+    let sst = builder.build_for_test().unwrap();
+    let data = sst.file.read(0, sst.file.size()).unwrap();
+    let len = data.len();
+
+    // move everything after the first block 5 GiB further into a sparse file, only the 64-bit
+    // offsets in the block meta and the footer change
+    let shift = 5 << 30;
+    let mut block_metas = sst.block_metas.clone();
+    for meta in &mut block_metas[1..] {
+        meta.offset += shift;
+    }
+    let mut tail = data[sst.bloom_offset..sst.block_meta_offset].to_vec();
+    BlockMeta::encode_block_meta(&block_metas, &mut tail);
+    let footer_offsets: Vec<u64> = data[(len - 44)..(len - 12)]
+        .chunks(8)
+        .map(|offset| u64::from_be_bytes(offset.try_into().unwrap()))
+        .collect();
+    let properties_offset = footer_offsets[3] as usize;
+    tail.extend_from_slice(&data[properties_offset..(len - 44)]);
+    for offset in footer_offsets {
+        tail.put_u64(offset + shift as u64);
+    }
+    tail.extend_from_slice(&data[(len - 12)..]);
+    let second_block = sst.block_metas[1].offset;
+    let path = dir.path().join("2.sst");
+    let file = std::fs::File::create(&path).unwrap();
+    file.write_all_at(&data[..second_block], 0).unwrap();
+    file.write_all_at(
+        &data[second_block..sst.bloom_offset],
+        (second_block + shift) as u64,
+    )
+    .unwrap();
+    file.write_all_at(&tail, (sst.bloom_offset + shift) as u64)
+        .unwrap();
+    drop(file);
+
+    // the first block now spans the hole, only the blocks after it are read
+    let sst = SsTable::open_for_test(FileObject::open(&path).unwrap()).unwrap();
+    assert!(sst.file.size() > 1 << 32);
+    assert_eq!(sst.block_metas[1].offset, second_block + shift);
+    let last = num_of_keys() - 1;
+    assert_eq!(
+        sst.get(&key_of(last), MAX_SEQ).unwrap(),
+        SsTableLookup::Found(Bytes::from(value_of(last)))
+    );
+    // the key after the first key of the second block, seeking the first key reads the first block
+    let first = (0..num_of_keys())
+        .find(|&idx| internal_key(&key_of(idx)) == sst.block_metas[1].first_key)
+        .unwrap()
+        + 1;
+    let mut iter = SsTableIterator::create_and_seek_to_key(Arc::new(sst), &key_of(first)).unwrap();
+    for idx in first..num_of_keys() {
+        assert_eq!(iter.key(), key_of(idx));
+        assert_eq!(iter.value(), value_of(idx));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_sst_open_corrupted() {
+    let (dir, sst) = generate_sst();
+    let data = sst.file.read(0, sst.file.size()).unwrap();
+    let open = |name: &str, data: &[u8]| {
+        let path = dir.path().join(name);
+        std::fs::write(&path, data).unwrap();
+        SsTable::open(7, None, FileObject::open(&path).unwrap())
+    };
+    assert!(open("valid.sst", &data).is_ok());
+
+    let mut corrupted = vec![
+        ("empty.sst", Vec::new()),
+        ("truncated.sst", data[..(data.len() - 20)].to_vec()),
+        ("tail.sst", data[(data.len() - 20)..].to_vec()),
+        (
+            "foreign.sst",
+            b"not an sst, just some text long enough to have a footer".to_vec(),
+        ),
+    ];
+    // an unknown format version
+    let mut unknown_version = data.clone();
+    let len = unknown_version.len();
+    unknown_version[len - 9] = 0xff;
+    corrupted.push(("version.sst", unknown_version));
+    // the offset of the properties is past the end
+    let mut bad_offset = data.clone();
+    bad_offset[len - 20] = 0xff;
+    corrupted.push(("offset.sst", bad_offset));
+    // a flipped byte in the properties
+    let mut bad_properties = data.clone();
+    bad_properties[len - 45] ^= 0xff;
+    corrupted.push(("properties.sst", bad_properties));
+    // the bloom filter is only the byte of the number of hash functions
+    let mut bad_bloom = data.clone();
+    let range_tombstones_offset =
+        u64::from_be_bytes(data[(len - 36)..(len - 28)].try_into().unwrap());
+    bad_bloom[(len - 44)..(len - 36)].copy_from_slice(&(range_tombstones_offset - 1).to_be_bytes());
+    corrupted.push(("bloom.sst", bad_bloom));
+    // the magic is damaged, the footer is not read as format version 1
+    let mut bad_magic = data.clone();
+    bad_magic[len - 1] ^= 0xff;
+    corrupted.push(("magic.sst", bad_magic));
+    for (name, data) in corrupted {
+        let err = open(name, &data)
+            .err()
+            .unwrap_or_else(|| panic!("{} is opened", name));
+        assert!(err.to_string().contains("SST 7"), "{}: {:?}", name, err);
+        if name == "foreign.sst" || name == "magic.sst" {
+            assert!(
+                format!("{:#}", err).contains("not an SST: bad magic"),
+                "{}: {:#}",
+                name,
+                err
+            );
+        }
+    }
+}
@@ -131,3 +131,45 @@ fn test_merge_5() {
     let iter = TwoMergeIterator::create(i1, i2).unwrap();
     check_iter_result(iter, vec![])
 }
+
+#[test]
+fn test_merge_backward() {
+    let i1 = MockIterator::new(vec![
+        (Bytes::from("a"), Bytes::from("1.1")),
+        (Bytes::from("c"), Bytes::from("3.1")),
+    ]);
+    let i2 = MockIterator::new(vec![
+        (Bytes::from("a"), Bytes::from("1.2")),
+        (Bytes::from("b"), Bytes::from("2.2")),
+        (Bytes::from("c"), Bytes::from("3.2")),
+        (Bytes::from("d"), Bytes::from("4.2")),
+    ]);
+    let mut iter = TwoMergeIterator::create(i1, i2).unwrap();
+    iter.seek_to_last().unwrap();
+    for (k, v) in [("d", "4.2"), ("c", "3.1"), ("b", "2.2"), ("a", "1.1")] {
+        assert_eq!(iter.key(), k.as_bytes());
+        assert_eq!(iter.value(), v.as_bytes());
+        iter.prev().unwrap();
+    }
+    assert!(!iter.is_valid());
+
+    // change the direction in the middle
+    iter.seek_for_prev(b"c").unwrap();
+    assert_eq!(iter.value(), b"3.1");
+    iter.prev().unwrap();
+    assert_eq!(iter.value(), b"2.2");
+    iter.next().unwrap();
+    assert_eq!(iter.value(), b"3.1");
+    iter.next().unwrap();
+    assert_eq!(iter.value(), b"4.2");
+    iter.prev().unwrap();
+    iter.prev().unwrap();
+    check_iter_result(
+        iter,
+        vec![
+            (Bytes::from("b"), Bytes::from("2.2")),
+            (Bytes::from("c"), Bytes::from("3.1")),
+            (Bytes::from("d"), Bytes::from("4.2")),
+        ],
+    );
+}
@@ -0,0 +1,292 @@
+use std::ops::Bound;
+use std::time::Duration;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+use lsm::iterators::StorageIterator;
+use lsm::lsm_storage::{LsmStorage, LsmStorageOptions};
+use lsm::mvcc::TransactionError;
+
+fn check_iter_result(iter: impl StorageIterator, expected: Vec<(&[u8], &[u8])>) {
+    let mut iter = iter;
+    for (k, v) in expected {
+        assert!(iter.is_valid());
+        assert_eq!(
+            iter.key(),
+            k,
+            "expected key: {:?}, actual key: {:?}",
+            Bytes::copy_from_slice(k),
+            Bytes::copy_from_slice(iter.key())
+        );
+        assert_eq!(iter.value(), v);
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+fn is_conflict(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<TransactionError>() == Some(&TransactionError::Conflict)
+}
+
+#[test]
+fn test_txn_read_own_writes() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    storage.put(b"2", b"2333").unwrap();
+    storage.put(b"3", b"23333").unwrap();
+
+    let mut txn = storage.begin();
+    txn.put(b"0", b"23").unwrap();
+    txn.put(b"2", b"2").unwrap();
+    txn.delete(b"3").unwrap();
+    // written after the transaction began, it is not visible to the transaction
+    storage.put(b"4", b"233333").unwrap();
+    assert_eq!(&txn.get(b"2").unwrap().unwrap()[..], b"2");
+    assert!(txn.get(b"3").unwrap().is_none());
+    check_iter_result(
+        txn.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![(b"0", b"23"), (b"1", b"233"), (b"2", b"2")],
+    );
+    check_iter_result(
+        txn.scan(Bound::Excluded(b"0"), Bound::Included(b"3"))
+            .unwrap(),
+        vec![(b"1", b"233"), (b"2", b"2")],
+    );
+    // the writes are not visible to others before commit
+    assert_eq!(&storage.get(b"2").unwrap().unwrap()[..], b"2333");
+    // the key written by others is in the scanned range
+    assert!(is_conflict(&txn.commit().unwrap_err()));
+    assert_eq!(&storage.get(b"2").unwrap().unwrap()[..], b"2333");
+
+    let mut txn = storage.begin();
+    txn.put(b"0", b"23").unwrap();
+    txn.put(b"2", b"2").unwrap();
+    txn.delete(b"3").unwrap();
+    check_iter_result(
+        txn.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![
+            (b"0", b"23"),
+            (b"1", b"233"),
+            (b"2", b"2"),
+            (b"4", b"233333"),
+        ],
+    );
+    txn.commit().unwrap();
+
+    check_iter_result(
+        storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![
+            (b"0", b"23"),
+            (b"1", b"233"),
+            (b"2", b"2"),
+            (b"4", b"233333"),
+        ],
+    );
+}
+
+#[test]
+fn test_txn_rollback() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    let mut txn = storage.begin();
+    txn.put(b"1", b"2").unwrap();
+    txn.put(b"2", b"2333").unwrap();
+    txn.rollback();
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"233");
+    assert!(storage.get(b"2").unwrap().is_none());
+}
+
+#[test]
+fn test_txn_conflict() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.put(b"counter", b"1").unwrap();
+
+    // both read the counter and increase it, only the first commit succeeds
+    let mut txn1 = storage.begin();
+    let mut txn2 = storage.begin();
+    assert_eq!(&txn1.get(b"counter").unwrap().unwrap()[..], b"1");
+    assert_eq!(&txn2.get(b"counter").unwrap().unwrap()[..], b"1");
+    txn1.put(b"counter", b"2").unwrap();
+    txn2.put(b"counter", b"2").unwrap();
+    txn1.commit().unwrap();
+    assert!(is_conflict(&txn2.commit().unwrap_err()));
+
+    // a non-transactional write conflicts too
+    let mut txn = storage.begin();
+    txn.get(b"counter").unwrap();
+    txn.put(b"counter", b"3").unwrap();
+    storage.put(b"counter", b"4").unwrap();
+    assert!(is_conflict(&txn.commit().unwrap_err()));
+    assert_eq!(&storage.get(b"counter").unwrap().unwrap()[..], b"4");
+
+    // keys read by a scan are validated
+    let mut txn = storage.begin();
+    check_iter_result(
+        txn.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![(b"counter", b"4")],
+    );
+    txn.put(b"sum", b"4").unwrap();
+    storage.delete(b"counter").unwrap();
+    assert!(is_conflict(&txn.commit().unwrap_err()));
+    assert!(storage.get(b"sum").unwrap().is_none());
+
+    // a key inserted into a scanned range is a phantom, both scan an empty range and insert a
+    // different key into it, only the first commit succeeds
+    let mut txn1 = storage.begin();
+    let mut txn2 = storage.begin();
+    check_iter_result(
+        txn1.scan(Bound::Included(b"job"), Bound::Excluded(b"jobz"))
+            .unwrap(),
+        vec![],
+    );
+    check_iter_result(
+        txn2.scan(Bound::Included(b"job"), Bound::Excluded(b"jobz"))
+            .unwrap(),
+        vec![],
+    );
+    txn1.put(b"job1", b"1").unwrap();
+    txn2.put(b"job2", b"2").unwrap();
+    txn1.commit().unwrap();
+    assert!(is_conflict(&txn2.commit().unwrap_err()));
+    assert!(storage.get(b"job2").unwrap().is_none());
+
+    // a range tombstone overlapping a scanned range conflicts too
+    let mut txn = storage.begin();
+    check_iter_result(
+        txn.scan(Bound::Excluded(b"job1"), Bound::Unbounded)
+            .unwrap(),
+        vec![],
+    );
+    txn.put(b"job3", b"3").unwrap();
+    storage.delete_range(b"job0", b"job2").unwrap();
+    assert!(is_conflict(&txn.commit().unwrap_err()));
+}
+
+#[test]
+fn test_txn_no_conflict() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    storage.put(b"2", b"2333").unwrap();
+
+    // disjoint read and write sets
+    let mut txn1 = storage.begin();
+    let mut txn2 = storage.begin();
+    txn1.get(b"1").unwrap();
+    txn1.put(b"1", b"2").unwrap();
+    txn2.get(b"2").unwrap();
+    txn2.put(b"2", b"2").unwrap();
+    txn1.commit().unwrap();
+    txn2.commit().unwrap();
+
+    // reading its own write doesn't depend on others, and blind writes never conflict
+    let mut txn = storage.begin();
+    txn.put(b"1", b"23").unwrap();
+    txn.get(b"1").unwrap();
+    txn.put(b"3", b"23333").unwrap();
+    storage.put(b"1", b"1").unwrap();
+    storage.put(b"3", b"3").unwrap();
+    txn.commit().unwrap();
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"23");
+    assert_eq!(&storage.get(b"3").unwrap().unwrap()[..], b"23333");
+
+    // writes outside a scanned range don't conflict
+    let mut txn = storage.begin();
+    txn.scan(Bound::Included(b"1"), Bound::Excluded(b"2"))
+        .unwrap();
+    txn.put(b"4", b"4").unwrap();
+    storage.put(b"2", b"2").unwrap();
+    storage.delete_range(b"2", b"3").unwrap();
+    txn.commit().unwrap();
+}
+
+#[test]
+fn test_pessimistic_txn_counter() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.put(b"counter", b"0").unwrap();
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..50 {
+                    let mut txn = storage.begin_pessimistic();
+                    let value = txn.get_for_update(b"counter").unwrap().unwrap();
+                    let value: u64 = std::str::from_utf8(&value).unwrap().parse().unwrap();
+                    txn.put(b"counter", (value + 1).to_string().as_bytes())
+                        .unwrap();
+                    txn.commit().unwrap();
+                }
+            });
+        }
+    });
+    assert_eq!(&storage.get(b"counter").unwrap().unwrap()[..], b"200");
+}
+
+#[test]
+fn test_pessimistic_txn_lock_timeout() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        lock_timeout: Duration::from_millis(50),
+        ..Default::default()
+    };
+    let storage = LsmStorage::open_with_options(&dir, options).unwrap();
+    let mut txn1 = storage.begin_pessimistic();
+    txn1.put(b"1", b"233").unwrap();
+    let mut txn2 = storage.begin_pessimistic();
+    let err = txn2.put(b"1", b"2333").unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<TransactionError>(),
+        Some(&TransactionError::LockTimeout)
+    );
+    // the lock is released once the holder ends
+    txn1.commit().unwrap();
+    assert_eq!(&txn2.get_for_update(b"1").unwrap().unwrap()[..], b"233");
+    txn2.put(b"1", b"2333").unwrap();
+    txn2.commit().unwrap();
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"2333");
+}
+
+#[test]
+fn test_pessimistic_txn_deadlock() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    let mut txn1 = storage.begin_pessimistic();
+    let mut txn2 = storage.begin_pessimistic();
+    txn1.put(b"1", b"1").unwrap();
+    txn2.put(b"2", b"2").unwrap();
+    std::thread::scope(|s| {
+        let handle = s.spawn(move || {
+            // waits for txn2
+            txn1.put(b"2", b"1").unwrap();
+            txn1.commit().unwrap();
+        });
+        // wait until txn1 is blocked on the lock of "2"
+        std::thread::sleep(Duration::from_millis(100));
+        let err = txn2.put(b"1", b"2").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<TransactionError>(),
+            Some(&TransactionError::Deadlock)
+        );
+        txn2.rollback();
+        handle.join().unwrap();
+    });
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"1");
+    assert_eq!(&storage.get(b"2").unwrap().unwrap()[..], b"1");
+}
+
+#[test]
+fn test_pessimistic_txn_conflict_with_plain_write() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(&dir).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    let mut txn = storage.begin_pessimistic();
+    assert_eq!(&txn.get_for_update(b"1").unwrap().unwrap()[..], b"233");
+    // writes outside transactions don't take the locks, they are detected at commit
+    storage.put(b"1", b"2").unwrap();
+    txn.put(b"1", b"2333").unwrap();
+    assert!(is_conflict(&txn.commit().unwrap_err()));
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"2");
+}